@@ -1,30 +1,966 @@
-use range_map::RangeSet;
+use std::{
+	collections::BTreeMap,
+	fmt, ops,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
 
-use crate::formatting::{Formatting, Text};
+use range_map::{Range, RangeSet};
+
+use crate::{
+	formatting::{Formatting, Text},
+	pipeline::Pipeline,
+};
 
 /// Used to identify interline connections
 #[derive(Hash, PartialEq, Eq, Debug, Clone, Copy)]
 pub(crate) struct AnnotationId(pub usize);
 
+/// How to treat malformed annotation ranges
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Strictness {
+	/// Fix what can be fixed, i.e swap bounds of the reversed ranges
+	#[default]
+	Lenient,
+	/// Reject every malformed range
+	Strict,
+}
+
+/// Treatment of annotations with the same ranges, text and severity
+///
+/// Colors are not compared, as [`crate::SnippetBuilder`] picks a new one for every annotation
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Deduplicate {
+	/// Report [`AnnotationError::Duplicate`]
+	#[default]
+	Reject,
+	/// Display only one of them, with the highest priority
+	Collapse,
+	/// Same as [`Deduplicate::Collapse`], but also append number of occurrences to the label
+	Count,
+}
+
+/// Where is the [`Opts::left_margin`] inserted
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum MarginPosition {
+	/// Indent the whole snippet
+	#[default]
+	BeforeGutter,
+	/// Separate line numbers from the source
+	AfterGutter,
+}
+
+/// Numbers displayed in the gutter of the source lines, with [`Opts::line_numbers`]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum GutterNumbers {
+	#[default]
+	Lines,
+	/// Byte offset of the line start in the original text, i.e for binary formats
+	ByteOffsets,
+	/// Line number and byte offset, as `3@24`
+	Both,
+}
+
+/// Terminator of the rendered lines, input line breaks are not affected
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum LineEnding {
+	#[default]
+	Lf,
+	/// `\r\n`, for the Windows consumers
+	CrLf,
+}
+impl LineEnding {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Lf => "\n",
+			Self::CrLf => "\r\n",
+		}
+	}
+}
+
+/// Display column tab stops are counted from, see [`Opts::tab_origin`]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum TabOrigin {
+	/// Start of the source line, tabs are expanded the same way wherever the source is displayed
+	#[default]
+	Line,
+	/// Start of the source text in the rendered snippet, so the tabs are aligned to the same stops
+	/// as when the source is printed by terminal. Gutter width is only known after rendering,
+	/// so the snippet with tabs is rendered twice
+	Gutter,
+	/// Specified display column
+	Column(usize),
+}
+
+/// Which whitespace of the source lines is made visible
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Whitespace {
+	#[default]
+	Hidden,
+	/// Annotated trailing whitespace is replaced with the marker, i.e `'·'` or `'␠'`, keeping its formatting
+	Trailing(char),
+	/// Trailing spaces and tabs of the lines, where they are touched by annotation ranges, as dim `·` and `→`
+	TrailingTouched,
+	/// Spaces and expanded tabs inside of annotation ranges as `·` and `→···`,
+	/// with the dimmed formatting of the annotation
+	Annotated,
+}
+
+/// Colors of the annotations added with [`crate::SnippetBuilder::error`] and other severity methods
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Palette {
+	/// Red errors, orange warnings, green notes and blue infos
+	#[default]
+	Default,
+	/// Doesn't rely on red/green distinction: orange errors, yellow warnings, purple notes and blue infos,
+	/// errors are also displayed in bold, and every severity has its own underline: heavy `━` for errors,
+	/// solid `─` for warnings, dashed `╌` for notes and dotted `┈` for infos
+	ColorBlind,
+}
+impl Palette {
+	/// Hue of the severity without variations, i.e for the diagnostic headers printed next to the snippet
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Palette, Severity};
+	///
+	/// assert_eq!(Palette::Default.formatting(Severity::Error), Formatting::ERROR);
+	/// assert_eq!(Palette::ColorBlind.formatting(Severity::Error), Formatting::ORANGE);
+	/// ```
+	pub fn formatting(self, severity: Severity) -> Formatting {
+		match (self, severity) {
+			(Palette::Default, Severity::Error) => Formatting::ERROR,
+			(Palette::Default, Severity::Warning) => Formatting::WARNING,
+			(Palette::Default, Severity::Note) => Formatting::NOTE,
+			(Palette::Default, Severity::Info) => Formatting::INFO,
+			(Palette::ColorBlind, Severity::Error) => Formatting::ORANGE,
+			(Palette::ColorBlind, Severity::Warning) => Formatting::YELLOW,
+			(Palette::ColorBlind, Severity::Note) => Formatting::PURPLE,
+			(Palette::ColorBlind, Severity::Info) => Formatting::BLUE,
+		}
+	}
+	/// Char used to draw the ranges of the annotation with given severity
+	pub(crate) fn underline(self, severity: Option<Severity>) -> char {
+		use crate::chars::single::*;
+		match (self, severity) {
+			(Palette::Default, _) | (Palette::ColorBlind, None | Some(Severity::Warning)) => {
+				BOTTOM.range_cont
+			}
+			(Palette::ColorBlind, Some(Severity::Error)) => RANGE_CONT_HEAVY,
+			(Palette::ColorBlind, Some(Severity::Note)) => RANGE_CONT_DASHED,
+			(Palette::ColorBlind, Some(Severity::Info)) => RANGE_CONT_DOTTED,
+		}
+	}
+}
+
+/// Kind of the annotation added with [`crate::SnippetBuilder::error`] and other severity methods, ordered from the most severe
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Severity {
+	Error,
+	Warning,
+	Note,
+	Info,
+}
+impl Severity {
+	pub fn name(self) -> &'static str {
+		match self {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+			Severity::Note => "note",
+			Severity::Info => "info",
+		}
+	}
+}
+
+/// Column of the multi-line annotation connector, relative to the connectors of the overlapping annotations
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum ConnectorColumn {
+	/// Right next to the source text, i.e to emphasize the annotation
+	Innermost,
+	/// Shorter connections are placed closer to the source text, so nested ones don't cross the others
+	#[default]
+	Auto,
+	/// Farthest from the source text
+	Outermost,
+}
+
+/// Vertical order of label rows of the nested annotations
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum LabelStacking {
+	/// Label of the containing annotation is placed further from the source line, so no connection crosses it
+	#[default]
+	OutermostFarthest,
+	/// Label of the containing annotation is placed right next to the source line
+	OutermostNearest,
+}
+
+/// Horizontal placement of the annotation label
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum LabelAnchor {
+	/// Right after the rightmost range
+	#[default]
+	Range,
+	/// Flush against [`Opts::max_width`], with the leader drawn from the rightmost range.
+	/// Such labels are never inlined
+	RightMargin,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AnnotationError {
+	/// Range start is located after its end, only reported with [`Strictness::Strict`]
+	ReversedRange {
+		annotation: usize,
+		start: usize,
+		end: usize,
+	},
+	/// Annotation has no ranges to point at
+	EmptyRanges { annotation: usize },
+	/// The same annotation was added twice
+	///
+	/// Every annotation is drawn and connected on its own, use [`Annotation::merge`] to combine them
+	Duplicate { annotation: usize, original: usize },
+	/// Annotations with different formatting can't be merged
+	FormattingMismatch,
+	/// The same [`Annotation::key`] is used by two annotations
+	DuplicateKey {
+		key: u64,
+		annotation: usize,
+		original: usize,
+	},
+	/// Snippet can't be rendered with these [`Opts`]
+	InvalidOpts(OptsError),
+	/// Label is anchored to the right margin, but [`Opts::max_width`] is not set
+	NoMaxWidth { annotation: usize },
+	/// Position passed to [`crate::AnnotationBuilder::line_col`] is past the end of its line, or of the source
+	PositionOutOfBounds {
+		annotation: usize,
+		position: crate::LineCol,
+	},
+}
+impl fmt::Display for AnnotationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ReversedRange {
+				annotation,
+				start,
+				end,
+			} => write!(
+				f,
+				"annotation #{annotation} has reversed range {start}..={end}"
+			),
+			Self::EmptyRanges { annotation } => write!(f, "annotation #{annotation} has no ranges"),
+			Self::Duplicate {
+				annotation,
+				original,
+			} => write!(
+				f,
+				"annotation #{annotation} is a duplicate of annotation #{original}"
+			),
+			Self::FormattingMismatch => {
+				write!(f, "annotations with different formatting can't be merged")
+			}
+			Self::DuplicateKey {
+				key,
+				annotation,
+				original,
+			} => write!(
+				f,
+				"annotation #{annotation} has the same key {key} as annotation #{original}"
+			),
+			Self::InvalidOpts(e) => write!(f, "invalid options: {e}"),
+			Self::NoMaxWidth { annotation } => write!(
+				f,
+				"annotation #{annotation} is anchored to the right margin, but max width is not set"
+			),
+			Self::PositionOutOfBounds {
+				annotation,
+				position,
+			} => write!(
+				f,
+				"annotation #{annotation} has out of bounds position {}:{}",
+				position.line, position.column
+			),
+		}
+	}
+}
+impl std::error::Error for AnnotationError {}
+
+/// Position of the annotation start, as displayed in the snippet
+#[derive(Clone, PartialEq, Debug)]
+pub struct AnnotationSummary {
+	/// Line number, starting from 1
+	pub line: usize,
+	/// Column with expanded tabs, starting from 1
+	pub column: usize,
+	pub text: Text,
+	/// [`Annotation::key`]
+	pub key: Option<u64>,
+	/// Index of the annotation in order of addition, as in [`crate::RenderedLine`] and the JSON runs.
+	/// Merged annotations have the index of the first of them
+	pub annotation: usize,
+}
+
+/// Adjustment made to the annotation to make it renderable
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RenderWarning {
+	/// Range was pointing past the end of text, and was clamped to the end of the last line
+	Clamped { annotation: usize },
+	/// Range start was located after its end, bounds were swapped, only with [`Strictness::Lenient`]
+	ReversedRange {
+		annotation: usize,
+		start: usize,
+		end: usize,
+	},
+	/// Annotation was merged into the identical one, see [`Deduplicate`]
+	Collapsed { annotation: usize, original: usize },
+}
+impl fmt::Display for RenderWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Clamped { annotation } => {
+				write!(f, "annotation #{annotation} was clamped to the end of text")
+			}
+			Self::ReversedRange {
+				annotation,
+				start,
+				end,
+			} => write!(
+				f,
+				"annotation #{annotation} had reversed range {start}..={end}, bounds were swapped"
+			),
+			Self::Collapsed {
+				annotation,
+				original,
+			} => write!(
+				f,
+				"annotation #{annotation} was collapsed into annotation #{original}"
+			),
+		}
+	}
+}
+
+/// Called with the line number and the source line with expanded tabs,
+/// returns char ranges of the line to be styled
+pub type Highlighter = dyn Fn(usize, &str) -> Vec<(ops::Range<usize>, Formatting)> + Send + Sync;
+
+/// Which source lines are displayed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FoldOpts {
+	/// Allow hiding source lines containing no annotations
+	pub enabled: bool,
+	/// Minimum lines of code above and below annotated line
+	pub context_lines: usize,
+	/// Never fold the first line of the source
+	pub anchor_first_line: bool,
+	/// Never fold the last line of the source
+	pub anchor_last_line: bool,
+	/// Shorter runs of folded lines are displayed instead of being replaced with a gap
+	pub min_fold: usize,
+	/// Display numbers of the folded lines after the gap marker
+	pub show_range: bool,
+	/// Only source lines with these numbers, starting from 1, are displayed.
+	///
+	/// Connectors of the multi-line annotations crossing its edges end with `▲`/`▼`, or `↕` when they cross both edges
+	/// of a single line window, instead of appearing to start or end at the edge.
+	/// Window past the end of the source is clamped to its last line
+	pub window: Option<ops::RangeInclusive<usize>>,
+}
+/// Folding enabled, with 2 lines of context around annotations, and runs of a single line are never folded
+impl Default for FoldOpts {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			context_lines: 2,
+			anchor_first_line: false,
+			anchor_last_line: false,
+			min_fold: 2,
+			show_range: false,
+			window: None,
+		}
+	}
+}
+
+/// Colors of the snippet parts not belonging to any annotation
+#[derive(Clone, PartialEq, Debug)]
+pub struct StyleOpts {
+	/// Style of the line numbers, and of the annotation and gap markers
+	pub gutter: Formatting,
+	/// Colors of the annotations added with [`crate::SnippetBuilder::error`] and other severity methods,
+	/// annotations added directly keep their own formatting. Underline chars are picked by [`Annotation::severity`]
+	/// when rendering, so they apply to all annotations
+	pub palette: Palette,
+}
+/// [`Formatting::line_number`] gutter, and [`Palette::Default`]
+impl Default for StyleOpts {
+	fn default() -> Self {
+		Self {
+			gutter: Formatting::line_number(),
+			palette: Palette::default(),
+		}
+	}
+}
+
+/// Contradictory or unrenderable combination of [`Opts`], see [`Opts::validate`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum OptsError {
+	/// Tabs can't be expanded to nothing, as annotations pointing at them would have no width
+	ZeroTabWidth,
+	/// Setting is displayed in a single row, but contains newline
+	Multiline { setting: &'static str },
+	/// Line numbers start from 1, prefix of the line 0 would never be displayed
+	LinePrefixOutOfRange,
+	/// [`FoldOpts::window`] contains no lines, i.e it is reversed, or only contains the line 0
+	EmptyWindow,
+	/// [`Opts::wrap`] is set, but there is no [`Opts::max_width`] to wrap at
+	WrapWithoutMaxWidth,
+	/// [`Opts::max_width`] leaves less than two columns for the source text after the gutter,
+	/// which includes line numbers, [`Opts::gutter_icons`], [`Opts::line_prefixes`] and [`Opts::left_margin`].
+	/// Gutter width depends on the rendered lines, so it is only reported by rendering
+	MaxWidthTooSmall {
+		max_width: usize,
+		gutter_width: usize,
+	},
+}
+impl fmt::Display for OptsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ZeroTabWidth => write!(f, "tab width should be at least 1"),
+			Self::Multiline { setting } => write!(f, "{setting} should not contain newlines"),
+			Self::LinePrefixOutOfRange => write!(f, "line prefixes are numbered from 1"),
+			Self::EmptyWindow => write!(f, "window should contain lines numbered from 1"),
+			Self::WrapWithoutMaxWidth => write!(f, "wrap requires max width to be set"),
+			Self::MaxWidthTooSmall {
+				max_width,
+				gutter_width,
+			} => write!(
+				f,
+				"max width {max_width} doesn't fit the source text after the gutter of width {gutter_width}"
+			),
+		}
+	}
+}
+impl std::error::Error for OptsError {}
+
 #[derive(Clone)]
 pub struct Opts {
-	/// For primary ranges, instead of creating line with range annotaions,
-	/// apply range colors directly to source string. Only useable with colors
+	/// Apply range colors directly to the annotated characters of source string.
+	/// Only useable with colors
 	pub apply_to_orig: bool,
-	/// Allow hiding source lines containing no annotations
-	pub fold: bool,
+	/// For primary ranges, instead of creating line with range annotaions,
+	/// put the label to the right of source line, range itself is then only marked by
+	/// the colors applied with [`Opts::apply_to_orig`], and has no effect without it
+	pub inline_labels: bool,
+	/// Which source lines are displayed, lines far from annotations are replaced with gaps
+	pub fold: FoldOpts,
+	/// Columns between tab stops, tabs are expanded to spaces before rendering
 	pub tab_width: usize,
+	/// Display column tab stops are counted from
+	pub tab_origin: TabOrigin,
 
-	/// Minimum lines of code above and below annotated line
-	pub context_lines: usize,
+	/// Checked while building annotations, as reversed ranges can't be represented in [`Annotation`]
+	pub strictness: Strictness,
+	/// Draw frame around the whole snippet, with the specified title embedded into its top border
+	pub frame: Option<Text>,
+	/// Display the row mapping colors and underlines to severities under the snippet, only severities of the drawn annotations are listed
+	pub legend: bool,
+	/// Terminator written after every rendered line
+	pub line_ending: LineEnding,
+	/// Write [`Opts::line_ending`] after the last line too, disable to join the snippet into other text
+	pub final_line_ending: bool,
+	/// Icon displayed after the line number, for the most severe annotation on the line.
+	/// Every line gets a cell as wide as the widest icon, lines without icons have it blank
+	pub gutter_icons: BTreeMap<Severity, Text>,
+	/// Display line numbers in the gutter, without them gutter is only two chars wide
+	pub line_numbers: bool,
+	/// What the numbers of source lines in the gutter are counting
+	pub gutter_numbers: GutterNumbers,
+	/// Replaces the space after line number, and the `·` marker of annotation lines, gap lines keep their `⋮` marker
+	pub gutter_separator: Option<String>,
+	/// Display column numbers above each displayed part of the source
+	pub column_ruler: bool,
+	/// Remove leading whitespace shared by all the displayed source lines,
+	/// lines which are folded away, and blank lines don't affect the removed width
+	pub dedent: bool,
+	/// Replaces default decimal line numbers, gutter is as wide as the widest formatted number
+	pub line_number_formatter: Option<Arc<dyn Fn(usize) -> String + Send + Sync>>,
+	/// Number of spaces every rendered line is indented with
+	pub left_margin: usize,
+	/// Whether [`Opts::left_margin`] indents the whole snippet, or separates the gutter from the source
+	pub margin_position: MarginPosition,
+	/// Whitespace of the source lines made visible with glyphs, only near annotation ranges
+	pub whitespace: Whitespace,
+	/// Glyph to display the end of file with, only when it is annotated
+	pub eof_marker: Option<char>,
+	/// Don't display the cell reserved for the spans pointing to EOL, on the lines where no annotation covers it
+	pub trim_eol: bool,
+	/// Treatment of the annotations with the same ranges, text and severity
+	pub deduplicate: Deduplicate,
+	/// Merge single-line annotations with the same text, severity and key on consecutive lines,
+	/// so their label is displayed once, with the ranges connected
+	pub collapse_adjacent: bool,
+	/// Labels of single-line annotations over this limit are elided, their ranges are still displayed.
+	/// Limit higher than [`Opts::max_annotations_per_line`] is never reached
+	pub max_labels_per_line: Option<usize>,
+	/// Colors of the gutter, and the palette of the builder severity methods
+	pub style: StyleOpts,
+	/// Vertical order of the label rows, when annotations are nested
+	pub label_stacking: LabelStacking,
+	/// Range starts in the row adjacent to the source line are replaced with arrow heads pointing at it
+	pub range_pointers: bool,
+	/// Syntax highlighting of the source lines, applied before annotation colors, folded lines are skipped
+	pub highlight: Option<Arc<Highlighter>>,
+	/// Stages of rendering, [`Pipeline::default`] is used if not set
+	pub pipeline: Option<Arc<Pipeline>>,
+	/// Displayed before the gutter of the source lines, by line number starting from 1
+	pub line_prefixes: BTreeMap<usize, Text>,
+	/// Single-line annotations over this limit are not displayed at all, unlike with [`Opts::max_labels_per_line`]
+	pub max_annotations_per_line: Option<usize>,
+	/// Invert [`Annotation::priority`], so the annotation with the lowest value wins, i.e for severity-like numbering
+	pub lower_priority_wins: bool,
+	/// Display width of the rendered lines, not counting the frame, labels with [`LabelAnchor::RightMargin`] end at it.
+	/// Lines are not truncated to it, but are wrapped with [`Opts::wrap`]
+	pub max_width: Option<usize>,
+	/// Wrap source lines wider than [`Opts::max_width`], continuation rows have `↳` in place of the line number.
+	/// Annotation rows are not wrapped, their ranges stay aligned with the first row
+	pub wrap: bool,
+}
+/// Labels are inlined and ranges are colored, tabs are 4 columns wide, and lines are numbered.
+/// Nothing is limited, and no decorations (frame, ruler, margin, prefixes) are drawn
+impl Default for Opts {
+	fn default() -> Self {
+		Self {
+			apply_to_orig: true,
+			inline_labels: true,
+			fold: FoldOpts::default(),
+			tab_width: 4,
+			tab_origin: TabOrigin::default(),
+			strictness: Strictness::default(),
+			frame: None,
+			legend: false,
+			line_ending: LineEnding::default(),
+			final_line_ending: true,
+			gutter_icons: BTreeMap::new(),
+			line_numbers: true,
+			gutter_numbers: GutterNumbers::default(),
+			gutter_separator: None,
+			column_ruler: false,
+			dedent: false,
+			line_number_formatter: None,
+			left_margin: 0,
+			margin_position: MarginPosition::default(),
+			whitespace: Whitespace::Hidden,
+			eof_marker: None,
+			trim_eol: false,
+			deduplicate: Deduplicate::default(),
+			collapse_adjacent: false,
+			max_labels_per_line: None,
+			style: StyleOpts::default(),
+			label_stacking: LabelStacking::default(),
+			range_pointers: false,
+			highlight: None,
+			pipeline: None,
+			line_prefixes: BTreeMap::new(),
+			max_annotations_per_line: None,
+			lower_priority_wins: false,
+			max_width: None,
+			wrap: false,
+		}
+	}
+}
+impl Opts {
+	/// Checked before rendering, reports the first found problem
+	pub fn validate(&self) -> Result<(), OptsError> {
+		if self.tab_width == 0 {
+			return Err(OptsError::ZeroTabWidth);
+		}
+		let multiline = |text: &Text| text.data().any(|c| *c == '\n');
+		if self.frame.as_ref().is_some_and(multiline) {
+			return Err(OptsError::Multiline { setting: "frame" });
+		}
+		if self
+			.gutter_separator
+			.as_ref()
+			.is_some_and(|s| s.contains('\n'))
+		{
+			return Err(OptsError::Multiline {
+				setting: "gutter separator",
+			});
+		}
+		if self.line_prefixes.values().any(multiline) {
+			return Err(OptsError::Multiline {
+				setting: "line prefix",
+			});
+		}
+		if self.gutter_icons.values().any(multiline) {
+			return Err(OptsError::Multiline {
+				setting: "gutter icon",
+			});
+		}
+		if self.line_prefixes.contains_key(&0) {
+			return Err(OptsError::LinePrefixOutOfRange);
+		}
+		if self
+			.fold
+			.window
+			.as_ref()
+			.is_some_and(|w| w.is_empty() || *w.end() == 0)
+		{
+			return Err(OptsError::EmptyWindow);
+		}
+		if self.wrap && self.max_width.is_none() {
+			return Err(OptsError::WrapWithoutMaxWidth);
+		}
+		Ok(())
+	}
+	/// Checks the annotation settings which depend on the options, i.e [`LabelAnchor::RightMargin`] requires
+	/// [`Opts::max_width`]. Done for every annotation before rendering, `index` is reported in the error
+	pub fn validate_annotation(
+		&self,
+		index: usize,
+		annotation: &Annotation,
+	) -> Result<(), AnnotationError> {
+		if annotation.label_anchor == LabelAnchor::RightMargin && self.max_width.is_none() {
+			return Err(AnnotationError::NoMaxWidth { annotation: index });
+		}
+		Ok(())
+	}
 }
 
-#[derive(Clone)]
+/// Callbacks are only displayed as `<fn>`
+impl fmt::Debug for Opts {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Opts")
+			.field("apply_to_orig", &self.apply_to_orig)
+			.field("inline_labels", &self.inline_labels)
+			.field("fold", &self.fold)
+			.field("tab_width", &self.tab_width)
+			.field("tab_origin", &self.tab_origin)
+			.field("strictness", &self.strictness)
+			.field("frame", &self.frame)
+			.field("legend", &self.legend)
+			.field("line_ending", &self.line_ending)
+			.field("final_line_ending", &self.final_line_ending)
+			.field("gutter_icons", &self.gutter_icons)
+			.field("line_numbers", &self.line_numbers)
+			.field("gutter_numbers", &self.gutter_numbers)
+			.field("gutter_separator", &self.gutter_separator)
+			.field("column_ruler", &self.column_ruler)
+			.field("dedent", &self.dedent)
+			.field(
+				"line_number_formatter",
+				&self.line_number_formatter.as_ref().map(|_| "<fn>"),
+			)
+			.field("left_margin", &self.left_margin)
+			.field("margin_position", &self.margin_position)
+			.field("whitespace", &self.whitespace)
+			.field("eof_marker", &self.eof_marker)
+			.field("trim_eol", &self.trim_eol)
+			.field("deduplicate", &self.deduplicate)
+			.field("collapse_adjacent", &self.collapse_adjacent)
+			.field("max_labels_per_line", &self.max_labels_per_line)
+			.field("style", &self.style)
+			.field("label_stacking", &self.label_stacking)
+			.field("range_pointers", &self.range_pointers)
+			.field("highlight", &self.highlight.as_ref().map(|_| "<fn>"))
+			.field("pipeline", &self.pipeline)
+			.field("line_prefixes", &self.line_prefixes)
+			.field("max_annotations_per_line", &self.max_annotations_per_line)
+			.field("lower_priority_wins", &self.lower_priority_wins)
+			.field("max_width", &self.max_width)
+			.field("wrap", &self.wrap)
+			.finish()
+	}
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Annotation {
+	/// Higher priority wins every conflict: its color is painted over the overlapping ranges,
+	/// its label is kept by the label limits, and labels at the same position, with their connectors,
+	/// are ordered by it. Direction is inverted by [`Opts::lower_priority_wins`]
 	pub priority: usize,
 	pub formatting: Formatting,
 	/// Byte ranges of the annotated regions
 	/// Should not be empty
+	///
+	/// Offset of the newline char, or the end of text points to the EOL of the line,
+	/// ranges past the end of text are clamped to the EOL of the last line
 	pub ranges: RangeSet<usize>,
 	pub text: Text,
+	/// Draw connector between lines of the multi-line annotation, without it every line
+	/// only has its ranges underlined, and the label is displayed on the last line
+	pub connect_lines: bool,
+	/// Stable identifier, i.e of the user diagnostic, which is reported back in [`AnnotationSummary`]
+	///
+	/// Should be unique, annotations with different keys are never deduplicated
+	pub key: Option<u64>,
+	/// Set by [`crate::SnippetBuilder::error`] and other severity methods, used for [`Opts::gutter_icons`]
+	pub severity: Option<Severity>,
+	/// Overrides placement of the connector, only used with [`Annotation::connect_lines`]
+	pub connector_column: ConnectorColumn,
+	/// Where the label is placed on the last annotated line
+	pub label_anchor: LabelAnchor,
+}
+
+impl Annotation {
+	/// Annotation with connected lines, zero priority, and no key or severity,
+	/// other fields may be set with the struct update syntax
+	///
+	/// ```
+	/// use ass_stroke::{Annotation, Formatting, Text};
+	/// use range_map::Range;
+	///
+	/// let annotation = Annotation {
+	///     priority: 1,
+	///     ..Annotation::new(
+	///         [Range::new(4, 4)].into_iter().collect(),
+	///         Text::from("here"),
+	///         Formatting::ERROR,
+	///     )
+	/// };
+	/// assert!(annotation.connect_lines);
+	/// ```
+	pub fn new(ranges: RangeSet<usize>, text: Text, formatting: Formatting) -> Self {
+		Self {
+			priority: 0,
+			formatting,
+			ranges,
+			text,
+			connect_lines: true,
+			key: None,
+			severity: None,
+			connector_column: ConnectorColumn::Auto,
+			label_anchor: LabelAnchor::Range,
+		}
+	}
+	/// Combine two annotations into one, with ranges of both and texts placed on separate lines.
+	///
+	/// Both annotations should have the same formatting, as only one color may be used for connections
+	pub fn merge(mut self, other: Annotation) -> Result<Self, AnnotationError> {
+		if self.formatting != other.formatting {
+			return Err(AnnotationError::FormattingMismatch);
+		}
+		self.priority = self.priority.max(other.priority);
+		self.ranges = self.ranges.union(&other.ranges);
+		self.severity = [self.severity, other.severity].into_iter().flatten().min();
+		if self.text.is_empty() {
+			self.text = other.text;
+		} else if !other.text.is_empty() {
+			self.text
+				.extend(Text::single(['\n'], Formatting::default()));
+			self.text.extend(other.text);
+		}
+		Ok(self)
+	}
+	/// Byte ranges of every occurrence of `pattern` in `txt`, for [`Annotation::ranges`].
+	///
+	/// Overlapping and adjacent occurrences are merged, matches may span multiple lines.
+	/// Set is empty if there are no matches, or the pattern is empty
+	///
+	/// ```
+	/// use ass_stroke::Annotation;
+	/// use range_map::Range;
+	///
+	/// let ranges = Annotation::ranges_from_matches("let x = x + 1;", "x");
+	/// assert_eq!(ranges.ranges().collect::<Vec<_>>(), [Range::new(4, 4), Range::new(8, 8)]);
+	/// ```
+	pub fn ranges_from_matches(txt: &str, pattern: &str) -> RangeSet<usize> {
+		let Some(first) = pattern.chars().next() else {
+			return RangeSet::new();
+		};
+		let mut ranges = Vec::new();
+		let mut from = 0;
+		while let Some(found) = txt[from..].find(pattern) {
+			let start = from + found;
+			ranges.push(Range::new(start, start + pattern.len() - 1));
+			// Next search starts inside of the match, to find the overlapping ones
+			from = start + first.len_utf8();
+		}
+		ranges.into_iter().collect()
+	}
+	/// Same as [`Annotation::ranges_from_matches`], but with the regex pattern, empty matches are skipped
+	#[cfg(feature = "regex")]
+	pub fn ranges_from_regex(txt: &str, pattern: &regex::Regex) -> RangeSet<usize> {
+		pattern
+			.find_iter(txt)
+			.filter(|m| !m.is_empty())
+			.map(|m| Range::new(m.start(), m.end() - 1))
+			.collect()
+	}
+}
+
+/// Source of unique [`Annotation::key`]s
+///
+/// Clones share the same counter, so keys may be allocated from multiple threads.
+/// Keys are only required to be unique within one snippet, allocator may be shared between snippets,
+/// but then the same diagnostic rendered twice needs to keep its key instead of allocating a new one
+#[derive(Clone, Default, Debug)]
+pub struct KeyAllocator(Arc<AtomicU64>);
+impl KeyAllocator {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	pub fn next(&self) -> u64 {
+		self.0.fetch_add(1, Ordering::Relaxed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use range_map::Range;
+
+	use super::*;
+
+	fn annotation(range: (usize, usize), text: &str, formatting: Formatting) -> Annotation {
+		Annotation::new(
+			[Range::new(range.0, range.1)].into_iter().collect(),
+			text.into(),
+			formatting,
+		)
+	}
+
+	#[test]
+	fn merge_same_formatting() {
+		let a = annotation((0, 2), "first", Formatting::color(0xff000000));
+		let b = annotation((6, 8), "second", Formatting::color(0xff000000));
+		let merged = a.merge(b).expect("same formatting");
+		assert_eq!(
+			merged.ranges,
+			[Range::new(0, 2), Range::new(6, 8)].into_iter().collect()
+		);
+		assert_eq!(merged.text.data().collect::<String>(), "first\nsecond");
+	}
+
+	#[test]
+	fn merge_different_formatting() {
+		let a = annotation((0, 2), "first", Formatting::color(0xff000000));
+		let b = annotation((6, 8), "second", Formatting::color(0x00ff0000));
+		assert_eq!(a.merge(b).err(), Some(AnnotationError::FormattingMismatch));
+	}
+
+	fn ranges(set: RangeSet<usize>) -> Vec<(usize, usize)> {
+		set.ranges().map(|r| (r.start, r.end)).collect()
+	}
+
+	#[test]
+	fn matches_overlapping() {
+		assert_eq!(
+			ranges(Annotation::ranges_from_matches("aaa b aa", "aa")),
+			[(0, 2), (6, 7)]
+		);
+		assert_eq!(ranges(Annotation::ranges_from_matches("abc", "")), []);
+		assert_eq!(ranges(Annotation::ranges_from_matches("abc", "d")), []);
+	}
+
+	#[test]
+	fn matches_multiline() {
+		let txt = "a = 1;\nb = a;\nb = 2;";
+		assert_eq!(
+			ranges(Annotation::ranges_from_matches(txt, ";\nb")),
+			[(5, 7), (12, 14)]
+		);
+	}
+
+	#[test]
+	fn matches_multibyte() {
+		let txt = "let имя = \"имя\";";
+		let found = ranges(Annotation::ranges_from_matches(txt, "имя"));
+		assert_eq!(found, [(4, 9), (14, 19)]);
+		assert_eq!(&txt[found[1].0..=found[1].1], "имя");
+		// Overlapping matches are searched from the next char, not from the next byte
+		assert_eq!(
+			ranges(Annotation::ranges_from_matches("яяя", "яя")),
+			[(0, 5)]
+		);
+	}
+
+	#[cfg(feature = "regex")]
+	#[test]
+	fn matches_regex() {
+		let pattern = regex::Regex::new(r"\bx\b|z*").unwrap();
+		assert_eq!(
+			ranges(Annotation::ranges_from_regex("let x = xy + x;", &pattern)),
+			[(4, 4), (13, 13)]
+		);
+	}
+
+	#[test]
+	fn key_allocator_threads() {
+		let allocator = KeyAllocator::new();
+		let threads = (0..8)
+			.map(|_| {
+				let allocator = allocator.clone();
+				std::thread::spawn(move || (0..1000).map(|_| allocator.next()).collect::<Vec<_>>())
+			})
+			.collect::<Vec<_>>();
+		let mut keys = threads
+			.into_iter()
+			.flat_map(|t| t.join().expect("no panic"))
+			.collect::<Vec<_>>();
+		keys.sort_unstable();
+		keys.dedup();
+		assert_eq!(keys.len(), 8000);
+	}
+
+	#[test]
+	fn opts_are_send_sync() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<Opts>();
+	}
+
+	#[test]
+	fn validate_default() {
+		assert_eq!(Opts::default().validate(), Ok(()));
+	}
+
+	#[test]
+	fn validate_invalid() {
+		let zero_tab = Opts {
+			tab_width: 0,
+			tab_origin: TabOrigin::Line,
+			..Opts::default()
+		};
+		assert_eq!(zero_tab.validate(), Err(OptsError::ZeroTabWidth));
+
+		let multiline_frame = Opts {
+			frame: Some("title\nsubtitle".into()),
+			..Opts::default()
+		};
+		assert_eq!(
+			multiline_frame.validate(),
+			Err(OptsError::Multiline { setting: "frame" })
+		);
+
+		let prefix_zero = Opts {
+			line_prefixes: [(0, "+".into())].into_iter().collect(),
+			..Opts::default()
+		};
+		assert_eq!(prefix_zero.validate(), Err(OptsError::LinePrefixOutOfRange));
+
+		#[allow(clippy::reversed_empty_ranges)]
+		for window in [3..=2, 0..=0] {
+			let empty_window = Opts {
+				fold: FoldOpts {
+					window: Some(window),
+					..FoldOpts::default()
+				},
+				..Opts::default()
+			};
+			assert_eq!(empty_window.validate(), Err(OptsError::EmptyWindow));
+		}
+
+		let wrap = Opts {
+			wrap: true,
+			..Opts::default()
+		};
+		assert_eq!(wrap.validate(), Err(OptsError::WrapWithoutMaxWidth));
+
+		let limits = Opts {
+			max_labels_per_line: Some(3),
+			max_annotations_per_line: Some(2),
+			..Opts::default()
+		};
+		// Label limit is never reached, but it is harmless
+		assert_eq!(limits.validate(), Ok(()));
+	}
 }
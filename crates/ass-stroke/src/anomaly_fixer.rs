@@ -162,8 +162,14 @@ thread_local! {
 ///
 /// Characters, which should be displayed as-is, but whose occupy more that one column, will be kept as is, and offsets will be fixed later
 ///
+/// Tab stops are counted from `tab_origin` display column, which is the column every line starts at.
+///
 /// Returns fixups to convert byte offsets to char offsets using [`apply_fixups`]
-pub fn fixup_byte_to_char(mut text: &str, tab_width: usize) -> (String, BTreeMap<usize, isize>) {
+pub fn fixup_byte_to_char(
+	mut text: &str,
+	tab_width: usize,
+	tab_origin: usize,
+) -> (String, BTreeMap<usize, isize>) {
 	let mut fixups = BTreeMap::new();
 	let mut out = String::new();
 	let mut fixup = |byte_offset: usize, source_bytes: usize, output_chars: usize| {
@@ -173,18 +179,21 @@ pub fn fixup_byte_to_char(mut text: &str, tab_width: usize) -> (String, BTreeMap
 	};
 
 	let mut total_byte_offset = 0;
-	let mut display_offset_since_newline = 0;
+	let mut display_offset_since_newline = tab_origin;
 	loop {
 		let mut current_segment_offset = 0;
 		for char in text.chars().take_while(|char| {
-			*char == '\n' || *char == ' ' || !NONSTANDARD_WIDTH.with(|r| r.contains(*char as u32))
+			*char == '\n'
+				|| *char == ' '
+				|| !(is_line_separator(*char)
+					|| NONSTANDARD_WIDTH.with(|r| r.contains(*char as u32)))
 		}) {
 			let char_bytes = char.len_utf8();
 			fixup(total_byte_offset, char_bytes, 1);
 			current_segment_offset += char_bytes;
 			total_byte_offset += char_bytes;
 			if char == '\n' {
-				display_offset_since_newline = 0;
+				display_offset_since_newline = tab_origin;
 			} else {
 				display_offset_since_newline += if is_fullwidth(char) { 2 } else { 1 };
 			}
@@ -203,7 +212,7 @@ pub fn fixup_byte_to_char(mut text: &str, tab_width: usize) -> (String, BTreeMap
 			'\t' => {
 				let mut size = 1;
 				display_offset_since_newline += 1;
-				while display_offset_since_newline % tab_width != 0 {
+				while !display_offset_since_newline.is_multiple_of(tab_width) {
 					display_offset_since_newline += 1;
 					size += 1;
 				}
@@ -214,20 +223,23 @@ pub fn fixup_byte_to_char(mut text: &str, tab_width: usize) -> (String, BTreeMap
 				fixup(total_byte_offset, 1, size);
 				total_byte_offset += 1;
 			}
-			'\r' if bytes[1] == b'\n' => {
+			'\r' if bytes.get(1) == Some(&b'\n') => {
 				out.push('\n');
 				text = &text[2..];
 				fixup(total_byte_offset, 2, 1);
 				total_byte_offset += 2;
 
-				display_offset_since_newline = 0;
+				display_offset_since_newline = tab_origin;
 			}
-			'\r' => {
-				out.push_str("<CR>");
-				text = &text[1..];
-				fixup(total_byte_offset, 1, 4);
-				display_offset_since_newline += 4;
-				total_byte_offset += 1;
+			// Lone CR (classic Mac OS), and unicode separators are displayed as line breaks, same as by editors
+			'\r' | '\u{2028}' | '\u{2029}' => {
+				out.push('\n');
+				let size = char.len_utf8();
+				text = &text[size..];
+				fixup(total_byte_offset, size, 1);
+				total_byte_offset += size;
+
+				display_offset_since_newline = tab_origin;
 			}
 			'\0' => {
 				out.push_str("<NUL>");
@@ -251,6 +263,27 @@ pub fn fixup_byte_to_char(mut text: &str, tab_width: usize) -> (String, BTreeMap
 	(out, fixups)
 }
 
+/// Byte offsets of every line start in the original text, lines are split the same way as by [`fixup_byte_to_char`]
+pub fn byte_linestarts(text: &str) -> Vec<usize> {
+	let mut out = vec![0];
+	let mut chars = text.char_indices().peekable();
+	while let Some((i, c)) = chars.next() {
+		match c {
+			// Next line starts after `\n`
+			'\r' if chars.peek().is_some_and(|(_, c)| *c == '\n') => {}
+			'\n' | '\r' => out.push(i + 1),
+			c if is_line_separator(c) => out.push(i + c.len_utf8()),
+			_ => {}
+		}
+	}
+	out
+}
+
+/// Unicode line and paragraph separators, which are displayed as line breaks
+fn is_line_separator(c: char) -> bool {
+	matches!(c, '\u{2028}' | '\u{2029}')
+}
+
 fn is_fullwidth(c: char) -> bool {
 	let ucs = c as u32;
 	ucs >= 0x1100
@@ -274,6 +307,15 @@ fn is_fullwidth(c: char) -> bool {
 			(0x30000..=0x3fffd).contains(&ucs))
 }
 
+/// Number of terminal cells used to display char
+pub fn char_width(c: char) -> usize {
+	if is_fullwidth(c) {
+		2
+	} else {
+		1
+	}
+}
+
 /// Some of the unicode codepoints require two columns to display, this function generates fixup to adjust
 /// char position to display
 pub fn fixup_char_to_display(text: impl Iterator<Item = char>) -> BTreeMap<usize, isize> {
@@ -300,6 +342,26 @@ pub fn apply_fixup(offset: &mut usize, fixups: &BTreeMap<usize, isize>) {
 		}
 	}
 }
+/// Same as [`apply_fixup`], but for the inclusive range end: offset is moved to the last char,
+/// produced from the source char, i.e to the last space of the expanded tab
+///
+/// `output_len` is the number of chars in the processed text
+pub fn apply_fixup_end(offset: &mut usize, fixups: &BTreeMap<usize, isize>, output_len: usize) {
+	// Fixup is recorded for every processed source char, offsets without one are past the end of text
+	if !fixups.contains_key(offset) {
+		apply_fixup(offset, fixups);
+		return;
+	}
+	match fixups.range(*offset + 1..).next() {
+		Some((next, _)) => {
+			*offset = *next;
+			apply_fixup(offset, fixups);
+			*offset -= 1;
+		}
+		None => *offset = output_len - 1,
+	}
+}
+#[cfg(test)]
 pub fn apply_fixups(offsets: &mut [usize], fixups: &BTreeMap<usize, isize>) {
 	for offset in offsets.iter_mut() {
 		apply_fixup(offset, fixups)
@@ -312,25 +374,57 @@ mod replacements {
 
 	#[test]
 	fn cr() {
-		let (out, map) = fixup_byte_to_char("\rhello", 4);
+		let (out, map) = fixup_byte_to_char("\rhello", 4, 0);
 		let mut offsets = [0, 1];
 		apply_fixups(&mut offsets, &map);
-		assert_eq!(out, "<CR>hello");
-		assert_eq!(offsets, [0, 4]);
+		assert_eq!(out, "\nhello");
+		assert_eq!(offsets, [0, 1]);
+
+		let (out, _) = fixup_byte_to_char("a\r\nb\r", 4, 0);
+		assert_eq!(out, "a\nb\n");
+	}
+
+	#[test]
+	fn line_separators() {
+		let (out, map) = fixup_byte_to_char("a\u{2028}b\u{2029}\tc", 4, 0);
+		// a, separator, b, separator, tab, c
+		let mut offsets = [0, 1, 4, 5, 8, 9];
+		apply_fixups(&mut offsets, &map);
+		assert_eq!(out, "a\nb\n    c");
+		assert_eq!(offsets, [0, 1, 2, 3, 4, 8]);
+	}
+
+	#[test]
+	fn byte_linestarts() {
+		let text = "a\r\nbc\rd\u{2028}\te\nf";
+		let starts = super::byte_linestarts(text);
+		assert_eq!(starts, [0, 3, 6, 10, 13]);
+		let (out, _) = fixup_byte_to_char(text, 4, 0);
+		assert_eq!(out.lines().count(), starts.len());
 	}
 
 	#[test]
 	fn tab() {
-		let (out, map) = fixup_byte_to_char("\t\thello", 2);
+		let (out, map) = fixup_byte_to_char("\t\thello", 2, 0);
 		let mut offsets = [0, 1, 2];
 		apply_fixups(&mut offsets, &map);
 		assert_eq!(out, "    hello");
 		assert_eq!(offsets, [0, 2, 4]);
 	}
 
+	#[test]
+	fn tab_origin() {
+		// Line starts at the 3rd display column, i.e after `1 ` gutter
+		let (out, map) = fixup_byte_to_char("a\t\tb\n\tc", 4, 2);
+		let mut offsets = [0, 1, 2, 3, 4, 5];
+		apply_fixups(&mut offsets, &map);
+		assert_eq!(out, "a     b\n  c");
+		assert_eq!(offsets, [0, 1, 2, 6, 7, 8]);
+	}
+
 	#[test]
 	fn combining() {
-		let (out, map) = fixup_byte_to_char("\u{0610}", 4);
+		let (out, map) = fixup_byte_to_char("\u{0610}", 4, 0);
 		let mut offsets = [0, 2];
 		apply_fixups(&mut offsets, &map);
 		assert_eq!(out, "<U+0610>");
@@ -339,7 +433,7 @@ mod replacements {
 
 	#[test]
 	fn combining_emoji() {
-		let (out, map) = fixup_byte_to_char("👨‍👨‍👧‍👧", 4);
+		let (out, map) = fixup_byte_to_char("👨‍👨‍👧‍👧", 4, 0);
 		let mut offsets = [0, 4, 7, 11, 14];
 		apply_fixups(&mut offsets, &map);
 		assert_eq!(out, "👨<U+200D>👨<U+200D>👧<U+200D>👧");
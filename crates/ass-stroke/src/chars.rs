@@ -1,4 +1,9 @@
 //! Definitions of used formatting characters and how to act when they are overlapped during formatting
+//!
+//! Every `cross` function returns the replacement char, and whether the style of the crossed char should be kept,
+//! or `None` if the char can't be crossed, i.e it is already crossed, or is unknown.
+//! Blank cells and straight lines perpendicular to the crossing line take the style of the crossing line, as it is drawn
+//! over them, while corners, junctions and already crossed lines keep their own style, as only a stroke is added to them
 
 /// Line connections
 pub(crate) mod line {
@@ -25,6 +30,29 @@ pub(crate) mod line {
 	pub const RANGE_CONTINUE_CROSS: char = '┼';
 	pub const RANGE_CONTINUE_CROSS_CROSS: char = '┿';
 
+	/// Replace the range start or end, when the connection continues past the displayed lines
+	pub const RANGE_CONTINUED_ABOVE: char = '▲';
+	pub const RANGE_CONTINUED_BELOW: char = '▼';
+	/// Both range start and end are outside of the single displayed line
+	pub const RANGE_CONTINUED_BOTH: char = '↕';
+
+	/// Connection is drawn below the char, so it is continued in the wrapped rows
+	pub fn continues_below(char: char) -> bool {
+		matches!(
+			char,
+			RANGE_START
+				| RANGE_START_CROSS
+				| RANGE_START_CROSS_CROSS
+				| RANGE_CONNECTION
+				| RANGE_CONNECTION_CROSS
+				| RANGE_CONNECTION_CROSS_CROSS
+				| RANGE_CONTINUE
+				| RANGE_CONTINUE_CROSS
+				| RANGE_CONTINUED_ABOVE
+				| RANGE_CONTINUED_BOTH
+		)
+	}
+
 	pub fn cross(char: char) -> Option<(bool, char)> {
 		match char {
 			CONTINUE => Some((true, CONTINUE_CROSS)),
@@ -50,13 +78,25 @@ pub(crate) mod line {
 			RANGE_CONTINUE => Some((false, RANGE_CONTINUE_CROSS)),
 			RANGE_CONTINUE_CROSS => Some((true, RANGE_CONTINUE_CROSS_CROSS)),
 
+			RANGE_CONTINUED_ABOVE | RANGE_CONTINUED_BELOW | RANGE_CONTINUED_BOTH => None,
+
 			' ' => Some((false, CONTINUE)),
 
-			c => unreachable!("{c:?}"),
+			_ => None,
 		}
 	}
 }
 
+/// Frame around the whole snippet
+pub(crate) mod frame {
+	pub const TOP_LEFT: char = '╭';
+	pub const TOP_RIGHT: char = '╮';
+	pub const BOTTOM_LEFT: char = '╰';
+	pub const BOTTOM_RIGHT: char = '╯';
+	pub const HORIZONTAL: char = '─';
+	pub const VERTICAL: char = '│';
+}
+
 /// Lines from range to annotation text/line connection
 pub mod arrow {
 	pub struct Chars {
@@ -113,20 +153,22 @@ pub mod arrow {
 			#[allow(unreachable_patterns)]
 			x if x == chars.arrow_cont_x_x || x == chars.arrow_rl_x_x => None,
 
-			x if x == chars.arrow_r => Some((false, chars.arrow_r_x)),
+			x if x == chars.arrow_r => Some((true, chars.arrow_r_x)),
 			x if x == chars.arrow_r_x => Some((true, chars.arrow_r_x_x)),
 			x if x == chars.arrow_r_x_x => None,
 
-			x if x == chars.arrow_l => Some((false, chars.arrow_l_x)),
+			x if x == chars.arrow_l => Some((true, chars.arrow_l_x)),
 			x if x == chars.arrow_l_x => Some((true, chars.arrow_l_x_x)),
 			x if x == chars.arrow_l_x_x => None,
 
-			x if x == chars.arrow_rl => Some((false, chars.arrow_rl_x)),
+			x if x == chars.arrow_rl => Some((true, chars.arrow_rl_x)),
 			x if x == chars.arrow_rl_x => Some((true, chars.arrow_rl_x_x)),
 
+			x if x == chars.arrow_inline => None,
+
 			' ' => Some((false, chars.cont)),
 
-			c => unreachable!("{c:?}"),
+			_ => None,
 		}
 	}
 }
@@ -144,6 +186,8 @@ pub(crate) mod single {
 		pub range_end: char,
 		pub range_end_x: char,
 		pub range_end_x_x: char,
+		/// Replaces the range start in the row adjacent to the source line, points towards the source
+		pub pointer: char,
 	}
 	pub static BOTTOM: Chars = Chars {
 		cont: '│',
@@ -159,13 +203,21 @@ pub(crate) mod single {
 		range_end: '╯',
 		range_end_x: '┦',
 		range_end_x_x: '┨',
+		pointer: '▲',
 	};
 	pub static TOP: Chars = Chars {
 		range_end: '╮',
 		range_end_x: '┧',
+		pointer: '▼',
 		..BOTTOM
 	};
 
+	/// Range continuations used by [`crate::Palette::ColorBlind`] to tell severities apart without hue
+	pub const RANGE_CONT_HEAVY: char = '━';
+	pub const RANGE_CONT_HEAVY_X: char = '┿';
+	pub const RANGE_CONT_DASHED: char = '╌';
+	pub const RANGE_CONT_DOTTED: char = '┈';
+
 	pub fn cross(chars: &Chars, char: char) -> Option<(bool, char)> {
 		match char {
 			x if x == chars.cont => Some((true, chars.cont_x)),
@@ -177,14 +229,32 @@ pub(crate) mod single {
 			x if x == chars.range_cont => Some((false, chars.range_cont_x)),
 			x if x == chars.range_cont_x => Some((true, chars.range_cont_x_x)),
 			x if x == chars.range_cont_x_x => None,
+			RANGE_CONT_HEAVY => Some((false, RANGE_CONT_HEAVY_X)),
+			RANGE_CONT_DASHED | RANGE_CONT_DOTTED => Some((false, chars.range_cont_x)),
+			RANGE_CONT_HEAVY_X => None,
 
 			x if x == chars.range_end => Some((true, chars.range_end_x)),
 			x if x == chars.range_end_x => Some((true, chars.range_end_x_x)),
 			x if x == chars.range_end_x_x => None,
 
+			x if x == chars.pointer => None,
+
 			' ' => Some((false, chars.cont)),
 
-			c => unreachable!("{c:?}"),
+			_ => None,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{arrow, line, single};
+
+	#[test]
+	fn unknown_chars_are_not_crossed() {
+		assert_eq!(line::cross('x'), None);
+		assert_eq!(arrow::cross(&arrow::BOTTOM, 'x'), None);
+		assert_eq!(single::cross(&single::TOP, 'x'), None);
+		assert_eq!(single::cross(&single::TOP, single::TOP.pointer), None);
+	}
+}
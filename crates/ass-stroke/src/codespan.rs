@@ -0,0 +1,197 @@
+//! Conversion of [`codespan_reporting`] diagnostics, to try this renderer without rewriting their construction
+//!
+//! ```
+//! use ass_stroke::codespan;
+//! use codespan_reporting::{
+//!     diagnostic::{Diagnostic, Label},
+//!     files::SimpleFile,
+//! };
+//!
+//! let file = SimpleFile::new("main.rs", "let a: i32 = \"abc\";");
+//! let diagnostic = Diagnostic::error()
+//!     .with_message("mismatched types")
+//!     .with_labels(vec![Label::primary((), 13..18).with_message("expected i32")]);
+//!
+//! let snippets = codespan::render(&file, &diagnostic).unwrap();
+//! assert!(snippets[0].plain().to_string().contains("expected i32"));
+//! ```
+
+use std::fmt;
+
+use codespan_reporting::{
+	diagnostic::{Diagnostic, LabelStyle, Severity},
+	files::{self, Files},
+};
+use range_map::Range;
+
+use crate::{Annotation, AnnotationError, Formatting, Opts, PreparedSource, Source, Text};
+
+#[derive(Debug)]
+pub enum Error {
+	/// Failed to get the labeled file
+	Files(files::Error),
+	Annotation(AnnotationError),
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Files(e) => write!(f, "{e}"),
+			Self::Annotation(e) => write!(f, "{e}"),
+		}
+	}
+}
+impl std::error::Error for Error {}
+impl From<files::Error> for Error {
+	fn from(e: files::Error) -> Self {
+		Self::Files(e)
+	}
+}
+impl From<AnnotationError> for Error {
+	fn from(e: AnnotationError) -> Self {
+		Self::Annotation(e)
+	}
+}
+
+fn severity(severity: Severity) -> crate::Severity {
+	match severity {
+		Severity::Bug | Severity::Error => crate::Severity::Error,
+		Severity::Warning => crate::Severity::Warning,
+		Severity::Note => crate::Severity::Note,
+		Severity::Help => crate::Severity::Info,
+	}
+}
+
+fn severity_formatting(severity: Severity) -> Formatting {
+	match severity {
+		Severity::Bug | Severity::Error => Formatting::ERROR,
+		Severity::Warning => Formatting::WARNING,
+		Severity::Note | Severity::Help => Formatting::NOTE,
+	}
+}
+
+/// Labels pointing into the `file_id` file.
+///
+/// Primary labels have the diagnostic severity color, and are drawn over the secondary ones, which are [`Formatting::INFO`]
+pub fn annotations<FileId: PartialEq>(
+	diagnostic: &Diagnostic<FileId>,
+	file_id: &FileId,
+) -> Vec<Annotation> {
+	diagnostic
+		.labels
+		.iter()
+		.filter(|label| label.file_id == *file_id)
+		.map(|label| {
+			let (priority, formatting, label_severity) = match label.style {
+				LabelStyle::Primary => (
+					1,
+					severity_formatting(diagnostic.severity),
+					severity(diagnostic.severity),
+				),
+				LabelStyle::Secondary => (0, Formatting::INFO, crate::Severity::Info),
+			};
+			// Empty ranges are pointing at the char after them
+			let end = label.range.end.max(label.range.start + 1) - 1;
+			Annotation {
+				priority,
+				severity: Some(label_severity),
+				..Annotation::new(
+					[Range::new(label.range.start, end)].into_iter().collect(),
+					Text::from(label.message.as_str()),
+					formatting,
+				)
+			}
+		})
+		.collect()
+}
+
+/// Default options, with the diagnostic header, i.e `error[E0308]: mismatched types`, as the frame title
+pub fn opts<FileId>(diagnostic: &Diagnostic<FileId>) -> Opts {
+	let name = match diagnostic.severity {
+		Severity::Bug => "bug",
+		Severity::Error => "error",
+		Severity::Warning => "warning",
+		Severity::Note => "note",
+		Severity::Help => "help",
+	};
+	let formatting = severity_formatting(diagnostic.severity);
+	let mut title = Text::from_str(name, formatting.clone());
+	if let Some(code) = &diagnostic.code {
+		title.append_styled(&format!("[{code}]"), formatting);
+	}
+	if !diagnostic.message.is_empty() {
+		title.push_str(&format!(": {}", diagnostic.message));
+	}
+	Opts {
+		frame: Some(title),
+		..Opts::default()
+	}
+}
+
+/// Snippet for every labeled file, in the order of their first label.
+///
+/// Diagnostic notes are not displayed
+pub fn render<'f, F: Files<'f>>(
+	files: &'f F,
+	diagnostic: &Diagnostic<F::FileId>,
+) -> Result<Vec<Source>, Error> {
+	let mut file_ids: Vec<F::FileId> = Vec::new();
+	for label in &diagnostic.labels {
+		if !file_ids.contains(&label.file_id) {
+			file_ids.push(label.file_id);
+		}
+	}
+	let mut out = Vec::new();
+	for file_id in file_ids {
+		let src = files.source(file_id)?;
+		let mut prepared = PreparedSource::new(&Text::from(src.as_ref()), opts(diagnostic));
+		for annotation in annotations(diagnostic, &file_id) {
+			prepared.add(annotation);
+		}
+		out.push(prepared.render()?);
+	}
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use codespan_reporting::{
+		diagnostic::{Diagnostic, Label},
+		files::SimpleFiles,
+	};
+
+	use super::{annotations, render};
+	use crate::Formatting;
+
+	#[test]
+	fn two_labels() {
+		let mut files = SimpleFiles::new();
+		let file = files.add("main.rs", "fn main() {\n    let a: i32 = \"abc\";\n}\n");
+		let diagnostic = Diagnostic::warning()
+			.with_code("E0308")
+			.with_message("mismatched types")
+			.with_labels(vec![
+				Label::primary(file, 29..34).with_message("expected String"),
+				Label::secondary(file, 23..26).with_message("expected due to this"),
+			]);
+
+		let converted = annotations(&diagnostic, &file);
+		assert_eq!(converted.len(), 2);
+		assert_eq!(converted[0].priority, 1);
+		assert_eq!(converted[0].formatting, Formatting::WARNING);
+		assert_eq!(converted[0].severity, Some(crate::Severity::Warning));
+		assert_eq!(converted[1].priority, 0);
+		assert_eq!(converted[1].formatting, Formatting::INFO);
+
+		let snippets = render(&files, &diagnostic).expect("valid diagnostic");
+		assert_eq!(snippets.len(), 1);
+		assert_eq!(
+			snippets[0].plain().to_string(),
+			"╭─ warning[E0308]: mismatched types ──────────╮\n\
+			 │ 1 fn main() {                               │\n\
+			 │ ·            ╭──── expected due to this     │\n\
+			 │ 2     let a: i32 = \"abc\"; 🢒 expected String │\n\
+			 │ 3 }                                         │\n\
+			 ╰─────────────────────────────────────────────╯\n"
+		);
+	}
+}
@@ -0,0 +1,220 @@
+//! Diff-style rendering of two versions of the text, i.e to show which change broke the code
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::Arc,
+};
+
+use range_map::Range;
+use similar::{capture_diff_slices, Algorithm, ChangeTag};
+
+use crate::{
+	anomaly_fixer::byte_linestarts, Annotation, AnnotationError, Formatting, Opts, PreparedSource,
+	Segment, Source, Text,
+};
+
+/// Lines with their terminators, the last one may have none. Terminator of the last line doesn't start a new one
+fn split_lines(text: &str) -> Vec<&str> {
+	let starts = byte_linestarts(text);
+	let ends = starts.iter().skip(1).copied().chain([text.len()]);
+	let mut lines = starts
+		.iter()
+		.zip(ends)
+		.map(|(start, end)| &text[*start..end])
+		.collect::<Vec<_>>();
+	if lines.len() > 1 && lines.last() == Some(&"") {
+		lines.pop();
+	}
+	lines
+}
+
+/// Render line diff of two versions of the text, removed lines are displayed in red with `-` prefix,
+/// and the added lines are displayed after them, in green with `+` prefix.
+///
+/// Annotations are attached to the `new` text, their ranges are byte offsets into it, and ranges spanning
+/// multiple lines also cover the removed lines between them. Gutter has both old and new line numbers,
+/// which replace [`Opts::line_number_formatter`] and [`Opts::line_prefixes`], changed lines are never folded
+///
+/// ```
+/// use ass_stroke::{parse_diff, Annotation, Formatting, Opts, Text};
+/// use range_map::Range;
+///
+/// let annotation = Annotation::new(
+///     [Range::new(19, 19)].into_iter().collect(),
+///     Text::from("not found"),
+///     Formatting::ERROR,
+/// );
+/// let source = parse_diff("let a = 1;\nlet b = a;", "let a = 1;\nlet b = c;", [annotation], Opts::default())
+///     .unwrap();
+/// let rendered = source.to_string();
+/// assert!(rendered.contains("-2   let b = a;"));
+/// assert!(rendered.contains("+  2 let b = c;"));
+/// ```
+pub fn parse_diff(
+	old: &str,
+	new: &str,
+	annotations: impl IntoIterator<Item = Annotation>,
+	mut opts: Opts,
+) -> Result<Source, AnnotationError> {
+	let old_lines = split_lines(old);
+	let new_lines = split_lines(new);
+
+	let mut text = Text::empty();
+	// Old and new numbers of every displayed line
+	let mut numbers = Vec::new();
+	// Offsets of the new lines in the displayed text
+	let mut new_starts = Vec::new();
+	let mut prefixes = BTreeMap::new();
+	let mut pinned = BTreeSet::new();
+	let mut len = 0;
+	let mut terminated = true;
+	for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+		for change in op.iter_changes(&old_lines, &new_lines) {
+			let line = change.value();
+			// Unterminated last line of either version may be followed by the lines of the other one
+			if !terminated {
+				text.push(Segment::new(['\n'], Formatting::default()));
+				len += 1;
+			}
+			terminated = line.ends_with('\n');
+			let number = numbers.len() + 1;
+			let formatting = match change.tag() {
+				ChangeTag::Equal => Formatting::default(),
+				ChangeTag::Delete => Formatting::RED,
+				ChangeTag::Insert => Formatting::GREEN,
+			};
+			if change.tag() != ChangeTag::Equal {
+				let marker = if change.tag() == ChangeTag::Delete {
+					'-'
+				} else {
+					'+'
+				};
+				prefixes.insert(number, Text::single([marker], formatting.clone()));
+				pinned.insert(number);
+			}
+			if change.new_index().is_some() {
+				new_starts.push(len);
+			}
+			text.extend(Text::single(line.chars(), formatting));
+			len += line.len();
+			numbers.push((change.old_index(), change.new_index()));
+		}
+	}
+
+	let width = old_lines.len().max(new_lines.len()).to_string().len();
+	opts.line_number_formatter = Some(Arc::new(move |number| {
+		let column = |n: Option<usize>| n.map_or_else(String::new, |n| (n + 1).to_string());
+		let (old, new) = numbers[number - 1];
+		format!("{:>width$} {:>width$}", column(old), column(new))
+	}));
+	opts.line_prefixes = prefixes;
+
+	let new_linestarts = byte_linestarts(new);
+	// Offsets inside of the line are kept, as the lines are copied with their terminators
+	let to_displayed = |offset: usize| {
+		let offset = offset.min(new.len());
+		let line = new_linestarts.partition_point(|start| *start <= offset) - 1;
+		new_starts[line] + offset - new_linestarts[line]
+	};
+	let mut prepared = PreparedSource::new(&text, opts);
+	prepared.pinned = pinned;
+	for mut annotation in annotations {
+		annotation.ranges = annotation
+			.ranges
+			.ranges()
+			.map(|r| Range::new(to_displayed(r.start), to_displayed(r.end)))
+			.collect();
+		prepared.add(annotation);
+	}
+	prepared.render()
+}
+
+#[cfg(test)]
+mod tests {
+	use range_map::Range;
+
+	use super::parse_diff;
+	use crate::{source_to_ansi, Annotation, Formatting, Opts, Text};
+
+	const OLD: &str = "fn main() {
+	let a = 1;
+	let b = 2;
+	let c = 3;
+	let d = 4;
+	let e = 5;
+	let f = 6;
+	let g = 7;
+	let h = 8;
+	println!(\"{a}\");
+}";
+	const NEW: &str = "fn main() {
+	let a = 1;
+	let b = 2;
+	let c = 3;
+	let d = a + b;
+	let e = d * 2;
+	let f = e - c;
+	let g = 7;
+	let h = 8;
+	println!(\"{a}\");
+}";
+
+	fn annotation(start: usize, end: usize) -> Annotation {
+		Annotation::new(
+			[Range::new(start, end)].into_iter().collect(),
+			Text::from("never used"),
+			Formatting::ERROR,
+		)
+	}
+
+	fn lines(rendered: &str) -> Vec<&str> {
+		rendered.lines().map(str::trim_end).collect()
+	}
+
+	#[test]
+	fn three_lines() {
+		let start = NEW.find("f =").unwrap();
+		let source = parse_diff(OLD, NEW, [annotation(start, start)], Opts::default())
+			.expect("valid annotations");
+		assert_eq!(
+			lines(&source.to_string()),
+			[
+				"     ⋮",
+				"  3  3     let b = 2;",
+				"  4  4     let c = 3;",
+				"- 5        let d = 4;",
+				"- 6        let e = 5;",
+				"- 7        let f = 6;",
+				"+    5     let d = a + b;",
+				"+    6     let e = d * 2;",
+				"+    7     let f = e - c; 🢒 never used",
+				"  8  8     let g = 7;",
+				"  9  9     let h = 8;",
+				"     ⋮",
+			]
+		);
+		assert_eq!(
+			source_to_ansi(&source),
+			include_str!("../../../fixtures/diff.ansi")
+		);
+	}
+
+	#[test]
+	fn unterminated_last_line() {
+		let source = parse_diff("a\nb", "a\nc\n", [annotation(2, 2)], Opts::default())
+			.expect("valid annotations");
+		// Removed last line is terminated, so the added lines are not joined to it
+		assert_eq!(
+			lines(&source.to_string()),
+			[" 1 1 a", "-2   b", "+  2 c 🢒 never used"]
+		);
+
+		// Trailing line break doesn't add an empty line
+		let source = parse_diff("a\n", "a\nb\n", [annotation(2, 2)], Opts::default())
+			.expect("valid annotations");
+		assert_eq!(
+			lines(&source.to_string()),
+			[" 1 1 a", "+  2 b 🢒 never used"]
+		);
+	}
+}
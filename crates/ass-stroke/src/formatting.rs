@@ -1,15 +1,57 @@
-use crate::segment::{Meta, MetaApply, Segment, SegmentBuffer};
+use std::{fmt, ops::RangeInclusive};
+
+use crate::{
+	anomaly_fixer::char_width,
+	segment::{Meta, MetaApply, Segment, SegmentBuffer},
+};
 
 pub type TextPart = Segment<char, Formatting>;
 pub type Text = SegmentBuffer<char, Formatting>;
 
-#[derive(Default, Clone, PartialEq, Debug)]
+#[derive(Default, Clone, PartialEq)]
 pub struct Formatting {
 	pub color: Option<u32>,
 	pub bg_color: Option<u32>,
 	pub bold: bool,
 	pub underline: bool,
+	/// Faint text, i.e for the glyphs replacing whitespace
+	pub dim: bool,
+	/// Drawn by the renderer, i.e line connectors, inline labels and the frame, and is not a part of the source text,
+	/// see [`Text::without_decoration`]
 	pub decoration: bool,
+	/// Index of the annotation in order of addition, set by the renderer for every char drawn for it,
+	/// including the annotated source text, see `json::Run::annotation`
+	pub annotation: Option<usize>,
+}
+/// Only the set properties, i.e `#ff000000 on #28282800 bold`, or `default`
+impl fmt::Debug for Formatting {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut parts = Vec::new();
+		if let Some(color) = self.color {
+			parts.push(format!("#{color:08x}"));
+		}
+		if let Some(bg_color) = self.bg_color {
+			parts.push(format!("on #{bg_color:08x}"));
+		}
+		for (set, name) in [
+			(self.bold, "bold"),
+			(self.underline, "underline"),
+			(self.dim, "dim"),
+			(self.decoration, "decoration"),
+		] {
+			if set {
+				parts.push(name.to_owned());
+			}
+		}
+		if let Some(annotation) = self.annotation {
+			parts.push(format!("annotation #{annotation}"));
+		}
+		if parts.is_empty() {
+			write!(f, "default")
+		} else {
+			write!(f, "{}", parts.join(" "))
+		}
+	}
 }
 impl Meta for Formatting {
 	fn try_merge(&mut self, other: &Self) -> bool {
@@ -31,6 +73,15 @@ impl MetaApply<Formatting> for Formatting {
 		if change.underline {
 			self.underline = true;
 		}
+		if change.dim {
+			self.dim = true;
+		}
+		if change.decoration {
+			self.decoration = true;
+		}
+		if let Some(annotation) = change.annotation {
+			self.annotation = Some(annotation);
+		}
 	}
 }
 
@@ -44,6 +95,39 @@ impl MetaApply<AddColorToUncolored> for Formatting {
 	}
 }
 
+/// Fixed colors, unlike the ones generated by [`crate::SnippetBuilder::error`] and other severity methods,
+/// which are only of the same hue
+///
+/// ```
+/// use ass_stroke::{Annotation, Formatting, Severity, SnippetBuilder};
+/// use range_map::Range;
+///
+/// let mut prepared = SnippetBuilder::new("let a = 1;").prepare().unwrap();
+/// prepared.add(Annotation {
+///     severity: Some(Severity::Error),
+///     ..Annotation::new(
+///         [Range::new(4, 4)].into_iter().collect(),
+///         "unused".into(),
+///         Formatting::ERROR,
+///     )
+/// });
+/// assert!(prepared.render().unwrap().plain().to_string().contains("unused"));
+/// ```
+impl Formatting {
+	pub const RED: Self = Self::color(0xfb493400);
+	pub const ORANGE: Self = Self::color(0xfe801900);
+	pub const YELLOW: Self = Self::color(0xfabd2f00);
+	pub const GREEN: Self = Self::color(0xb8bb2600);
+	pub const BLUE: Self = Self::color(0x83a59800);
+	pub const PURPLE: Self = Self::color(0xd3869b00);
+
+	/// Same hues as the default [`crate::Palette`]
+	pub const ERROR: Self = Self::RED;
+	pub const WARNING: Self = Self::ORANGE;
+	pub const NOTE: Self = Self::GREEN;
+	pub const INFO: Self = Self::BLUE;
+}
+
 impl Formatting {
 	pub fn line_number() -> Self {
 		Self {
@@ -52,37 +136,413 @@ impl Formatting {
 			..Default::default()
 		}
 	}
-	pub fn color(color: u32) -> Self {
+	pub fn frame() -> Self {
 		Self {
-			color: Some(color),
+			color: Some(0x92837400),
 			..Default::default()
 		}
 	}
+	pub const fn color(color: u32) -> Self {
+		Self {
+			color: Some(color),
+			bg_color: None,
+			bold: false,
+			underline: false,
+			dim: false,
+			decoration: false,
+			annotation: None,
+		}
+	}
 	pub fn rgb([r, g, b]: [u8; 3]) -> Self {
 		Self::color(u32::from_be_bytes([r, g, b, 0]))
 	}
 
+	pub fn dim(mut self) -> Self {
+		self.dim = true;
+		self
+	}
 	pub fn decoration(mut self) -> Self {
 		self.decoration = true;
 		self
 	}
 }
 
+impl Text {
+	/// Text with the single style
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let label = Text::from_str("mismatched types", Formatting::color(0xfb493400));
+	/// assert_eq!(label.segments().count(), 1);
+	/// ```
+	#[allow(clippy::should_implement_trait)]
+	pub fn from_str(text: &str, formatting: Formatting) -> Self {
+		let mut out = Self::empty();
+		out.append_styled(text, formatting);
+		out
+	}
+	/// Start building text run by run
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let label = Text::build()
+	///     .str("expected ")
+	///     .styled("i32", Formatting::color(0xfb493400))
+	///     .str(", found ")
+	///     .styled("String", Formatting::color(0x83a59800))
+	///     .finish();
+	/// assert_eq!(label.data().collect::<String>(), "expected i32, found String");
+	/// ```
+	pub fn build() -> TextBuilder {
+		TextBuilder(Self::empty())
+	}
+	/// Build text from differently styled runs
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let label = Text::from_runs([
+	///     ("expected ", Formatting::default()),
+	///     ("String", Formatting::color(0xfb493400)),
+	///     (", found ", Formatting::default()),
+	///     ("usize", Formatting::color(0xb8bb2600)),
+	/// ]);
+	/// assert_eq!(label.data().collect::<String>(), "expected String, found usize");
+	/// ```
+	pub fn from_runs<'s>(runs: impl IntoIterator<Item = (&'s str, Formatting)>) -> Self {
+		let mut out = Self::empty();
+		for (text, formatting) in runs {
+			out.append_styled(text, formatting);
+		}
+		out
+	}
+	/// Append run of unstyled text
+	pub fn push_str(&mut self, text: &str) {
+		self.append_styled(text, Formatting::default())
+	}
+	/// Append run of text with specified style
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let mut label = Text::empty();
+	/// label.append_styled("unused variable ", Formatting::default());
+	/// label.append_styled("x", Formatting::color(0xfabd2f00));
+	/// assert_eq!(label.len(), 17);
+	/// ```
+	pub fn append_styled(&mut self, text: &str, formatting: Formatting) {
+		if text.is_empty() {
+			return;
+		}
+		self.push(Segment::new(text.chars(), formatting));
+	}
+	/// Number of terminal cells used to display the text, fullwidth chars take two cells each,
+	/// use it to align custom headers and footers with the rendered snippet
+	///
+	/// ```
+	/// use ass_stroke::Text;
+	///
+	/// assert_eq!(Text::from("abc").width(), 3);
+	/// assert_eq!(Text::from("ＡＢＣ").width(), 6);
+	/// ```
+	pub fn width(&self) -> usize {
+		self.data().map(|c| char_width(*c)).sum()
+	}
+	/// Iterate over chars, together with their style
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let red = Formatting::color(0xfb493400);
+	/// let label = Text::build().str("a").styled("b", red.clone()).finish();
+	/// assert_eq!(
+	///     label.chars_with_format().collect::<Vec<_>>(),
+	///     [('a', &Formatting::default()), ('b', &red)],
+	/// );
+	/// ```
+	pub fn chars_with_format(&self) -> impl Iterator<Item = (char, &Formatting)> {
+		self.segments()
+			.flat_map(|s| s.iter().map(move |c| (*c, s.meta())))
+	}
+	/// Text without the [`Formatting::decoration`] runs, i.e to copy the source line without connectors
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let line = Text::build()
+	///     .styled("│ ", Formatting::color(0xfb493400).decoration())
+	///     .str("let a = 1;")
+	///     .finish();
+	/// assert_eq!(line.without_decoration().data().collect::<String>(), "let a = 1;");
+	/// ```
+	pub fn without_decoration(&self) -> Self {
+		let mut out = Self::empty();
+		for segment in self.segments().filter(|s| !s.meta().decoration) {
+			out.push(segment.clone());
+		}
+		out
+	}
+}
+
+/// Maps byte offsets of the escape-laden string to the offsets of the text parsed by [`Text::from_ansi_with_offsets`]
+#[derive(Debug, Clone)]
+pub struct AnsiOffsets {
+	/// For every byte of the original string, and for its end.
+	/// Bytes of the stripped escape sequences are mapped to the next visible byte
+	stripped: Vec<usize>,
+}
+impl AnsiOffsets {
+	/// # Panics
+	/// If offset is past the end of the original string
+	pub fn offset(&self, raw: usize) -> usize {
+		self.stripped[raw]
+	}
+	/// Convert inclusive range computed on the original string
+	pub fn range(&self, raw: RangeInclusive<usize>) -> RangeInclusive<usize> {
+		self.offset(*raw.start())..=self.offset(*raw.end())
+	}
+}
+
+/// Standard xterm colors for SGR 30-37 and 90-97, also the first 16 colors of the 256 color palette
+pub(crate) const ANSI_COLORS: [u32; 16] = [
+	0x00000000, 0xcd000000, 0x00cd0000, 0xcdcd0000, 0x0000ee00, 0xcd00cd00, 0x00cdcd00, 0xe5e5e500,
+	0x7f7f7f00, 0xff000000, 0x00ff0000, 0xffff0000, 0x5c5cff00, 0xff00ff00, 0x00ffff00, 0xffffff00,
+];
+
+fn ansi_256(color: u8) -> u32 {
+	match color {
+		0..=15 => ANSI_COLORS[color as usize],
+		16..=231 => {
+			let cube = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+			let color = color - 16;
+			u32::from_be_bytes([cube(color / 36), cube(color / 6 % 6), cube(color % 6), 0])
+		}
+		232..=255 => {
+			let gray = 8 + (color - 232) * 10;
+			u32::from_be_bytes([gray, gray, gray, 0])
+		}
+	}
+}
+
+/// Extended color after 38/48, consumes its arguments from `params`
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<u32> {
+	let byte = |v: u16| u8::try_from(v).ok();
+	match params.next()? {
+		5 => Some(ansi_256(byte(params.next()?)?)),
+		2 => {
+			let [r, g, b] = [params.next()?, params.next()?, params.next()?];
+			Some(u32::from_be_bytes([byte(r)?, byte(g)?, byte(b)?, 0]))
+		}
+		_ => None,
+	}
+}
+
+fn apply_sgr(formatting: &mut Formatting, params: &str) {
+	// Empty parameters are treated as zeros, `ESC[m` is a reset
+	let mut params = params
+		.split([';', ':'])
+		.map(|p| p.parse::<u16>().unwrap_or(0));
+	while let Some(code) = params.next() {
+		match code {
+			0 => *formatting = Formatting::default(),
+			1 => formatting.bold = true,
+			2 => formatting.dim = true,
+			4 => formatting.underline = true,
+			22 => {
+				formatting.bold = false;
+				formatting.dim = false;
+			}
+			24 => formatting.underline = false,
+			30..=37 => formatting.color = Some(ANSI_COLORS[code as usize - 30]),
+			90..=97 => formatting.color = Some(ANSI_COLORS[code as usize - 90 + 8]),
+			40..=47 => formatting.bg_color = Some(ANSI_COLORS[code as usize - 40]),
+			100..=107 => formatting.bg_color = Some(ANSI_COLORS[code as usize - 100 + 8]),
+			38 => {
+				if let Some(color) = extended_color(&mut params) {
+					formatting.color = Some(color);
+				}
+			}
+			48 => {
+				if let Some(color) = extended_color(&mut params) {
+					formatting.bg_color = Some(color);
+				}
+			}
+			39 => formatting.color = None,
+			49 => formatting.bg_color = None,
+			// Italic, blink, etc. have no representation in Formatting
+			_ => {}
+		}
+	}
+}
+
+impl Text {
+	/// Parse text colored with SGR escape sequences, i.e output of another tool.
+	///
+	/// Styles which can't be represented by [`Formatting`], and all other escape sequences are stripped
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let text = Text::from_ansi("\x1b[1;31merror\x1b[0m: unused");
+	/// assert_eq!(text.data().collect::<String>(), "error: unused");
+	/// assert_eq!(text.segments().next().unwrap().meta().color, Some(0xcd000000));
+	/// ```
+	pub fn from_ansi(text: &str) -> Self {
+		Self::from_ansi_with_offsets(text).0
+	}
+	/// Same as [`Text::from_ansi`], also returns offsets fixup table, for annotation ranges computed on the original string
+	pub fn from_ansi_with_offsets(text: &str) -> (Self, AnsiOffsets) {
+		let mut out = Self::empty();
+		let mut stripped = Vec::with_capacity(text.len() + 1);
+		let mut formatting = Formatting::default();
+		let mut visible = 0;
+		let bytes = text.as_bytes();
+		let mut i = 0;
+		while i < bytes.len() {
+			if bytes[i] != b'\x1b' {
+				let end = bytes[i..]
+					.iter()
+					.position(|b| *b == b'\x1b')
+					.map_or(bytes.len(), |p| i + p);
+				out.append_styled(&text[i..end], formatting.clone());
+				stripped.extend(visible..visible + end - i);
+				visible += end - i;
+				i = end;
+				continue;
+			}
+			let start = i;
+			i += 1;
+			match bytes.get(i) {
+				// CSI: parameters and intermediates, then the final byte
+				Some(b'[') => {
+					i += 1;
+					let params = i;
+					while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+						i += 1;
+					}
+					if bytes.get(i) == Some(&b'm') {
+						apply_sgr(&mut formatting, &text[params..i]);
+					}
+					i = (i + 1).min(bytes.len());
+				}
+				// OSC, i.e hyperlinks: terminated by either BEL or ST
+				Some(b']') => {
+					while i < bytes.len()
+						&& bytes[i] != b'\x07'
+						&& !bytes[i..].starts_with(b"\x1b\\")
+					{
+						i += 1;
+					}
+					if bytes.get(i) == Some(&b'\x07') {
+						i += 1;
+					} else {
+						i = (i + 2).min(bytes.len());
+					}
+				}
+				// Intermediate bytes, i.e charset selection, then the final byte
+				Some(_) => {
+					while i < bytes.len() && (0x20..=0x2f).contains(&bytes[i]) {
+						i += 1;
+					}
+					if bytes.get(i).is_some_and(|c| (0x30..=0x7e).contains(c)) {
+						i += 1;
+					}
+				}
+				None => {}
+			}
+			stripped.extend(std::iter::repeat_n(visible, i - start));
+		}
+		stripped.push(visible);
+		(out, AnsiOffsets { stripped })
+	}
+}
+
+impl From<&str> for Text {
+	fn from(text: &str) -> Self {
+		Self::from_str(text, Formatting::default())
+	}
+}
+
+/// Builder for the [`Text`] consisting of multiple styled runs, see [`Text::build`]
+#[derive(Debug)]
+pub struct TextBuilder(Text);
+impl TextBuilder {
+	/// Append run of unstyled text
+	pub fn str(self, text: &str) -> Self {
+		self.styled(text, Formatting::default())
+	}
+	/// Append run of text with specified style
+	pub fn styled(mut self, text: &str, formatting: Formatting) -> Self {
+		self.0.append_styled(text, formatting);
+		self
+	}
+	pub fn finish(self) -> Text {
+		self.0
+	}
+}
+
+/// Escapes are only emitted when displayed style changes, and only for the changed attributes,
+/// so the text doesn't need to be compacted. Styled text is always terminated with reset,
+/// so that the next line starts from the default state
 pub fn text_to_ansi(buf: &Text, out: &mut String) {
 	use std::fmt::Write;
 
-	for frag in buf.segments() {
-		if let Some(color) = frag.meta().color {
-			let [r, g, b, _a] = u32::to_be_bytes(color);
-			write!(out, "\x1b[38;2;{r};{g};{b}m").expect("no fmt error");
-		}
-		if let Some(bg_color) = frag.meta().bg_color {
-			let [r, g, b, _a] = u32::to_be_bytes(bg_color);
-			write!(out, "\x1b[48;2;{r};{g};{b}m").expect("no fmt error")
+	fn sgr(out: &mut String, code: impl fmt::Display) {
+		write!(out, "\x1b[{code}m").expect("no fmt error")
+	}
+	let visible = |f: &Formatting| Formatting {
+		decoration: false,
+		annotation: None,
+		..f.clone()
+	};
+	let default = Formatting::default();
+	let mut active = default.clone();
+	for frag in buf.segments().filter(|s| !s.is_empty()) {
+		let meta = visible(frag.meta());
+		if meta != active {
+			if meta == default {
+				sgr(out, 0);
+			} else {
+				if meta.color != active.color {
+					match meta.color {
+						Some(color) => {
+							let [r, g, b, _a] = u32::to_be_bytes(color);
+							sgr(out, format_args!("38;2;{r};{g};{b}"));
+						}
+						None => sgr(out, 39),
+					}
+				}
+				if meta.bg_color != active.bg_color {
+					match meta.bg_color {
+						Some(bg_color) => {
+							let [r, g, b, _a] = u32::to_be_bytes(bg_color);
+							sgr(out, format_args!("48;2;{r};{g};{b}"));
+						}
+						None => sgr(out, 49),
+					}
+				}
+				// Bold and dim are disabled with the same code
+				let intensity_off = (active.bold && !meta.bold) || (active.dim && !meta.dim);
+				if intensity_off {
+					sgr(out, 22);
+				}
+				if meta.bold && (intensity_off || !active.bold) {
+					sgr(out, 1);
+				}
+				if meta.dim && (intensity_off || !active.dim) {
+					sgr(out, 2);
+				}
+				if meta.underline != active.underline {
+					sgr(out, if meta.underline { 4 } else { 24 });
+				}
+			}
+			active = meta;
 		}
 		write!(out, "{}", frag.iter().copied().collect::<String>()).expect("no fmt error");
-		if frag.meta().color.is_some() || frag.meta().bg_color.is_some() {
-			write!(out, "\x1b[0m").expect("no fmt error")
-		}
+	}
+	if active != default {
+		sgr(out, 0);
 	}
 }
@@ -73,6 +73,8 @@ pub(crate) fn group_singleline(annotations: &[LineAnnotation]) -> SingleLine {
 		.iter()
 		.filter(|a| !processed.contains(&a.id))
 		.filter(|a| !a.left && !a.right.is_empty() && a.right.data().all(|c| *c != '\n'))
+		// Leader of the right-anchored label starts from the range, not from the line end
+		.filter(|a| !a.right_anchored)
 		.filter(|a| can_use(&occupied, &a.ranges))
 		.max_by_key(|a| {
 			(
@@ -120,13 +122,31 @@ pub(crate) fn group_singleline(annotations: &[LineAnnotation]) -> SingleLine {
 	}
 
 	let mut hide_ranges_for = HashSet::new();
+	let mut painted = annotations
+		.iter()
+		.filter(|a| processed.contains(&a.id))
+		.map(|a| (a.ranges.clone(), a.priority))
+		.collect::<Vec<_>>();
 
 	// Ensure inlined annotations are not shadowing leftmost/rightmost item
 	// New annotation should be either not intersect with others, or
-	for a in annotations.iter().filter(|a| !processed.contains(&a.id)) {
+	// Inlined annotations are painted over the already displayed ones, so only those with not lower priority
+	// can be inlined, others keep their own range row
+	let mut rest = annotations
+		.iter()
+		.filter(|a| !processed.contains(&a.id))
+		.collect::<Vec<_>>();
+	rest.sort_by_key(|a| a.priority);
+	for a in rest {
 		if !can_use(&occupied, &a.ranges) {
 			continue;
 		}
+		if painted.iter().any(|(ranges, priority)| {
+			*priority > a.priority && !ranges.intersection(&a.ranges).is_empty()
+		}) {
+			continue;
+		}
+		painted.push((a.ranges.clone(), a.priority));
 		inline.push(InlineAnnotation {
 			ranges: a.ranges.clone(),
 			formatting: a.formatting.clone(),
@@ -143,6 +163,18 @@ pub(crate) fn group_singleline(annotations: &[LineAnnotation]) -> SingleLine {
 	}
 }
 
+/// Apply range colors to the source text, labels are left for range annotations
+pub(crate) fn apply_range_formatting(text: &mut Text, annotations: &[LineAnnotation]) {
+	let mut annotations = annotations.iter().collect::<Vec<_>>();
+	// Higher priority colors are applied last, so they win on overlap
+	annotations.sort_by_key(|a| a.priority);
+	for annotation in annotations {
+		for range in annotation.ranges.ranges() {
+			text.apply_meta(range.start..=range.end, &annotation.formatting)
+		}
+	}
+}
+
 pub(crate) fn apply_inline_annotations(
 	text: &mut Text,
 	annotations: &[InlineAnnotation],
@@ -153,11 +185,13 @@ pub(crate) fn apply_inline_annotations(
 			text.apply_meta(range.start..=range.end, &annotation.formatting)
 		}
 	}
-	if let Some((formatting, right)) = right {
+	// Label is not a part of the source line
+	if let Some((formatting, mut right)) = right {
 		text.extend(Text::single(
 			[crate::chars::arrow::BOTTOM.arrow_inline, ' '],
-			formatting,
+			formatting.decoration(),
 		));
+		right.apply_meta(.., &Formatting::default().decoration());
 		text.extend(right);
 	}
 }
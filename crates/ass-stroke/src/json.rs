@@ -0,0 +1,292 @@
+//! Structured output, i.e for web frontends, which need to know what every rendered line is made of
+//!
+//! ```
+//! use ass_stroke::{json::LineKind, SnippetBuilder, Text};
+//!
+//! let mut snippet = SnippetBuilder::new("let a = 1;\nlet b = a;");
+//! snippet.error(Text::from("defined here")).range(4..=4).build();
+//! snippet.note(Text::from("used here")).range(19..=19).build();
+//! let tree = snippet.build().json_tree();
+//! assert_eq!(tree.lines[0].kind, LineKind::Source { number: 1, annotation: None });
+//! assert_eq!(tree.lines[0].text(), "1 let a = 1; 🢒 defined here");
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Formatting, RenderedLine, Source, Text};
+
+/// Rendered snippet, see [`Source::json_tree`]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Snippet {
+	/// [`Source::gutter_width`], every line starts with the gutter of this width, except for [`LineKind::Other`]
+	pub gutter_width: usize,
+	pub lines: Vec<Line>,
+}
+
+/// What the rendered line was made of
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LineKind {
+	/// Line of the source text, numbered from 1
+	///
+	/// `annotation` is set for the lines connected to the multi-line annotation, by its index in order of addition
+	Source {
+		number: usize,
+		annotation: Option<usize>,
+	},
+	/// Rest of the source line, which is wrapped with [`crate::Opts::wrap`]
+	Continuation { number: usize },
+	/// Row of the annotation ranges and labels, `annotation` is the same as for [`LineKind::Source`]
+	Label { annotation: Option<usize> },
+	/// Replacement of the folded lines, with the numbers of the first and the last of them,
+	/// and the byte offsets of the folded text, end exclusive
+	Gap {
+		folded: (usize, usize),
+		bytes: (usize, usize),
+	},
+	/// Frame borders, column ruler, legend, and the lines added by custom stages
+	Other,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Line {
+	#[serde(flatten)]
+	pub kind: LineKind,
+	pub runs: Vec<Run>,
+}
+impl Line {
+	/// Text of all the runs, without styles
+	pub fn text(&self) -> String {
+		self.runs.iter().map(|r| r.text.as_str()).collect()
+	}
+}
+
+/// Consecutive chars with the same style, colors are in `#rrggbb` notation
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Run {
+	pub text: String,
+	pub fg: Option<String>,
+	pub bg: Option<String>,
+	pub bold: bool,
+	pub underline: bool,
+	pub dim: bool,
+	/// Run is drawn by the renderer, i.e connectors, inline labels and the frame, and is not a part of the source text
+	pub decoration: bool,
+	/// Index of the annotation this run is drawn for, in order of addition, see [`Formatting::annotation`]
+	pub annotation: Option<usize>,
+}
+
+fn color(color: u32) -> String {
+	let [r, g, b, _a] = color.to_be_bytes();
+	format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn runs(text: &Text) -> Vec<Run> {
+	let mut out: Vec<Run> = Vec::new();
+	let mut last = None;
+	for segment in text.segments().filter(|s| !s.is_empty()) {
+		// Segments are not always compacted
+		if last == Some(segment.meta()) {
+			let run = out.last_mut().expect("formatting is set with run");
+			run.text.extend(segment.iter());
+			continue;
+		}
+		last = Some(segment.meta());
+		let Formatting {
+			color: fg,
+			bg_color,
+			bold,
+			underline,
+			dim,
+			decoration,
+			annotation,
+		} = segment.meta();
+		out.push(Run {
+			text: segment.iter().collect(),
+			fg: fg.map(color),
+			bg: bg_color.map(color),
+			bold: *bold,
+			underline: *underline,
+			dim: *dim,
+			decoration: *decoration,
+			annotation: *annotation,
+		});
+	}
+	out
+}
+
+impl Source {
+	/// Serializable [`Source::rendered_lines`], with gutter and text joined into the styled runs
+	pub fn json_tree(&self) -> Snippet {
+		let lines = self
+			.lines
+			.iter()
+			.map(|line| {
+				let kind = match line.to_rendered() {
+					RenderedLine::Source {
+						number, annotation, ..
+					} => LineKind::Source { number, annotation },
+					RenderedLine::Continuation { number, .. } => LineKind::Continuation { number },
+					RenderedLine::Label { annotation, .. } => LineKind::Label { annotation },
+					RenderedLine::Gap { folded, bytes, .. } => LineKind::Gap {
+						folded: (*folded.start(), *folded.end()),
+						bytes: (bytes.start, bytes.end),
+					},
+					RenderedLine::Raw { .. } => LineKind::Other,
+				};
+				Line {
+					kind,
+					runs: runs(&line.rendered()),
+				}
+			})
+			.collect();
+		Snippet {
+			gutter_width: self.gutter_width(),
+			lines,
+		}
+	}
+	/// [`Source::json_tree`] as a JSON value
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::to_value(self.json_tree()).expect("tree is always serializable")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use range_map::Range;
+	use serde_json::json;
+
+	use super::{LineKind, Snippet};
+	use crate::{Annotation, FoldOpts, Formatting, Opts, PreparedSource, SnippetBuilder, Text};
+
+	fn snippet() -> crate::Source {
+		let src = (1..=10)
+			.map(|i| format!("l{i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let mut prepared = PreparedSource::new(
+			&Text::from(src.as_str()),
+			Opts {
+				fold: FoldOpts {
+					context_lines: 0,
+					..FoldOpts::default()
+				},
+				..Opts::default()
+			},
+		);
+		prepared.add(Annotation::new(
+			[Range::new(0, 1), Range::new(27, 28)].into_iter().collect(),
+			Text::from("first and last"),
+			Formatting::ERROR,
+		));
+		prepared.add(Annotation::new(
+			[Range::new(29, 29)].into_iter().collect(),
+			Text::from("number"),
+			Formatting::NOTE,
+		));
+		prepared.render().expect("valid annotations")
+	}
+
+	#[test]
+	fn line_kinds() {
+		let tree = snippet().json_tree();
+		let kinds = tree
+			.lines
+			.iter()
+			.map(|l| l.kind.clone())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			kinds,
+			[
+				LineKind::Source {
+					number: 1,
+					annotation: Some(0)
+				},
+				LineKind::Gap {
+					folded: (2, 9),
+					bytes: (3, 27)
+				},
+				LineKind::Label {
+					annotation: Some(0)
+				},
+				LineKind::Source {
+					number: 10,
+					annotation: None
+				},
+			]
+		);
+		assert_eq!(tree.lines[2].text(), " · ╰─┬─── first and last");
+		assert_eq!(tree.gutter_width, 5);
+	}
+
+	#[test]
+	fn round_trip() {
+		let source = snippet();
+		let tree = source.json_tree();
+		let value = source.to_json();
+		assert_eq!(
+			serde_json::from_value::<Snippet>(value.clone()).unwrap(),
+			tree
+		);
+		let text = serde_json::to_string(&value).unwrap();
+		assert_eq!(serde_json::from_str::<Snippet>(&text).unwrap(), tree);
+	}
+
+	#[test]
+	fn schema() {
+		let mut snippet = SnippetBuilder::new("ab").line_numbers(false);
+		snippet.error(Text::from("x")).range(0..=0).build();
+		let value = snippet.build().to_json();
+		assert_eq!(value["gutter_width"], json!(2));
+		let line = &value["lines"][0];
+		assert_eq!(line["kind"], json!("source"));
+		assert_eq!(line["number"], json!(1));
+		assert_eq!(line["annotation"], json!(null));
+		assert_eq!(line["runs"][0]["text"], json!("  "));
+		assert_eq!(line["runs"][0]["fg"], json!("#928374"));
+		assert_eq!(line["runs"][0]["bg"], json!("#282828"));
+		assert_eq!(line["runs"][0]["decoration"], json!(false));
+	}
+
+	#[test]
+	fn run_annotations() {
+		let mut snippet = SnippetBuilder::new("a();\nb();\nc = d;").collapse_adjacent(true);
+		snippet.warning(Text::from("unused")).range(0..=2).build();
+		snippet.warning(Text::from("unused")).range(5..=7).build();
+		snippet
+			.error(Text::from("undefined"))
+			.range(14..=14)
+			.key(12)
+			.build();
+		let source = snippet.build();
+		let tree = source.json_tree();
+		let runs = tree
+			.lines
+			.iter()
+			.flat_map(|l| &l.runs)
+			.filter(|r| r.annotation.is_some())
+			.map(|r| (r.text.as_str(), r.annotation.unwrap()))
+			.collect::<Vec<_>>();
+		// Second annotation is merged into the first one, third keeps its index in order of addition
+		assert_eq!(
+			runs,
+			[
+				("╭─", 0),
+				("a()", 0),
+				("╰─", 0),
+				("b()", 0),
+				("🢒 ", 0),
+				("unused", 0),
+				("  ", 0),
+				("d", 2),
+				("🢒 ", 2),
+				("undefined", 2),
+			]
+		);
+		// Only two annotations are left, but they are still found by index
+		assert_eq!(source.summaries().len(), 2);
+		assert_eq!(source.summary(0).map(|s| s.line), Some(1));
+		assert_eq!(source.summary(1), None);
+		assert_eq!(source.summary(2).and_then(|s| s.key), Some(12));
+	}
+}
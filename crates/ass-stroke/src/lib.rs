@@ -1,67 +1,162 @@
 use std::{
+	borrow::Cow,
+	cell::OnceCell,
+	cmp::Reverse,
 	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	fmt,
 	ops::RangeInclusive,
+	sync::Arc,
 };
 
 mod segment;
-use annotation::{Annotation, AnnotationId, Opts};
-use anomaly_fixer::{apply_fixup, fixup_byte_to_char, fixup_char_to_display};
-use formatting::{AddColorToUncolored, Text};
+use annotation::{AnnotationId, Highlighter};
+use anomaly_fixer::{
+	apply_fixup, apply_fixup_end, byte_linestarts, char_width, fixup_byte_to_char,
+	fixup_char_to_display,
+};
+use formatting::AddColorToUncolored;
+use pipeline::{Ctx, Pipeline};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use random_color::{Color, Luminosity, RandomColor};
 use range_map::{Range, RangeSet};
-use segment::{Segment, SegmentBuffer};
-use single_line::LineAnnotation;
-
-use crate::formatting::Formatting;
+use single_line::{AnnotationRow, LineAnnotation, RightAnchor};
 
 mod annotation;
 mod anomaly_fixer;
 mod chars;
+#[cfg(feature = "codespan-reporting")]
+pub mod codespan;
+#[cfg(feature = "diff")]
+mod diff;
 mod formatting;
 mod inline;
+#[cfg(feature = "json")]
+pub mod json;
+mod markdown;
+#[cfg(feature = "miette")]
+pub mod miette;
+pub mod pipeline;
 mod single_line;
+mod svg;
+#[cfg(feature = "termcolor")]
+mod termcolor;
 
-#[derive(Clone)]
-struct RawLine {
+pub use annotation::{
+	Annotation, AnnotationError, AnnotationSummary, ConnectorColumn, Deduplicate, FoldOpts,
+	GutterNumbers, KeyAllocator, LabelAnchor, LabelStacking, LineEnding, MarginPosition, Opts,
+	OptsError, Palette, RenderWarning, Severity, Strictness, StyleOpts, TabOrigin, Whitespace,
+};
+#[cfg(feature = "diff")]
+pub use diff::parse_diff;
+pub use formatting::{AnsiOffsets, Formatting, Text, TextBuilder, TextPart};
+pub use markdown::DisplayMarkdown;
+pub use segment::{Meta, MetaApply, Segment, SegmentBuffer};
+pub use single_line::group_nonconflicting;
+pub use svg::SvgOpts;
+
+/// Line displayed as is, i.e frame borders, column ruler, and the lines added with [`Source::push_raw`]
+#[derive(Clone, Debug)]
+pub struct RawLine {
 	data: Text,
 }
+impl RawLine {
+	fn new(data: Text) -> Self {
+		Self { data }
+	}
+	pub fn data(&self) -> &Text {
+		&self.data
+	}
+	pub fn data_mut(&mut self) -> &mut Text {
+		&mut self.data
+	}
+}
 
-struct AnnotationLine {
+/// Row of annotation ranges and labels
+#[derive(Clone, Debug)]
+pub struct AnnotationLine {
 	prefix: Text,
 	line: Text,
 	/// There will be lines drawn to connect lines with the same annotation id specified
 	annotation: Option<AnnotationId>,
+	/// Label at the end of the row, which is moved to [`Opts::max_width`]
+	anchor: Option<RightAnchor>,
 }
 
-struct GapLine {
+/// Replacement of the folded lines
+#[derive(Clone, Debug)]
+pub struct GapLine {
 	prefix: Text,
 	line: Text,
+	/// Numbers of the first and the last folded lines
+	line_nums: RangeInclusive<usize>,
+	/// Byte offsets of the folded text in the original, line break of the last folded line included
+	bytes: std::ops::Range<usize>,
+}
+impl GapLine {
+	/// Numbers of the first and the last folded lines, i.e to expand the gap on demand
+	pub fn folded_lines(&self) -> RangeInclusive<usize> {
+		self.line_nums.clone()
+	}
+	/// Byte offsets of the folded text in the original, line break of the last folded line included
+	pub fn folded_bytes(&self) -> std::ops::Range<usize> {
+		self.bytes.clone()
+	}
 }
 
-struct TextLine {
+/// Line of the source text
+#[derive(Clone, Debug)]
+pub struct TextLine {
 	prefix: Text,
 	line_num: usize,
+	/// Offset of the line start in the original text
+	byte_offset: usize,
+	/// Offset of the next line start, or the end of the original text
+	byte_end: usize,
 	line: Text,
+	/// Columns and widths of expanded tabs
+	tabs: BTreeMap<usize, usize>,
 	/// Is this line allowed to be hidden by fold?
 	fold: bool,
+	/// Is the reserved EOL char covered by any annotation, see [`Opts::trim_eol`]
+	eol_used: bool,
+	/// The most severe of the annotations on this line, for [`Opts::gutter_icons`]
+	severity: Option<Severity>,
 	annotation: Option<AnnotationId>,
 	annotations: Vec<LineAnnotation>,
-	top_annotations: Vec<(Option<AnnotationId>, Text)>,
-	bottom_annotations: Vec<(Option<AnnotationId>, Text)>,
+	top_annotations: Vec<AnnotationRow>,
+	bottom_annotations: Vec<AnnotationRow>,
+	/// Row is the wrapped part of the line
+	continuation: bool,
 }
 impl TextLine {
-	fn add_prefix(&mut self, this: Text, annotations: Text) {
-		self.prefix.extend(this);
-		for (_, ele) in self.bottom_annotations.iter_mut() {
-			ele.splice(0..0, Some(annotations.clone()));
-		}
+	/// Line number, starting from 1
+	pub fn line_num(&self) -> usize {
+		self.line_num
+	}
+	/// Byte offset of the line start in the original text
+	pub fn byte_offset(&self) -> usize {
+		self.byte_offset
 	}
 	fn len(&self) -> usize {
 		self.line.len()
 	}
+	/// Line contains nothing but whitespace (reserved EOL slot included)
 	fn is_empty(&self) -> bool {
-		self.line.is_empty()
+		self.line.data().all(|c| c.is_whitespace())
+	}
+	fn has_annotations(&self) -> bool {
+		self.annotation.is_some()
+			|| !self.annotations.is_empty()
+			|| !self.top_annotations.is_empty()
+			|| !self.bottom_annotations.is_empty()
+	}
+	/// Blank line, which carries no annotation, and may be removed from snippet edges
+	fn is_trimmable(&self) -> bool {
+		self.is_empty() && self.fold && !self.has_annotations()
+	}
+	/// Column of the reserved char, used for spans pointing to EOL
+	fn eol(&self) -> usize {
+		self.len().saturating_sub(1)
 	}
 	// fn trim_end(&mut self) {
 	// 	self.line.truncate(self.line.trim_end().len());
@@ -91,15 +186,52 @@ fn cons_slices<T>(mut slice: &mut [T], test: impl Fn(&T) -> bool) -> Vec<&mut [T
 	out
 }
 
-enum Line {
+#[derive(Clone)]
+pub enum Line {
 	Text(TextLine),
 	Annotation(AnnotationLine),
 	Raw(RawLine),
 	Nop,
 	Gap(GapLine),
 }
+/// Kind of the line, and its plain text, i.e `Text(3, "fn main() {")`
+impl fmt::Debug for Line {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let plain = |text: &Text| text.data().collect::<String>();
+		match self {
+			Line::Text(t) => f
+				.debug_tuple("Text")
+				.field(&t.line_num)
+				.field(&plain(&t.line))
+				.finish(),
+			Line::Annotation(a) => f.debug_tuple("Annotation").field(&plain(&a.line)).finish(),
+			Line::Raw(r) => f.debug_tuple("Raw").field(&plain(&r.data)).finish(),
+			Line::Nop => write!(f, "Nop"),
+			Line::Gap(g) => f.debug_tuple("Gap").field(&plain(&g.line)).finish(),
+		}
+	}
+}
 impl Line {
-	fn text_mut(&mut self) -> Option<&mut Text> {
+	/// Text of the line, without gutter, `None` for raw lines
+	pub fn text(&self) -> Option<&Text> {
+		Some(match self {
+			Line::Text(t) => &t.line,
+			Line::Gap(t) => &t.line,
+			Line::Annotation(t) => &t.line,
+			_ => return None,
+		})
+	}
+	/// Gutter of the line, `None` for raw lines
+	pub fn prefix(&self) -> Option<&Text> {
+		Some(match self {
+			Line::Text(t) => &t.prefix,
+			Line::Gap(t) => &t.prefix,
+			Line::Annotation(t) => &t.prefix,
+			_ => return None,
+		})
+	}
+	/// Text of the line, without gutter, `None` for raw lines
+	pub fn text_mut(&mut self) -> Option<&mut Text> {
 		Some(match self {
 			Line::Text(t) => &mut t.line,
 			Line::Gap(t) => &mut t.line,
@@ -107,6 +239,15 @@ impl Line {
 			_ => return None,
 		})
 	}
+	/// Gutter of the line, `None` for raw lines
+	pub fn prefix_mut(&mut self) -> Option<&mut Text> {
+		Some(match self {
+			Line::Text(t) => &mut t.prefix,
+			Line::Gap(t) => &mut t.prefix,
+			Line::Annotation(t) => &mut t.prefix,
+			_ => return None,
+		})
+	}
 	fn is_text(&self) -> bool {
 		matches!(self, Self::Text(_))
 	}
@@ -128,31 +269,336 @@ impl Line {
 			_ => None,
 		}
 	}
-	fn as_gap_mut(&mut self) -> Option<&mut GapLine> {
+	fn as_gap(&self) -> Option<&GapLine> {
 		match self {
 			Line::Gap(t) => Some(t),
 			_ => None,
 		}
 	}
-	fn as_text(&self) -> Option<&TextLine> {
+	fn as_gap_mut(&mut self) -> Option<&mut GapLine> {
 		match self {
-			Line::Text(t) => Some(t),
+			Line::Gap(t) => Some(t),
 			_ => None,
 		}
 	}
-	fn as_raw(&self) -> Option<&RawLine> {
+	fn as_text(&self) -> Option<&TextLine> {
 		match self {
-			Line::Raw(r) => Some(r),
+			Line::Text(t) => Some(t),
 			_ => None,
 		}
 	}
 	fn is_nop(&self) -> bool {
 		matches!(self, Self::Nop)
 	}
+	/// Gutter and text joined, as the line is displayed
+	pub fn rendered(&self) -> Cow<'_, Text> {
+		let (prefix, line) = match self {
+			Line::Text(t) => (&t.prefix, &t.line),
+			Line::Annotation(t) => (&t.prefix, &t.line),
+			Line::Gap(t) => (&t.prefix, &t.line),
+			Line::Raw(r) => return Cow::Borrowed(&r.data),
+			Line::Nop => unreachable!("nops are removed by cleanup"),
+		};
+		let mut buf = SegmentBuffer::new([]);
+		buf.extend(prefix.clone());
+		buf.extend(line.clone());
+		Cow::Owned(buf)
+	}
+	fn to_rendered(&self) -> RenderedLine<'_> {
+		match self {
+			Line::Text(t) if t.continuation => RenderedLine::Continuation {
+				number: t.line_num,
+				gutter: &t.prefix,
+				text: &t.line,
+			},
+			Line::Text(t) => RenderedLine::Source {
+				number: t.line_num,
+				annotation: t.annotation.map(|a| a.0),
+				gutter: &t.prefix,
+				text: &t.line,
+			},
+			Line::Annotation(t) => RenderedLine::Label {
+				annotation: t.annotation.map(|a| a.0),
+				gutter: &t.prefix,
+				text: &t.line,
+			},
+			Line::Gap(t) => RenderedLine::Gap {
+				folded: t.line_nums.clone(),
+				bytes: t.bytes.clone(),
+				gutter: &t.prefix,
+				text: &t.line,
+			},
+			Line::Raw(r) => RenderedLine::Raw { text: &r.data },
+			Line::Nop => unreachable!("nops are removed by cleanup"),
+		}
+	}
+}
+
+/// Line of the rendered snippet, see [`Source::rendered_lines`]
+///
+/// `gutter` is the line number with [`Opts::line_prefixes`], [`Opts::left_margin`] and the frame border,
+/// connectors of the multi-line annotations are drawn into the `text`
+#[derive(Clone, PartialEq, Debug)]
+pub enum RenderedLine<'s> {
+	/// Line of the source text, numbered from 1
+	///
+	/// `annotation` is set for the lines connected to the multi-line annotation, by its index in order of addition
+	Source {
+		number: usize,
+		annotation: Option<usize>,
+		gutter: &'s Text,
+		text: &'s Text,
+	},
+	/// Rest of the source line `number`, which is wrapped with [`Opts::wrap`]
+	Continuation {
+		number: usize,
+		gutter: &'s Text,
+		text: &'s Text,
+	},
+	/// Row of the annotation ranges and labels, `annotation` is the same as for [`RenderedLine::Source`]
+	Label {
+		annotation: Option<usize>,
+		gutter: &'s Text,
+		text: &'s Text,
+	},
+	/// Replacement of the folded lines, with the numbers of the first and the last of them,
+	/// and the byte offsets of the folded text, see [`GapLine::folded_bytes`]
+	Gap {
+		folded: RangeInclusive<usize>,
+		bytes: std::ops::Range<usize>,
+		gutter: &'s Text,
+		text: &'s Text,
+	},
+	/// Frame borders, column ruler, legend, and the lines added with [`Source::push_raw`]
+	Raw { text: &'s Text },
 }
 
+#[derive(Clone)]
 pub struct Source {
 	lines: Vec<Line>,
+	warnings: Vec<RenderWarning>,
+	summaries: Vec<AnnotationSummary>,
+	/// Width of connector columns of every consecutive group of lines, set by [`pipeline::LineConnections`]
+	paddings: Vec<usize>,
+	/// Gutter of the rows wrapped with [`Opts::wrap`] in every consecutive group of lines, set by [`pipeline::LineNumbers`]
+	wrap_prefixes: Vec<Text>,
+	/// Number of columns removed by [`pipeline::Dedent`]
+	dedent: usize,
+	/// Line numbers replaced by every gap, set by [`pipeline::Finalize`]
+	folded: Vec<RangeInclusive<usize>>,
+	/// Set by [`pipeline::Finalize`], after it lines are only wrapped by the frame, and not trimmed by the cleanup
+	finalized: bool,
+	line_ending: LineEnding,
+	final_line_ending: bool,
+	/// Width of everything before the source text, except the line connectors
+	gutter_width: usize,
+	/// Multi-line annotations crossing the top and the bottom edge of [`FoldOpts::window`]
+	continued_above: HashSet<AnnotationId>,
+	continued_below: HashSet<AnnotationId>,
+}
+impl fmt::Debug for Source {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Source")
+			.field("lines", &self.lines)
+			.field("warnings", &self.warnings)
+			.field("summaries", &self.summaries)
+			.field("folded", &self.folded)
+			.finish_non_exhaustive()
+	}
+}
+impl Source {
+	pub fn lines(&self) -> &[Line] {
+		&self.lines
+	}
+	/// Lines can be modified by the custom [`pipeline::Stage`], but not added or removed
+	pub fn lines_mut(&mut self) -> &mut [Line] {
+		&mut self.lines
+	}
+	/// Position and text of every rendered annotation, for the terse `file:line:col: message` output
+	pub fn summaries(&self) -> &[AnnotationSummary] {
+		&self.summaries
+	}
+	/// Summary of the rendered annotation by its index in order of addition, i.e to find the [`Annotation::key`]
+	/// of the annotation in [`RenderedLine`]. Annotations merged into the other ones have no summary
+	pub fn summary(&self, annotation: usize) -> Option<&AnnotationSummary> {
+		self.summaries.iter().find(|s| s.annotation == annotation)
+	}
+	/// Ranges of the source line numbers, which were hidden by fold, in the order of the displayed gaps
+	pub fn folded(&self) -> &[RangeInclusive<usize>] {
+		&self.folded
+	}
+	/// Adjustments made to the annotations during rendering
+	pub fn warnings(&self) -> &[RenderWarning] {
+		&self.warnings
+	}
+	/// Rendered lines with their kinds, i.e for making only the source text selectable in UI
+	///
+	/// ```
+	/// use ass_stroke::{RenderedLine, SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let source = snippet.build();
+	/// let RenderedLine::Source { number, text, .. } = source.rendered_lines().next().unwrap() else {
+	///     panic!("first line is the source line");
+	/// };
+	/// assert_eq!(number, 1);
+	/// assert_eq!(text.data().collect::<String>(), "let a = 1; 🢒 here");
+	/// ```
+	pub fn rendered_lines(&self) -> impl Iterator<Item = RenderedLine<'_>> {
+		self.lines.iter().map(Line::to_rendered)
+	}
+	/// Display snippet with ANSI escape codes for colors
+	///
+	/// ```
+	/// use ass_stroke::{SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let source = snippet.build();
+	/// let message = format!("failed:\n{}", source.display_ansi());
+	/// assert!(message.contains("\x1b["));
+	/// ```
+	pub fn display_ansi(&self) -> DisplayAnsi<'_> {
+		DisplayAnsi(self)
+	}
+	/// Display snippet without styles, same as the [`fmt::Display`] of the [`Source`] itself
+	pub fn plain(&self) -> DisplayPlain<'_> {
+		DisplayPlain(self)
+	}
+	/// Write snippet with ANSI escape codes for colors, i.e directly to the stdout
+	pub fn write_ansi(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+		struct Adapter<'w> {
+			out: &'w mut dyn std::io::Write,
+			error: Option<std::io::Error>,
+		}
+		impl fmt::Write for Adapter<'_> {
+			fn write_str(&mut self, s: &str) -> fmt::Result {
+				self.out.write_all(s.as_bytes()).map_err(|e| {
+					self.error = Some(e);
+					fmt::Error
+				})
+			}
+		}
+		let mut adapter = Adapter { out, error: None };
+		match self.write_lines(&mut adapter, true) {
+			Ok(()) => Ok(()),
+			Err(fmt::Error) => Err(adapter
+				.error
+				.unwrap_or_else(|| std::io::Error::other("formatter error"))),
+		}
+	}
+	/// Shared by all the ways of outputting the snippet as a text
+	fn write_lines(&self, out: &mut dyn fmt::Write, ansi: bool) -> fmt::Result {
+		let mut buf = String::new();
+		for (i, line) in self.lines.iter().enumerate() {
+			let data = line.rendered();
+			if ansi {
+				buf.clear();
+				formatting::text_to_ansi(&data, &mut buf);
+				out.write_str(&buf)?;
+			} else {
+				for c in data.data() {
+					out.write_char(*c)?;
+				}
+			}
+			out.write_str(self.line_terminator(i))?;
+		}
+		Ok(())
+	}
+	/// [`Opts::line_ending`] after the line with this index
+	fn line_terminator(&self, index: usize) -> &'static str {
+		if index + 1 == self.lines.len() && !self.final_line_ending {
+			""
+		} else {
+			self.line_ending.as_str()
+		}
+	}
+	/// Display width of the gutter, including [`Opts::line_prefixes`], [`Opts::left_margin`], the frame border,
+	/// and the column of multi-line annotation connectors.
+	///
+	/// Pad custom lines with it, to align them with the source text
+	///
+	/// ```
+	/// use ass_stroke::{SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let mut source = snippet.build();
+	/// let hint = format!("{}run with RUST_BACKTRACE=1", " ".repeat(source.gutter_width()));
+	/// source.push_raw(Text::from(hint.as_str()));
+	/// assert_eq!(
+	///     source.plain().to_string(),
+	///     "1 let a = 1; 🢒 here\n  run with RUST_BACKTRACE=1\n",
+	/// );
+	/// ```
+	pub fn gutter_width(&self) -> usize {
+		// Connectors are drawn inside of the line text, so their column is not a part of the gutter itself
+		self.gutter_width + self.paddings.iter().max().copied().unwrap_or(0)
+	}
+	/// Append line, which is displayed as is, it is kept by the cleanup,
+	/// and gets the frame when added by a [`pipeline::Stage`] running before [`pipeline::Frame`]
+	pub fn push_raw(&mut self, text: Text) {
+		self.lines.push(Line::Raw(RawLine::new(text)));
+	}
+	/// Insert line, which is displayed as is, before the line at `index`
+	///
+	/// # Panics
+	///
+	/// If `index` is greater than the number of lines
+	pub fn insert_raw(&mut self, index: usize, text: Text) {
+		self.lines.insert(index, Line::Raw(RawLine::new(text)));
+	}
+	/// Width of the widest rendered line, in terminal cells
+	pub fn rendered_width(&self) -> usize {
+		self.lines
+			.iter()
+			.map(|line| match line {
+				Line::Text(t) => t.prefix.width() + t.line.width(),
+				Line::Annotation(t) => t.prefix.width() + t.line.width(),
+				Line::Gap(t) => t.prefix.width() + t.line.width(),
+				Line::Raw(r) => r.data.width(),
+				Line::Nop => 0,
+			})
+			.max()
+			.unwrap_or(0)
+	}
+	/// Snippet with only `height` rendered lines starting from `start`, i.e for scrolling the large snippet in TUI.
+	///
+	/// Lines are cut after processing, so the gutter stays as wide as in the full snippet,
+	/// and multi-line annotations keep the connectors of the visible lines
+	///
+	/// ```
+	/// use ass_stroke::{SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;\nlet b = 2;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let source = snippet.build();
+	/// assert_eq!(source.render_window(1, 10).plain().to_string(), "2 let b = 2; \n");
+	/// ```
+	pub fn render_window(&self, start: usize, height: usize) -> Source {
+		let start = start.min(self.lines.len());
+		let end = start.saturating_add(height).min(self.lines.len());
+		let lines = self.lines[start..end].to_vec();
+		Source {
+			folded: lines
+				.iter()
+				.filter_map(|l| l.as_gap().map(|g| g.line_nums.clone()))
+				.collect(),
+			lines,
+			warnings: self.warnings.clone(),
+			summaries: self.summaries.clone(),
+			paddings: self.paddings.clone(),
+			wrap_prefixes: self.wrap_prefixes.clone(),
+			dedent: self.dedent,
+			line_ending: self.line_ending,
+			final_line_ending: self.final_line_ending,
+			gutter_width: self.gutter_width,
+			continued_above: self.continued_above.clone(),
+			continued_below: self.continued_below.clone(),
+			finalized: self.finalized,
+		}
+	}
 }
 
 fn cleanup_nops(source: &mut Source) {
@@ -168,17 +614,21 @@ fn cleanup_nops(source: &mut Source) {
 
 /// Remove NOP/empty annotation lines
 fn cleanup(source: &mut Source) {
+	if source.finalized {
+		cleanup_nops(source);
+		return;
+	}
 	for slice in cons_slices(&mut source.lines, Line::is_text) {
 		for line in slice
 			.iter_mut()
-			.take_while(|l| l.as_text().unwrap().is_empty())
+			.take_while(|l| l.as_text().is_none_or(TextLine::is_trimmable))
 		{
 			*line = Line::Nop;
 		}
 		for line in slice
 			.iter_mut()
 			.rev()
-			.take_while(|l| l.as_text().unwrap().is_empty())
+			.take_while(|l| l.as_text().is_none_or(TextLine::is_trimmable))
 		{
 			*line = Line::Nop;
 		}
@@ -188,6 +638,17 @@ fn cleanup(source: &mut Source) {
 		if slice.len() == 1 {
 			continue;
 		}
+		let mut gaps = slice.iter().filter_map(Line::as_gap);
+		let first = gaps.next().expect("not empty");
+		let (mut line_nums, mut bytes) = (first.line_nums.clone(), first.bytes.clone());
+		for gap in gaps {
+			line_nums = *line_nums.start().min(gap.line_nums.start())
+				..=*line_nums.end().max(gap.line_nums.end());
+			bytes = bytes.start.min(gap.bytes.start)..bytes.end.max(gap.bytes.end);
+		}
+		let first = slice[0].as_gap_mut().expect("gap slice");
+		first.line_nums = line_nums;
+		first.bytes = bytes;
 		for ele in slice.iter_mut().skip(1) {
 			*ele = Line::Nop;
 		}
@@ -196,9 +657,27 @@ fn cleanup(source: &mut Source) {
 }
 
 fn fold(source: &mut Source, opts: &Opts) {
+	let last_line = source
+		.lines
+		.iter()
+		.filter_map(|l| l.as_text().map(|t| t.line_num))
+		.max();
+	// Anchored lines are displayed regardless of distance to annotations
+	let anchored = |t: &TextLine| {
+		(opts.fold.anchor_first_line && t.line_num == 1)
+			|| (opts.fold.anchor_last_line && Some(t.line_num) == last_line)
+	};
 	for slice in cons_slices(&mut source.lines, Line::is_text) {
+		let mut foldable = vec![false; slice.len()];
 		'line: for i in 0..slice.len() {
-			for j in i.saturating_sub(opts.context_lines)..=(i + opts.context_lines) {
+			// Lines carrying annotations are never folded, even if they were marked foldable by custom stages
+			if slice[i]
+				.as_text()
+				.is_some_and(|t| anchored(t) || t.has_annotations())
+			{
+				continue;
+			}
+			for j in i.saturating_sub(opts.fold.context_lines)..=(i + opts.fold.context_lines) {
 				let Some(ctx) = slice.get(j) else {
 						continue;
 					};
@@ -210,56 +689,295 @@ fn fold(source: &mut Source, opts: &Opts) {
 				}
 				continue 'line;
 			}
-			slice[i] = Line::Gap(GapLine {
-				prefix: Text::new([]),
-				line: Text::new([]),
-			});
+			foldable[i] = true;
+		}
+		// Gap is not shorter than the short runs of lines it replaces, those are kept as is
+		let mut i = 0;
+		while i < slice.len() {
+			if !foldable[i] {
+				i += 1;
+				continue;
+			}
+			let run = foldable[i..].iter().take_while(|f| **f).count();
+			if run >= opts.fold.min_fold {
+				for line in &mut slice[i..i + run] {
+					let t = line.as_text().expect("text slice");
+					*line = Line::Gap(GapLine {
+						prefix: Text::new([]),
+						line: Text::new([]),
+						line_nums: t.line_num..=t.line_num,
+						bytes: t.byte_offset..t.byte_end,
+					});
+				}
+			}
+			i += run;
 		}
 	}
 	cleanup(source);
 }
 
-fn draw_line_numbers(source: &mut Source) {
+fn highlight_lines(source: &mut Source, highlight: &Highlighter) {
+	for line in source.lines.iter_mut().filter_map(Line::as_text_mut) {
+		let eol = line.eol();
+		let text = line.line.data().take(eol).collect::<String>();
+		for (range, formatting) in highlight(line.line_num, &text) {
+			// Reserved EOL char is not a part of the source
+			let range = range.start..range.end.min(eol);
+			if range.is_empty() {
+				continue;
+			}
+			line.line.apply_meta(range, &formatting);
+		}
+	}
+}
+
+/// Replace annotated trailing whitespace with visible glyph, char offsets are not changed
+fn mark_trailing_whitespace(source: &mut Source, glyph: char) {
+	for line in source.lines.iter_mut().filter_map(Line::as_text_mut) {
+		let eol = line.eol();
+		let trailing_start = line
+			.line
+			.data()
+			.take(eol)
+			.enumerate()
+			.filter(|(_, c)| !c.is_whitespace())
+			.last()
+			.map_or(0, |(i, _)| i + 1);
+		for i in trailing_start..eol {
+			if !line.annotations.iter().any(|a| a.ranges.contains(i)) {
+				continue;
+			}
+			let (_, fmt) = line.line.get(i).expect("in bounds");
+			line.line.splice(
+				i..=i,
+				Some(SegmentBuffer::new([Segment::new([glyph], fmt)])),
+			);
+		}
+	}
+}
+
+/// Display trailing whitespace touching annotation ranges with dim `·` and `→` glyphs, tabs are already expanded,
+/// so only their first column is replaced
+fn show_trailing_whitespace(source: &mut Source, opts: &Opts) {
+	let dim = Formatting {
+		color: opts.style.gutter.color,
+		..Formatting::default()
+	};
+	for line in source.lines.iter_mut().filter_map(Line::as_text_mut) {
+		let eol = line.eol();
+		let trailing_start = line
+			.line
+			.data()
+			.take(eol)
+			.enumerate()
+			.filter(|(_, c)| !c.is_whitespace())
+			.last()
+			.map_or(0, |(i, _)| i + 1);
+		if trailing_start == eol {
+			continue;
+		}
+		let touched = line
+			.annotations
+			.iter()
+			.any(|a| (trailing_start.saturating_sub(1)..=eol).any(|i| a.ranges.contains(i)));
+		if !touched {
+			continue;
+		}
+		let mut i = trailing_start;
+		while i < eol {
+			let glyph = if line.tabs.contains_key(&i) {
+				'→'
+			} else {
+				'·'
+			};
+			line.line.splice(
+				i..=i,
+				Some(SegmentBuffer::new([Segment::new([glyph], dim.clone())])),
+			);
+			// Rest of the expanded tab is kept blank
+			i += line.tabs.get(&i).copied().unwrap_or(1);
+		}
+	}
+}
+
+/// Display whitespace covered by annotation ranges, spaces as `·`, and expanded tabs as `→···`,
+/// with the dimmed formatting of the annotation
+fn show_visible_whitespace(source: &mut Source) {
+	for line in source.lines.iter_mut().filter_map(Line::as_text_mut) {
+		let eol = line.eol();
+		let mut annotations = line.annotations.iter().collect::<Vec<_>>();
+		// Higher priority annotations are applied last, so their formatting wins
+		annotations.sort_by_key(|a| a.priority);
+		let mut glyphs = BTreeMap::new();
+		for annotation in annotations {
+			let formatting = annotation.formatting.clone().dim();
+			for i in annotation.ranges.elements().filter(|i| *i < eol) {
+				// Rest of the expanded tab is displayed the same way as spaces
+				let glyph = if line.tabs.contains_key(&i) {
+					'→'
+				} else {
+					'·'
+				};
+				glyphs.insert(i, (glyph, formatting.clone()));
+			}
+		}
+		for (i, (glyph, formatting)) in glyphs {
+			if line.line.get(i).expect("in bounds").0 != ' ' {
+				continue;
+			}
+			line.line.splice(
+				i..=i,
+				Some(SegmentBuffer::new([Segment::new([glyph], formatting)])),
+			);
+		}
+	}
+}
+
+fn draw_line_numbers(source: &mut Source, opts: &Opts) {
+	let format_number = |num: usize| match &opts.line_number_formatter {
+		Some(formatter) => formatter(num),
+		None => num.to_string(),
+	};
+	let icon_width = opts
+		.gutter_icons
+		.values()
+		.map(Text::width)
+		.max()
+		.unwrap_or(0);
+	let mut gutter_width = 0;
+	let mut wrap_prefixes = Vec::new();
 	for lines in &mut cons_slices(&mut source.lines, |l| {
 		l.is_annotation() || l.is_text() || l.is_gap()
 	}) {
-		let max_num = lines
+		let numbers = lines
 			.iter()
-			.filter_map(|l| match l {
-				Line::Text(t) => Some(t.line_num),
+			.map(|l| match l {
+				Line::Text(t) if opts.line_numbers => Some(match opts.gutter_numbers {
+					GutterNumbers::Lines => format_number(t.line_num),
+					GutterNumbers::ByteOffsets => t.byte_offset.to_string(),
+					GutterNumbers::Both => {
+						format!("{}@{}", format_number(t.line_num), t.byte_offset)
+					}
+				}),
 				_ => None,
 			})
+			.collect::<Vec<_>>();
+		// Without numbers single column is kept, so connectors still have a margin,
+		// it is also never narrower than the annotation and gap markers
+		let max_len = numbers
+			.iter()
+			.flatten()
+			.map(|n| n.chars().map(char_width).sum::<usize>())
 			.max()
-			.unwrap_or(0);
-		let max_len = max_num.to_string().len();
-		let prefix_segment = Segment::new(vec![' '; max_len - 1], Formatting::line_number());
-		for line in lines.iter_mut() {
+			.unwrap_or(1)
+			.max(1);
+		// Custom separator replaces annotation marker, which is also meaningless without numbers to distinguish from,
+		// gap marker is always kept, otherwise the gap looks like an empty source line
+		let (separator, annotation_marker) = match &opts.gutter_separator {
+			Some(separator) => (separator.as_str(), ' '),
+			None if !opts.line_numbers => (" ", ' '),
+			None => (" ", '·'),
+		};
+		let gap_marker = '⋮';
+		let continuation_marker = '↳';
+		// Icon cell is separated from the number with a space
+		let icon_cell = if icon_width == 0 { 0 } else { icon_width + 1 };
+		gutter_width = gutter_width
+			.max(max_len + icon_cell + separator.chars().map(char_width).sum::<usize>());
+		// Right-aligned by display width, as formatted numbers are not always ascii
+		let gutter = |column: &str, icon: Option<&Text>| {
+			let width = column.chars().map(char_width).sum::<usize>();
+			let mut data = vec![' '; max_len.saturating_sub(width)];
+			data.extend(column.chars());
+			let mut out = Text::single(data, opts.style.gutter.clone());
+			if icon_cell != 0 {
+				out.push(Segment::new([' '], opts.style.gutter.clone()));
+				let icon = icon.cloned().unwrap_or_else(Text::empty);
+				let padding = icon_width - icon.width();
+				out.extend(icon);
+				out.push(Segment::new(vec![' '; padding], opts.style.gutter.clone()));
+			}
+			out.push(Segment::new(separator.chars(), opts.style.gutter.clone()));
+			out
+		};
+		if opts.wrap {
+			wrap_prefixes.push(gutter(&continuation_marker.to_string(), None));
+		}
+		for (line, number) in lines.iter_mut().zip(numbers) {
 			match line {
-				Line::Text(t) => t.prefix.extend(SegmentBuffer::new([Segment::new(
-					format!("{:>width$} ", t.line_num, width = max_len).chars(),
-					Formatting::line_number(),
-				)])),
-				Line::Annotation(a) => a.prefix.extend(SegmentBuffer::new([
-					prefix_segment.clone(),
-					Segment::new(['·', ' '], Formatting::line_number()),
-				])),
-				Line::Gap(a) => a.prefix.extend(SegmentBuffer::new([
-					prefix_segment.clone(),
-					Segment::new(['⋮', ' '], Formatting::line_number()),
-				])),
+				Line::Text(t) => {
+					let icon = t.severity.and_then(|s| opts.gutter_icons.get(&s));
+					t.prefix
+						.extend(gutter(number.as_deref().unwrap_or(""), icon));
+				}
+				Line::Annotation(a) => a
+					.prefix
+					.extend(gutter(&annotation_marker.to_string(), None)),
+				Line::Gap(a) => {
+					a.prefix.extend(gutter(&gap_marker.to_string(), None));
+					if opts.fold.show_range {
+						let (start, end) = a.line_nums.clone().into_inner();
+						let range = if start == end {
+							format_number(start)
+						} else {
+							format!("{}..{}", format_number(start), format_number(end))
+						};
+						a.line.extend(SegmentBuffer::new([Segment::new(
+							range.chars(),
+							opts.style.gutter.clone(),
+						)]));
+					}
+				}
 				_ => unreachable!(),
 			}
 		}
 	}
+	source.gutter_width = gutter_width;
+	source.wrap_prefixes = wrap_prefixes;
+}
+
+/// Insert [`Opts::line_prefixes`] before the gutter, other lines are padded to the widest prefix
+fn draw_line_prefixes(source: &mut Source, opts: &Opts) {
+	let width = opts
+		.line_prefixes
+		.values()
+		.map(Text::width)
+		.max()
+		.unwrap_or(0);
+	source.gutter_width += width;
+	for prefix in &mut source.wrap_prefixes {
+		prefix.splice(
+			0..0,
+			Some(Text::single(vec![' '; width], Formatting::default())),
+		);
+	}
+	for line in &mut source.lines {
+		let (prefix, marker) = match line {
+			Line::Text(t) => (&mut t.prefix, opts.line_prefixes.get(&t.line_num)),
+			Line::Annotation(t) => (&mut t.prefix, None),
+			Line::Gap(t) => (&mut t.prefix, None),
+			Line::Raw(_) | Line::Nop => continue,
+		};
+		let mut marker = marker.cloned().unwrap_or_else(Text::empty);
+		let padding = width - marker.width();
+		marker.extend(Text::single(vec![' '; padding], Formatting::default()));
+		prefix.splice(0..0, Some(marker));
+	}
 }
 
+/// Returns padding inserted before the text of each slice
 fn draw_line_connections(
 	source: &mut Source,
-	annotation_formats: HashMap<AnnotationId, Formatting>,
-) {
-	for lines in &mut cons_slices(&mut source.lines, |l| {
+	annotation_formats: &HashMap<AnnotationId, Formatting>,
+	connector_columns: &HashMap<AnnotationId, ConnectorColumn>,
+) -> Vec<usize> {
+	let mut paddings = Vec::new();
+	let mut slices = cons_slices(&mut source.lines, |l| {
 		l.is_annotation() || l.is_text() || l.is_gap()
-	}) {
+	});
+	let last_slice = slices.len().saturating_sub(1);
+	for (slice, lines) in slices.iter_mut().enumerate() {
+		let mut padding = 0;
 		#[derive(Debug)]
 		struct Connection {
 			range: Range<usize>,
@@ -287,16 +1005,61 @@ fn draw_line_connections(
 				conn.connected.push(i);
 			}
 		}
+		// Connections continuing past the displayed lines reach the snippet edges
+		let last = lines.len() - 1;
+		let continued_above = if slice == 0 {
+			&source.continued_above
+		} else {
+			&HashSet::new()
+		};
+		let continued_below = if slice == last_slice {
+			&source.continued_below
+		} else {
+			&HashSet::new()
+		};
+		for id in continued_above {
+			let conn = connected_annotations.entry(*id).or_insert(Connection {
+				range: Range::new(0, last),
+				connected: Vec::new(),
+			});
+			conn.range.start = 0;
+		}
+		for id in continued_below {
+			let conn = connected_annotations.entry(*id).or_insert(Connection {
+				range: Range::new(0, last),
+				connected: Vec::new(),
+			});
+			conn.range.end = last;
+		}
 		let mut grouped = connected_annotations
 			.iter()
 			.map(|(k, v)| (*k, vec![v.range].into_iter().collect::<RangeSet<usize>>()))
 			.collect::<Vec<_>>();
 
-		grouped.sort_by_key(|a| a.1.num_elements());
-		let grouped = single_line::group_nonconflicting(&grouped, &HashSet::new());
+		// Shorter connections are drawn first, and then are pushed right by the longer ones, so
+		// nested connections end up inside of the outer ones, while the non-overlapping annotations
+		// end up in the same group and share the connector column.
+		// Ties are ordered by position, as iteration order of the map is not stable between runs
+		grouped.sort_by_key(|(id, ranges)| (ranges.num_elements(), ranges.elements().next(), id.0));
+		// Pinned connections are grouped separately, so they are placed inside or outside of all the others
+		let grouped = [
+			ConnectorColumn::Innermost,
+			ConnectorColumn::Auto,
+			ConnectorColumn::Outermost,
+		]
+		.into_iter()
+		.flat_map(|column| {
+			let pinned = grouped
+				.iter()
+				.filter(|(id, _)| connector_columns.get(id).copied().unwrap_or_default() == column)
+				.cloned()
+				.collect::<Vec<_>>();
+			single_line::group_nonconflicting(&pinned, &HashSet::new())
+		})
+		.collect::<Vec<_>>();
 
 		for group in grouped {
-			for annotation in group {
+			for (i, annotation) in group.into_iter().enumerate() {
 				let annotation_fmt = annotation_formats
 					.get(&annotation)
 					.expect("id is used in string but not defined")
@@ -304,28 +1067,12 @@ fn draw_line_connections(
 					.decoration();
 				let conn = connected_annotations.get(&annotation).expect("exists");
 				let range = conn.range;
-				let mut max_index = usize::MAX;
-				for line in range.start..=range.end {
-					match &lines[line] {
-						Line::Text(t) if t.line.data().all(|c| c.is_whitespace()) => {}
-						Line::Text(t) => {
-							let whitespaces =
-								t.line.data().take_while(|i| i.is_whitespace()).count();
-							max_index = max_index.min(whitespaces)
-						}
-						Line::Annotation(t) if t.line.data().all(|c| c.is_whitespace()) => {}
-						Line::Annotation(t) => {
-							let whitespaces =
-								t.line.data().take_while(|i| i.is_whitespace()).count();
-							max_index = max_index.min(whitespaces)
-						}
-						Line::Gap(_) => {}
-						_ => unreachable!(),
-					}
-				}
-				while max_index < 2 {
+				// Every group gets its own connector column, source indentation is kept intact.
+				// Annotation lines are already applied at this point, so padding is inserted into them too,
+				// and range markers stay aligned with the source text
+				if i == 0 {
 					let seg = Some(SegmentBuffer::new([Segment::new(
-						vec![' '; 2 - max_index],
+						vec![' '; 2],
 						annotation_fmt.clone(),
 					)]));
 					for line in lines.iter_mut() {
@@ -336,64 +1083,265 @@ fn draw_line_connections(
 							_ => unreachable!(),
 						}
 					}
-					max_index = 2;
+					padding += 2;
 				}
-				if max_index >= 2 {
-					let offset = max_index - 2;
-
-					for line in range.start..=range.end {
-						use chars::line::*;
-						let char = if range.start == range.end {
-							RANGE_EMPTY
-						} else if line == range.start {
-							RANGE_START
-						} else if line == range.end {
-							RANGE_END
-						} else if conn.connected.contains(&line) {
-							RANGE_CONNECTION
-						} else {
-							RANGE_CONTINUE
-						};
-						let text = lines[line].text_mut().expect("only with text reachable");
-						if text.len() <= offset {
-							text.resize(offset + 1, ' ', annotation_fmt.clone());
-						}
-						text.splice(
-							offset..=offset,
-							Some(SegmentBuffer::new([Segment::new(
-								[char],
-								annotation_fmt.clone(),
-							)])),
-						);
-
-						if conn.connected.contains(&line) {
-							for i in offset + 1..text.len() {
-								let (char, fmt) = text.get(i).expect("in bounds");
-								if !text.get(i).expect("in bounds").0.is_whitespace()
-									&& !fmt.decoration
-								{
-									break;
-								}
-								if let Some((keep_style, replacement)) = cross(char) {
-									text.splice(
-										i..=i,
-										Some(SegmentBuffer::new([Segment::new(
-											[replacement],
-											if keep_style {
-												fmt
-											} else {
-												annotation_fmt.clone()
-											},
-										)])),
-									)
-								}
+				for line in range.start..=range.end {
+					use chars::line::*;
+					let above = line == range.start && continued_above.contains(&annotation);
+					let below = line == range.end && continued_below.contains(&annotation);
+					let char = if above && below {
+						RANGE_CONTINUED_BOTH
+					} else if above {
+						RANGE_CONTINUED_ABOVE
+					} else if below {
+						RANGE_CONTINUED_BELOW
+					} else if range.start == range.end {
+						RANGE_EMPTY
+					} else if line == range.start {
+						RANGE_START
+					} else if line == range.end {
+						RANGE_END
+					} else if conn.connected.contains(&line) {
+						RANGE_CONNECTION
+					} else {
+						RANGE_CONTINUE
+					};
+					let text = lines[line].text_mut().expect("only with text reachable");
+					// Connector column is the padding inserted for the group, labels are never placed into it
+					debug_assert!(text.get(0).is_some_and(|(_, fmt)| fmt.decoration));
+					text.splice(
+						0..=0,
+						Some(SegmentBuffer::new([Segment::new(
+							[char],
+							annotation_fmt.clone(),
+						)])),
+					);
+
+					if conn.connected.contains(&line) {
+						for i in 1..text.len() {
+							let (char, fmt) = text.get(i).expect("in bounds");
+							if !text.get(i).expect("in bounds").0.is_whitespace() && !fmt.decoration
+							{
+								break;
+							}
+							if let Some((keep_style, replacement)) = cross(char) {
+								text.splice(
+									i..=i,
+									Some(SegmentBuffer::new([Segment::new(
+										[replacement],
+										if keep_style {
+											fmt
+										} else {
+											annotation_fmt.clone()
+										},
+									)])),
+								)
 							}
 						}
 					}
 				}
 			}
 		}
+		paddings.push(padding);
+	}
+	paddings
+}
+
+fn apply_left_margin(source: &mut Source, opts: &Opts) {
+	let margin = Text::single(vec![' '; opts.left_margin], Formatting::default());
+	source.gutter_width += opts.left_margin;
+	let lines = source.lines.iter_mut().filter_map(Line::prefix_mut);
+	for prefix in lines.chain(&mut source.wrap_prefixes) {
+		match opts.margin_position {
+			MarginPosition::BeforeGutter => prefix.splice(0..0, Some(margin.clone())),
+			MarginPosition::AfterGutter => prefix.extend(margin.clone()),
+		}
+	}
+}
+
+/// Move labels anchored to the right margin, so the widest row of every label ends at `max_width`
+fn anchor_labels_right(source: &mut Source, max_width: usize) {
+	let mut widths: HashMap<AnnotationId, usize> = HashMap::new();
+	for line in &source.lines {
+		if let Line::Annotation(
+			t @ AnnotationLine {
+				anchor: Some(anchor),
+				..
+			},
+		) = line
+		{
+			let width = widths.entry(anchor.id).or_default();
+			*width = (*width).max(t.prefix.width() + t.line.width());
+		}
+	}
+	for line in &mut source.lines {
+		let Line::Annotation(AnnotationLine {
+			line,
+			anchor: Some(anchor),
+			..
+		}) = line
+		else {
+			continue;
+		};
+		// Labels wider than the margin are kept in place
+		let shift = max_width.saturating_sub(widths[&anchor.id]);
+		let (c, formatting) = anchor
+			.leader
+			.clone()
+			.unwrap_or((' ', Formatting::default()));
+		let at = line.len() - anchor.len;
+		line.splice(at..at, Some(Text::single(vec![c; shift], formatting)));
+	}
+}
+
+/// Remove indentation shared by all the displayed source lines and annotation rows
+fn dedent(source: &mut Source) {
+	let leading = |text: &Text| text.data().take_while(|c| **c == ' ').count();
+	let Some(shared) = source
+		.lines
+		.iter()
+		.filter_map(|l| match l {
+			Line::Text(t) if !t.is_empty() => Some(leading(&t.line)),
+			Line::Annotation(a) if a.line.data().any(|c| *c != ' ') => Some(leading(&a.line)),
+			_ => None,
+		})
+		.min()
+	else {
+		return;
+	};
+	if shared == 0 {
+		return;
+	}
+	for line in &mut source.lines {
+		let text = match line {
+			// Reserved EOL char of the blank lines is kept
+			Line::Text(t) => {
+				let remove = shared.min(t.eol());
+				t.line.splice(0..remove, None);
+				continue;
+			}
+			Line::Annotation(a) => &mut a.line,
+			_ => continue,
+		};
+		let remove = shared.min(leading(text));
+		text.splice(0..remove, None);
+	}
+	source.dedent = shared;
+}
+
+/// Inserts column numbers above every slice, text columns are counted from 1
+fn draw_column_ruler(source: &mut Source, paddings: &[usize], opts: &Opts) {
+	let dedent = source.dedent;
+	let is_slice = |l: &Line| l.is_annotation() || l.is_text() || l.is_gap();
+	let mut paddings = paddings.iter();
+	let mut out = Vec::with_capacity(source.lines.len());
+	let mut lines = source.lines.drain(..).peekable();
+	while let Some(line) = lines.next() {
+		if !is_slice(&line) {
+			out.push(line);
+			continue;
+		}
+		let padding = *paddings
+			.next()
+			.expect("padding is computed for every slice");
+		let mut slice = vec![line];
+		while let Some(line) = lines.next_if(is_slice) {
+			slice.push(line);
+		}
+
+		let gutter = slice
+			.iter()
+			.find_map(Line::as_text)
+			.map(|t| t.prefix.width())
+			.unwrap_or(0);
+		let width = slice
+			.iter()
+			.map(|l| match l {
+				Line::Text(t) => t.prefix.width() + t.line.width(),
+				Line::Annotation(t) => t.prefix.width() + t.line.width(),
+				Line::Gap(t) => t.prefix.width() + t.line.width(),
+				_ => unreachable!(),
+			})
+			.max()
+			.unwrap_or(0);
+		// Columns are numbered in the original source, even if the indentation was removed
+		let columns = dedent + 1..=dedent + width.saturating_sub(gutter + padding);
+
+		let ruler = |digit: &dyn Fn(usize) -> char| {
+			let mut data = vec![' '; gutter + padding];
+			data.extend(columns.clone().map(digit));
+			Line::Raw(RawLine::new(Text::single(data, opts.style.gutter.clone())))
+		};
+		if *columns.end() >= 10 {
+			out.push(ruler(&|c| {
+				if c % 10 == 0 {
+					char::from_digit((c / 10 % 10) as u32, 10).expect("single digit")
+				} else {
+					' '
+				}
+			}));
+		}
+		out.push(ruler(&|c| {
+			char::from_digit((c % 10) as u32, 10).expect("single digit")
+		}));
+		out.extend(slice);
+	}
+	drop(lines);
+	source.lines = out;
+}
+
+fn draw_frame(source: &mut Source, title: &Text) {
+	use chars::frame::*;
+	if source.lines.is_empty() {
+		return;
+	}
+	let title_width = title.width();
+	let width = source
+		.rendered_width()
+		.max(if title_width == 0 { 0 } else { title_width + 1 });
+	let fmt = Formatting::frame().decoration();
+
+	let mut top = Text::single([TOP_LEFT, HORIZONTAL], fmt.clone());
+	let mut top_width = 0;
+	if title_width != 0 {
+		top.extend(Text::single([' '], fmt.clone()));
+		top.extend(title.clone());
+		top.extend(Text::single([' '], fmt.clone()));
+		top_width = title_width + 2;
+	}
+	top.extend(Text::single(
+		vec![HORIZONTAL; width + 1 - top_width]
+			.into_iter()
+			.chain([TOP_RIGHT]),
+		fmt.clone(),
+	));
+
+	for line in source.lines.iter_mut() {
+		let padding = width - line.rendered().width();
+		let left = Text::single([VERTICAL, ' '], fmt.clone());
+		let right = Text::single(
+			vec![' '; padding + 1].into_iter().chain([VERTICAL]),
+			fmt.clone(),
+		);
+		if let Line::Raw(raw) = line {
+			raw.data.splice(0..0, Some(left));
+			raw.data.extend(right);
+		} else {
+			line.prefix_mut().expect("not raw").splice(0..0, Some(left));
+			line.text_mut().expect("not raw").extend(right);
+		}
 	}
+
+	let bottom = Text::single(
+		[BOTTOM_LEFT]
+			.into_iter()
+			.chain(vec![HORIZONTAL; width + 2])
+			.chain([BOTTOM_RIGHT]),
+		fmt,
+	);
+	source.lines.insert(0, Line::Raw(RawLine::new(top)));
+	source.lines.push(Line::Raw(RawLine::new(bottom)));
+	source.gutter_width += 2;
 }
 
 fn generate_annotations(source: &mut Source, opts: &Opts) {
@@ -403,8 +1351,30 @@ fn generate_annotations(source: &mut Source, opts: &Opts) {
 		.flat_map(Line::as_text_mut)
 		.filter(|t| !t.annotations.is_empty())
 	{
-		let hide_ranges_for = if opts.apply_to_orig {
+		let mut elided = 0;
+		if let Some(max_annotations) = opts.max_annotations_per_line {
+			// Multi-line annotations are never removed, as their connections span other lines
+			let kept = line.annotations.iter().filter(|a| a.left).count();
+			let mut removable = line
+				.annotations
+				.iter()
+				.filter(|a| !a.left)
+				.map(|a| (a.id, a.priority))
+				.collect::<Vec<_>>();
+			removable.sort_by_key(|(_, priority)| Reverse(*priority));
+			let removed = removable
+				.into_iter()
+				.skip(max_annotations.saturating_sub(kept))
+				.map(|(id, _)| id)
+				.collect::<HashSet<_>>();
+			line.annotations.retain(|a| !removed.contains(&a.id));
+			elided += removed.len();
+		}
+
+		let mut inline_labels = 0;
+		let hide_ranges_for = if opts.apply_to_orig && opts.inline_labels {
 			let parsed = inline::group_singleline(&line.annotations);
+			inline_labels = usize::from(parsed.right.is_some());
 			assert!(line.annotation.is_none());
 			line.annotation = parsed.annotation;
 			inline::apply_inline_annotations(&mut line.line, &parsed.inline, parsed.right);
@@ -414,18 +1384,53 @@ fn generate_annotations(source: &mut Source, opts: &Opts) {
 			line.fold = false;
 
 			parsed.hide_ranges_for
+		} else if opts.apply_to_orig {
+			inline::apply_range_formatting(&mut line.line, &line.annotations);
+			HashSet::new()
 		} else {
 			HashSet::new()
 		};
 
+		if let Some(max_labels) = opts.max_labels_per_line {
+			let mut labeled = line
+				.annotations
+				.iter_mut()
+				.filter(|a| !a.right.is_empty())
+				.collect::<Vec<_>>();
+			// Multi-line annotations are never elided, as their connections span other lines
+			let kept = labeled.iter().filter(|a| a.left).count() + inline_labels;
+			labeled.retain(|a| !a.left);
+			labeled.sort_by_key(|a| Reverse(a.priority));
+			// Ranges are still displayed, only label rows are removed
+			for annotation in labeled.into_iter().skip(max_labels.saturating_sub(kept)) {
+				annotation.right = Text::empty();
+				elided += 1;
+			}
+		}
+
 		let char_to_display_fixup = fixup_char_to_display(line.line.data().copied());
 		let mut extra = single_line::generate_range_annotations(
 			line.annotations.clone(),
 			&char_to_display_fixup,
 			&hide_ranges_for,
 			false,
+			opts.label_stacking,
+			opts.range_pointers,
 		);
 		extra.reverse();
+		if elided != 0 {
+			extra.insert(
+				0,
+				(
+					None,
+					Text::single(
+						format!("… and {elided} more").chars(),
+						opts.style.gutter.clone(),
+					),
+					None,
+				),
+			);
+		}
 		// TODO: instead of writing generated annotations into lines, return them from this function, and apply later
 		line.top_annotations = extra;
 		line.annotations.truncate(0);
@@ -447,13 +1452,14 @@ fn apply_annotations(source: &mut Source) {
 			}
 		}
 		insertions.reverse();
-		for (i, (annotation, line)) in insertions {
+		for (i, (annotation, line, anchor)) in insertions {
 			source.lines.insert(
 				i - 1,
 				Line::Annotation(AnnotationLine {
 					line,
 					annotation,
 					prefix: SegmentBuffer::new([]),
+					anchor,
 				}),
 			);
 		}
@@ -472,64 +1478,137 @@ fn apply_annotations(source: &mut Source) {
 			}
 		}
 		insertions.reverse();
-		for (i, (annotation, line)) in insertions {
+		for (i, (annotation, line, anchor)) in insertions {
 			source.lines.insert(
 				i,
 				Line::Annotation(AnnotationLine {
 					line,
 					annotation,
 					prefix: SegmentBuffer::new([]),
+					anchor,
 				}),
 			);
 		}
 	}
 }
 
-fn process(
-	source: &mut Source,
-	annotation_formats: HashMap<AnnotationId, Formatting>,
-	opts: &Opts,
-) {
-	cleanup(source);
-	// Format inline annotations
-	generate_annotations(source, opts);
-	// Make gaps in files
-	if opts.fold {
-		fold(source, opts)
-	}
-	// Expand annotation buffers
-	apply_annotations(source);
-	// Connect annotation lines
-	draw_line_connections(source, annotation_formats);
-	// Apply line numbers
-	draw_line_numbers(source);
-	// To raw
-	{
-		for line in &mut source.lines {
-			match line {
-				Line::Text(t) => {
-					let mut buf = SegmentBuffer::new([]);
-					buf.extend(t.prefix.clone());
-					buf.extend(t.line.clone());
-					*line = Line::Raw(RawLine { data: buf });
-				}
-				Line::Annotation(t) => {
-					let mut buf = SegmentBuffer::new([]);
-					buf.extend(t.prefix.clone());
-					buf.extend(t.line.clone());
-					*line = Line::Raw(RawLine { data: buf })
+fn finalize(source: &mut Source, opts: &Opts) {
+	for line in &mut source.lines {
+		match line {
+			Line::Text(t) => {
+				if opts.trim_eol && !t.eol_used {
+					// Reserved char is the last one, unless inline label is placed after it
+					let len = t.line.len();
+					let blank = t.line.get(len.wrapping_sub(1)).is_some_and(|(c, fmt)| {
+						c == ' ' && fmt.bg_color.is_none() && !fmt.underline
+					});
+					if blank {
+						t.line.splice(len - 1..len, None);
+					}
 				}
-				Line::Gap(t) => {
-					let mut buf = SegmentBuffer::new([]);
-					buf.extend(t.prefix.clone());
-					buf.extend(t.line.clone());
-					*line = Line::Raw(RawLine { data: buf })
+			}
+			Line::Gap(t) => source.folded.push(t.line_nums.clone()),
+			Line::Annotation(_) | Line::Raw(_) | Line::Nop => {}
+		}
+	}
+	source.finalized = true;
+}
+
+/// Split source lines wider than `max_width` into the continuation rows, connectors passing through the line are
+/// continued in them. Lines are kept as is if less than two columns are left after the gutter, as wide chars won't fit
+fn wrap_lines(source: &mut Source, max_width: usize) {
+	let is_slice = |l: &Line| l.is_annotation() || l.is_text() || l.is_gap();
+	let mut paddings = source.paddings.iter().copied();
+	let mut prefixes = source.wrap_prefixes.iter();
+	let mut padding = 0;
+	let mut prefix = Text::empty();
+	let mut in_slice = false;
+	let mut out = Vec::with_capacity(source.lines.len());
+	for line in source.lines.drain(..) {
+		if !is_slice(&line) {
+			in_slice = false;
+			out.push(line);
+			continue;
+		}
+		if !in_slice {
+			padding = paddings.next().unwrap_or(0);
+			prefix = prefixes.next().cloned().unwrap_or_else(Text::empty);
+			in_slice = true;
+		}
+		let Line::Text(mut t) = line else {
+			out.push(line);
+			continue;
+		};
+		let width = max_width.saturating_sub(t.prefix.width() + padding);
+		if width < 2 || t.line.width() <= width + padding {
+			out.push(Line::Text(t));
+			continue;
+		}
+		let connectors = t.line.slice(..padding);
+		let mut continued = Text::empty();
+		for segment in connectors.segments() {
+			continued.push(Segment::new(
+				segment.iter().map(|c| {
+					if chars::line::continues_below(*c) {
+						chars::line::RANGE_CONTINUE
+					} else {
+						' '
+					}
+				}),
+				segment.meta().clone(),
+			));
+		}
+		let mut rest = t.line.slice(padding..);
+		let mut rows = Vec::new();
+		while !rest.is_empty() {
+			let mut taken = 0;
+			let mut len = 0;
+			for c in rest.data() {
+				taken += char_width(*c);
+				if taken > width {
+					break;
 				}
-				Line::Raw(_) | Line::Nop => {}
+				len += 1;
 			}
+			rows.push(rest.slice(..len.max(1)));
+			rest = rest.slice(len.max(1)..);
+		}
+		let mut rows = rows.into_iter();
+		let mut first = connectors;
+		first.extend(rows.next().expect("line is not empty"));
+		let mut continuation = t.clone();
+		continuation.prefix = prefix.clone();
+		continuation.annotation = None;
+		continuation.continuation = true;
+		t.line = first;
+		out.push(Line::Text(t));
+		for row in rows {
+			let mut line = continued.clone();
+			line.extend(row);
+			out.push(Line::Text(TextLine {
+				line,
+				..continuation.clone()
+			}));
 		}
 	}
-	cleanup(source);
+	source.lines = out;
+}
+
+fn process(
+	source: &mut Source,
+	annotation_formats: HashMap<AnnotationId, Formatting>,
+	connector_columns: HashMap<AnnotationId, ConnectorColumn>,
+	opts: &Opts,
+) {
+	let ctx = Ctx {
+		opts,
+		annotation_formats,
+		connector_columns,
+	};
+	match &opts.pipeline {
+		Some(pipeline) => pipeline.run(source, &ctx),
+		None => Pipeline::default().run(source, &ctx),
+	}
 }
 
 fn linestarts(str: &str) -> BTreeSet<usize> {
@@ -541,9 +1620,29 @@ fn linestarts(str: &str) -> BTreeSet<usize> {
 	}
 	linestarts
 }
-struct LineCol {
-	line: usize,
-	column: usize,
+/// Position in the source, both line and column are counted from 0.
+///
+/// When passed to [`AnnotationBuilder::line_col`], column is counted in chars of the original line,
+/// so neither tab expansion, nor multibyte chars need to be accounted for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineCol {
+	pub line: usize,
+	pub column: usize,
+}
+/// Byte offset of the position, line breaks are the same as displayed, column equal to the line length points to its EOL
+fn linecol_to_offset(src: &str, linestarts: &[usize], pos: LineCol) -> Option<usize> {
+	let start = *linestarts.get(pos.line)?;
+	let end = linestarts.get(pos.line + 1).copied().unwrap_or(src.len());
+	let line = &src[start..end];
+	let line = line
+		.strip_suffix("\r\n")
+		.or_else(|| line.strip_suffix(['\n', '\r', '\u{2028}', '\u{2029}']))
+		.unwrap_or(line);
+	line.char_indices()
+		.map(|(i, _)| i)
+		.chain([line.len()])
+		.nth(pos.column)
+		.map(|i| start + i)
 }
 fn offset_to_linecol(mut offset: usize, linestarts: &BTreeSet<usize>) -> LineCol {
 	let mut line = 0;
@@ -560,53 +1659,431 @@ fn offset_to_linecol(mut offset: usize, linestarts: &BTreeSet<usize>) -> LineCol
 	}
 }
 
-fn parse(txt: &str, annotations: &[Annotation], opts: &Opts) -> Source {
-	let (txt, byte_to_char_fixup) = fixup_byte_to_char(txt, opts.tab_width);
-	let mut annotations = annotations.to_vec();
+/// Collapse annotations with the same ranges, text and formatting, the highest priority is kept
+fn deduplicate(
+	annotations: &[(usize, Annotation)],
+	mode: Deduplicate,
+	warnings: &mut Vec<RenderWarning>,
+) -> Vec<(usize, Annotation)> {
+	let mut out: Vec<(usize, Annotation, usize)> = Vec::new();
+	for (i, annotation) in annotations {
+		// Builder gives every annotation its own color, so only the plain text and severity are compared
+		if let Some((original, existing, count)) = out.iter_mut().find(|(_, a, _)| {
+			a.ranges == annotation.ranges
+				&& a.text.data().eq(annotation.text.data())
+				&& a.severity == annotation.severity
+				&& a.key == annotation.key
+		}) {
+			existing.priority = existing.priority.max(annotation.priority);
+			*count += 1;
+			warnings.push(RenderWarning::Collapsed {
+				annotation: *i,
+				original: *original,
+			});
+		} else {
+			out.push((*i, annotation.clone(), 1));
+		}
+	}
+	out.into_iter()
+		.map(|(i, mut annotation, count)| {
+			if mode == Deduplicate::Count && count > 1 && !annotation.text.is_empty() {
+				annotation
+					.text
+					.append_styled(&format!(" (×{count})"), Formatting::default());
+			}
+			(i, annotation)
+		})
+		.collect()
+}
+
+/// Merge single-line annotations with the same text, severity and key, which are placed on consecutive lines,
+/// so their label is displayed once. Ranges are expected to be already converted to char offsets
+fn collapse_adjacent(
+	annotations: Vec<(usize, Annotation)>,
+	linestarts: &BTreeSet<usize>,
+) -> Vec<(usize, Annotation)> {
+	let single_line = |annotation: &Annotation| {
+		let first = annotation.ranges.ranges().next()?;
+		let last = annotation.ranges.ranges().last()?;
+		let line = offset_to_linecol(first.start, linestarts).line;
+		(offset_to_linecol(last.end, linestarts).line == line).then_some(line)
+	};
+	// Merged annotation, and the last line it was extended to
+	let mut out: Vec<(usize, Annotation, Option<usize>)> = Vec::new();
+	for (id, annotation) in annotations {
+		let line = single_line(&annotation).filter(|_| !annotation.text.is_empty());
+		let group = line.and_then(|line| {
+			out.iter_mut().find(|(_, a, last)| {
+				// Builder gives every annotation its own color, so only the plain text is compared
+				*last == Some(line.wrapping_sub(1))
+					&& a.text.data().eq(annotation.text.data())
+					&& a.severity == annotation.severity
+					&& a.key == annotation.key
+					&& a.connect_lines == annotation.connect_lines
+			})
+		});
+		match group {
+			Some((_, existing, last)) => {
+				existing.priority = existing.priority.max(annotation.priority);
+				existing.ranges = existing.ranges.union(&annotation.ranges);
+				*last = line;
+			}
+			None => out.push((id, annotation, line)),
+		}
+	}
+	out.into_iter()
+		.map(|(id, annotation, _)| (id, annotation))
+		.collect()
+}
+
+/// Annotations are paired with their index in order of addition, which is reported in errors
+fn validate(annotations: &[(usize, Annotation)], opts: &Opts) -> Result<(), AnnotationError> {
+	for (n, (i, annotation)) in annotations.iter().enumerate() {
+		let i = *i;
+		let previous = || annotations[..n].iter();
+		if annotation.ranges.is_empty() {
+			return Err(AnnotationError::EmptyRanges { annotation: i });
+		}
+		opts.validate_annotation(i, annotation)?;
+		if let Some((original, _)) = previous().find(|(_, a)| a == annotation) {
+			return Err(AnnotationError::Duplicate {
+				annotation: i,
+				original: *original,
+			});
+		}
+		if let Some(key) = annotation.key {
+			if let Some((original, _)) = previous().find(|(_, a)| a.key == Some(key)) {
+				return Err(AnnotationError::DuplicateKey {
+					key,
+					annotation: i,
+					original: *original,
+				});
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Source text, which is already split into lines, but not yet processed
+///
+/// Annotations may be added to it, and it can be rendered multiple times,
+/// i.e when they are found at different phases of the compilation
+///
+/// ```
+/// use ass_stroke::{Annotation, Formatting, Opts, PreparedSource, Text};
+/// use range_map::Range;
+///
+/// fn annotation(start: usize, end: usize, formatting: Formatting, text: &str) -> Annotation {
+///     Annotation::new([Range::new(start, end)].into_iter().collect(), Text::from(text), formatting)
+/// }
+///
+/// let mut prepared = PreparedSource::new(&Text::from("let a: i32 = \"abc\";"), Opts::default());
+/// prepared.add(annotation(13, 17, Formatting::ERROR, "not an i32"));
+/// let parsed = prepared.render().unwrap();
+///
+/// prepared.add(annotation(4, 4, Formatting::NOTE, "never used"));
+/// let checked = prepared.render().unwrap();
+/// assert_eq!(parsed.summaries().len(), 1);
+/// assert_eq!(checked.summaries().len(), 2);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PreparedSource {
+	/// Original text, to expand tabs again from the rendered gutter width
+	src: Text,
+	/// Display column tab stops were counted from
+	tab_origin: usize,
+	lines: Vec<Text>,
+	byte_to_char_fixup: BTreeMap<usize, isize>,
+	linestarts: BTreeSet<usize>,
+	/// Offsets of the line starts in the original text, for [`GutterNumbers::ByteOffsets`]
+	byte_linestarts: Vec<usize>,
+	/// Length of the original text in bytes
+	byte_len: usize,
+	/// Columns and widths of expanded tabs, for every line
+	tabs: Vec<BTreeMap<usize, usize>>,
+	/// Offset of the reserved EOL char of the last line
+	eof: usize,
+	annotations: Vec<Annotation>,
+	opts: Opts,
+	/// Found before rendering, i.e by the builder
+	warnings: Vec<RenderWarning>,
+	/// Numbers of the lines which are never folded, i.e changed lines of the diff
+	pinned: BTreeSet<usize>,
+}
+impl PreparedSource {
+	/// Source text may be already styled, i.e by syntax highlighter,
+	/// annotation colors are applied over it with [`Opts::apply_to_orig`]
+	pub fn new(src: &Text, opts: Opts) -> Self {
+		let tab_origin = match opts.tab_origin {
+			TabOrigin::Line | TabOrigin::Gutter => 0,
+			TabOrigin::Column(column) => column,
+		};
+		Self::with_tab_origin(src, opts, tab_origin)
+	}
+	fn with_tab_origin(src: &Text, opts: Opts, tab_origin: usize) -> Self {
+		let (txt, byte_to_char_fixup) =
+			fixup_byte_to_char(&src.data().collect::<String>(), opts.tab_width, tab_origin);
+		let styled = restyle(src, &txt, &byte_to_char_fixup);
+		let linestarts = linestarts(&txt);
+		let byte_linestarts = byte_linestarts(&src.data().collect::<String>());
+
+		let mut tabs = vec![BTreeMap::new(); linestarts.len() + 1];
+		let mut fixups = byte_to_char_fixup.iter().peekable();
+		let mut shift = 0;
+		for (byte, c) in src.data().collect::<String>().char_indices() {
+			while let Some((_, v)) = fixups.next_if(|(offset, _)| **offset < byte) {
+				shift += v;
+			}
+			if c == '\t' {
+				let pos = offset_to_linecol((byte as isize + shift) as usize, &linestarts);
+				let width = byte_to_char_fixup[&byte] + 1;
+				tabs[pos.line].insert(pos.column, width as usize);
+			}
+		}
+
+		let mut lines = Vec::new();
+		let mut line_start = 0;
+		for (i, c) in styled.data().enumerate() {
+			if *c == '\n' {
+				lines.push(styled.slice(line_start..i));
+				line_start = i + 1;
+			}
+		}
+		lines.push(styled.slice(line_start..));
+		for line in lines.iter_mut() {
+			// Reserve 1 char for the spans pointing to EOL
+			line.push(Segment::new([' '], Formatting::default()));
+			line.compact();
+		}
+		Self {
+			src: src.clone(),
+			tab_origin,
+			lines,
+			byte_to_char_fixup,
+			linestarts,
+			byte_linestarts,
+			byte_len: src.data().map(|c| c.len_utf8()).sum(),
+			tabs,
+			eof: txt.chars().count(),
+			annotations: Vec::new(),
+			opts,
+			warnings: Vec::new(),
+			pinned: BTreeSet::new(),
+		}
+	}
+	/// Add annotation, ranges are byte offsets into the original text.
+	/// Annotation is checked on render
+	pub fn add(&mut self, annotation: Annotation) {
+		self.annotations.push(annotation);
+	}
+	pub fn render(&self) -> Result<Source, AnnotationError> {
+		let source = render(self)?;
+		if self.opts.tab_origin != TabOrigin::Gutter || self.tabs.iter().all(BTreeMap::is_empty) {
+			return Ok(source);
+		}
+		// Gutter width is only known after rendering, and it doesn't depend on the tab widths,
+		// so the source is rendered again with tab stops counted from it
+		let tab_width = self.opts.tab_width;
+		let tab_origin =
+			(source.gutter_width() + tab_width - source.dedent % tab_width) % tab_width;
+		if tab_origin == self.tab_origin {
+			return Ok(source);
+		}
+		let mut prepared = Self::with_tab_origin(&self.src, self.opts.clone(), tab_origin);
+		prepared.annotations.clone_from(&self.annotations);
+		prepared.warnings.clone_from(&self.warnings);
+		prepared.pinned.clone_from(&self.pinned);
+		render(&prepared)
+	}
+}
+/// Add every annotation, same as [`PreparedSource::add`]
+impl Extend<Annotation> for PreparedSource {
+	fn extend<T: IntoIterator<Item = Annotation>>(&mut self, annotations: T) {
+		for annotation in annotations {
+			self.add(annotation);
+		}
+	}
+}
+
+/// Apply styles of the source chars to the chars they were replaced with by [`fixup_byte_to_char`]
+fn restyle(src: &Text, fixed: &str, byte_to_char_fixup: &BTreeMap<usize, isize>) -> Text {
+	let fixed = fixed.chars().collect::<Vec<_>>();
+	let mut starts = Vec::new();
+	let mut fixups = byte_to_char_fixup.iter().peekable();
+	let mut shift = 0;
+	let mut byte = 0;
+	for (c, formatting) in src.chars_with_format() {
+		while let Some((_, v)) = fixups.next_if(|(offset, _)| **offset < byte) {
+			shift += v;
+		}
+		starts.push(((byte as isize + shift) as usize, formatting));
+		byte += c.len_utf8();
+	}
+	let mut out = Text::empty();
+	for (i, (start, formatting)) in starts.iter().enumerate() {
+		let end = starts.get(i + 1).map_or(fixed.len(), |(end, _)| *end);
+		out.push(Segment::new(
+			fixed[*start..end].iter().copied(),
+			(*formatting).clone(),
+		));
+	}
+	out.compact();
+	out
+}
+
+/// Underline sample and name of every severity, i.e `── error  ── warning`, from the most severe
+fn legend(entries: &[(Severity, Formatting)], palette: Palette) -> Text {
+	let mut entries = entries.to_vec();
+	entries.sort_by_key(|(severity, _)| *severity);
+	let mut out = Text::empty();
+	for (i, (severity, formatting)) in entries.into_iter().enumerate() {
+		if i != 0 {
+			out.push_str("  ");
+		}
+		let line = palette.underline(Some(severity));
+		out.append_styled(&format!("{line}{line}"), formatting.clone());
+		out.append_styled(&format!(" {}", severity.name()), formatting);
+	}
+	out
+}
+
+/// Replace the reserved EOL char of the last line with glyph, if it is annotated
+fn mark_eof(lines: &mut [Line], glyph: char, opts: &Opts) {
+	let Some(last) = lines.last_mut().and_then(Line::as_text_mut) else {
+		return;
+	};
+	let eol = last.eol();
+	if !last.annotations.iter().any(|a| a.ranges.contains(eol)) {
+		return;
+	}
+	last.line.splice(
+		eol..=eol,
+		Some(Text::single([glyph], opts.style.gutter.clone())),
+	);
+}
+
+/// Blank lines on the edges of the snippet are removed, so text without any
+/// non-whitespace chars and annotations produces an empty source
+fn render(prepared: &PreparedSource) -> Result<Source, AnnotationError> {
+	let opts = &prepared.opts;
+	opts.validate().map_err(AnnotationError::InvalidOpts)?;
+	let eof = prepared.eof;
+	let mut warnings = prepared.warnings.clone();
+	for (i, annotation) in prepared.annotations.iter().enumerate() {
+		let Some(mut end) = annotation.ranges.ranges().last().map(|r| r.end) else {
+			continue;
+		};
+		apply_fixup(&mut end, &prepared.byte_to_char_fixup);
+		if end > eof {
+			warnings.push(RenderWarning::Clamped { annotation: i });
+		}
+	}
+	// Indexes in order of addition are kept through deduplication and collapsing, they are used as the ids
+	let mut annotations = prepared
+		.annotations
+		.iter()
+		.cloned()
+		.enumerate()
+		.collect::<Vec<_>>();
+	// Everything after compares priorities as higher wins
+	if opts.lower_priority_wins {
+		for (_, annotation) in &mut annotations {
+			annotation.priority = usize::MAX - annotation.priority;
+		}
+	}
+	// Dropped duplicates never get an id, so no connectors are allocated for them
+	if opts.deduplicate != Deduplicate::Reject {
+		annotations = deduplicate(&annotations, opts.deduplicate, &mut warnings);
+	}
+	validate(&annotations, opts)?;
 
 	// Convert byte offsets to char offsets
-	for annotation in annotations.iter_mut() {
+	for (_, annotation) in annotations.iter_mut() {
 		let ranges: RangeSet<usize> = annotation
 			.ranges
 			.ranges()
 			.map(|r| {
 				let mut start = r.start;
 				let mut end = r.end;
-				apply_fixup(&mut start, &byte_to_char_fixup);
-				apply_fixup(&mut end, &byte_to_char_fixup);
-				Range::new(start, end)
+				apply_fixup(&mut start, &prepared.byte_to_char_fixup);
+				apply_fixup_end(&mut end, &prepared.byte_to_char_fixup, eof);
+				// Ranges past the end of text are clamped to the EOL char,
+				// every other line already ends with newline, which takes the EOL char place
+				Range::new(start.min(eof), end.min(eof))
 			})
 			.collect();
 		annotation.ranges = ranges;
 	}
-	let linestarts = linestarts(&txt);
+	let linestarts = &prepared.linestarts;
+	if opts.collapse_adjacent {
+		annotations = collapse_adjacent(annotations, linestarts);
+	}
+
+	let summaries = annotations
+		.iter()
+		.map(|(id, annotation)| {
+			let start = annotation.ranges.ranges().next().expect("validated").start;
+			let LineCol { line, column } = offset_to_linecol(start, linestarts);
+			AnnotationSummary {
+				line: line + 1,
+				column: column + 1,
+				text: annotation.text.clone(),
+				key: annotation.key,
+				annotation: *id,
+			}
+		})
+		.collect();
+
+	// Chars drawn for the annotation, including its label, are linked to it
+	for (id, annotation) in &mut annotations {
+		annotation.formatting.annotation = Some(*id);
+		let linked = Formatting {
+			annotation: Some(*id),
+			..Formatting::default()
+		};
+		annotation.text.apply_meta(.., &linked);
+	}
 
-	let mut lines: Vec<Line> = txt
-		.split('\n')
-		.map(|s| s.to_string())
+	let mut lines: Vec<Line> = prepared
+		.lines
+		.iter()
 		.enumerate()
 		.map(|(num, line)| TextLine {
 			line_num: num + 1,
-			line: SegmentBuffer::new([Segment::new(
-				// Reserve 1 char for the spans pointing to EOL
-				line.chars().chain([' '].into_iter()),
-				Formatting::default(),
-			)]),
+			byte_offset: prepared.byte_linestarts[num],
+			byte_end: prepared
+				.byte_linestarts
+				.get(num + 1)
+				.copied()
+				.unwrap_or(prepared.byte_len),
+			line: line.clone(),
+			tabs: prepared.tabs[num].clone(),
 			annotation: None,
 			prefix: SegmentBuffer::new([]),
 			annotations: Vec::new(),
 			bottom_annotations: Vec::new(),
 			top_annotations: Vec::new(),
-			fold: true,
+			fold: !prepared.pinned.contains(&(num + 1)),
+			eol_used: false,
+			severity: None,
+			continuation: false,
 		})
 		.map(Line::Text)
 		.collect();
 
-	for (aid, annotation) in annotations.iter().enumerate() {
+	// Window past the end of the source is clamped to its last line
+	let window = opts.fold.window.as_ref().map(|window| {
+		let last = lines.len();
+		(*window.start()).min(last)..=(*window.end()).min(last)
+	});
+	let mut continued_above = HashSet::new();
+	let mut continued_below = HashSet::new();
+	for (aid, annotation) in &annotations {
+		let aid = *aid;
 		let mut line_ranges: BTreeMap<usize, RangeSet<usize>> = BTreeMap::new();
 		for range in annotation.ranges.ranges() {
-			let start = offset_to_linecol(range.start, &linestarts);
-			let end = offset_to_linecol(range.end, &linestarts);
+			let start = offset_to_linecol(range.start, linestarts);
+			let end = offset_to_linecol(range.end, linestarts);
 
 			if start.line == end.line {
 				let set = line_ranges.entry(start.line).or_insert_with(RangeSet::new);
@@ -615,8 +2092,10 @@ fn parse(txt: &str, annotations: &[Annotation], opts: &Opts) -> Source {
 				{
 					let set = line_ranges.entry(start.line).or_insert_with(RangeSet::new);
 					let line = lines[start.line].as_text().expect("annotation OOB");
+					// Blank line only has the reserved EOL char, range is attached to it
+					let eol = line.eol();
 					*set = set.union(
-						&[Range::new(start.column, line.len() - 1)]
+						&[Range::new(start.column.min(eol), eol)]
 							.into_iter()
 							.collect(),
 					);
@@ -627,18 +2106,34 @@ fn parse(txt: &str, annotations: &[Annotation], opts: &Opts) -> Source {
 				}
 			}
 		}
-		let left = line_ranges.len() > 1;
+		let left = annotation.connect_lines && line_ranges.len() > 1;
 		let line_ranges_len = line_ranges.len();
+		if let (true, Some(window)) = (left, &window) {
+			let first = line_ranges.keys().next().expect("not empty") + 1;
+			let last = line_ranges.keys().next_back().expect("not empty") + 1;
+			if first < *window.start() && last >= *window.start() {
+				continued_above.insert(AnnotationId(aid));
+			}
+			if last > *window.end() && first <= *window.end() {
+				continued_below.insert(AnnotationId(aid));
+			}
+		}
 
 		for (i, (line, ranges)) in line_ranges.into_iter().enumerate() {
 			let last = i == line_ranges_len - 1;
 			let line = lines[line].as_text_mut().expect("annotation OOB");
+			line.severity = [line.severity, annotation.severity]
+				.into_iter()
+				.flatten()
+				.min();
 			line.annotations.push(LineAnnotation {
 				id: AnnotationId(aid),
 				priority: annotation.priority,
 				ranges,
 				formatting: annotation.formatting.clone(),
+				underline: opts.style.palette.underline(annotation.severity),
 				left,
+				right_anchored: annotation.label_anchor == LabelAnchor::RightMargin,
 				right: if last {
 					annotation.text.clone()
 				} else {
@@ -649,33 +2144,133 @@ fn parse(txt: &str, annotations: &[Annotation], opts: &Opts) -> Source {
 		}
 	}
 
-	let mut source = Source { lines };
+	for line in lines.iter_mut().filter_map(Line::as_text_mut) {
+		let eol = line.eol();
+		line.eol_used = line.annotations.iter().any(|a| a.ranges.contains(eol));
+	}
+	if let Some(glyph) = opts.eof_marker {
+		mark_eof(&mut lines, glyph, opts);
+	}
+	if let Some(window) = &window {
+		lines.retain(|l| l.as_text().is_none_or(|t| window.contains(&t.line_num)));
+	}
+
+	let mut source = Source {
+		lines,
+		warnings,
+		summaries,
+		paddings: Vec::new(),
+		wrap_prefixes: Vec::new(),
+		dedent: 0,
+		folded: Vec::new(),
+		line_ending: opts.line_ending,
+		final_line_ending: opts.final_line_ending,
+		gutter_width: 0,
+		continued_above,
+		continued_below,
+		finalized: false,
+	};
 
 	let annotation_formats = annotations
 		.iter()
-		.enumerate()
-		.map(|(aid, a)| (AnnotationId(aid), a.formatting.clone()))
+		.map(|(aid, a)| (AnnotationId(*aid), a.formatting.clone()))
+		.collect();
+
+	let connector_columns = annotations
+		.iter()
+		.filter(|(_, a)| a.connector_column != ConnectorColumn::Auto)
+		.map(|(aid, a)| (AnnotationId(*aid), a.connector_column))
 		.collect();
 
-	process(&mut source, annotation_formats, opts);
+	process(&mut source, annotation_formats, connector_columns, opts);
+	if let Some(max_width) = opts.max_width {
+		// Frame is not counted in the max width
+		let frame = if opts.frame.is_some() { 2 } else { 0 };
+		let gutter_width = source.gutter_width.saturating_sub(frame);
+		// Two columns fit every char, including the wide ones
+		if max_width < gutter_width + 2 {
+			return Err(AnnotationError::InvalidOpts(OptsError::MaxWidthTooSmall {
+				max_width,
+				gutter_width,
+			}));
+		}
+	}
+
+	if opts.legend {
+		// Annotations hidden by the window or limits have nothing drawn for them
+		let drawn = source
+			.lines
+			.iter()
+			.filter_map(Line::text)
+			.flat_map(Text::segments)
+			.filter_map(|s| s.meta().annotation)
+			.collect::<HashSet<_>>();
+		// Formatting of the first drawn annotation of every used severity
+		let mut entries: Vec<(Severity, Formatting)> = Vec::new();
+		for (id, annotation) in &annotations {
+			let Some(severity) = annotation.severity.filter(|_| drawn.contains(id)) else {
+				continue;
+			};
+			if !entries.iter().any(|(s, _)| *s == severity) {
+				let formatting = Formatting {
+					annotation: None,
+					..annotation.formatting.clone()
+				};
+				entries.push((severity, formatting));
+			}
+		}
+		if !entries.is_empty() {
+			source.lines.push(Line::Raw(RawLine::new(legend(
+				&entries,
+				opts.style.palette,
+			))));
+		}
+	}
 
-	source
+	Ok(source)
 }
 
 pub fn source_to_ansi(source: &Source) -> String {
-	let mut out = String::new();
-	for line in &source.lines {
-		let line = line
-			.as_raw()
-			.expect("after processing all lines should turn raw");
-		let mut data = line.data.clone();
-		data.compact();
-		formatting::text_to_ansi(&data, &mut out);
-		out.push('\n');
+	source.display_ansi().to_string()
+}
+
+/// Displays rendered snippet without styles, which is the safe default for logs,
+/// use [`Source::display_ansi`] for colored output
+///
+/// ```
+/// use ass_stroke::{SnippetBuilder, Text};
+///
+/// let mut snippet = SnippetBuilder::new("let a = 1;");
+/// snippet.error(Text::from("here")).range(4..=4).build();
+/// let source = snippet.build();
+/// assert_eq!(format!("{source}"), "1 let a = 1; 🢒 here\n");
+/// assert_eq!(source.to_string(), source.plain().to_string());
+/// ```
+impl fmt::Display for Source {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.write_lines(f, false)
+	}
+}
+
+/// Rendered snippet with ANSI colors, see [`Source::display_ansi`]
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayAnsi<'s>(&'s Source);
+impl fmt::Display for DisplayAnsi<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.write_lines(f, true)
 	}
-	out
 }
 
+/// Rendered snippet without any styles, see [`Source::plain`]
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayPlain<'s>(&'s Source);
+impl fmt::Display for DisplayPlain<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.write_lines(f, false)
+	}
+}
+
+#[derive(Clone, Debug)]
 pub struct FormattingGenerator {
 	rand: SmallRng,
 }
@@ -701,20 +2296,234 @@ impl FormattingGenerator {
 	}
 }
 
+#[derive(Clone, Debug)]
 pub struct SnippetBuilder {
-	src: String,
+	src: Text,
+	/// Annotation ranges are checked against the byte length of the source
+	src_bytes: usize,
 	generator: FormattingGenerator,
 	annotations: Vec<Annotation>,
+	opts: Opts,
+	/// Errors found during annotation building, reported on build
+	errors: Vec<AnnotationError>,
+	warnings: Vec<RenderWarning>,
+	/// Plain source text and byte offsets of its line starts, for [`AnnotationBuilder::line_col`]
+	plain: OnceCell<(String, Vec<usize>)>,
 }
 impl SnippetBuilder {
 	pub fn new(src: impl AsRef<str>) -> Self {
+		Self::styled(Text::from(src.as_ref()))
+	}
+	/// Source text with its own styles, i.e syntax highlighting, which is kept outside of annotated ranges.
+	///
+	/// Annotation ranges are still byte offsets into the source string
+	pub fn styled(src: Text) -> Self {
+		let text = src.data().collect::<String>();
 		Self {
-			src: src.as_ref().to_string(),
-			generator: FormattingGenerator::new(src.as_ref().as_bytes()),
+			generator: FormattingGenerator::new(text.as_bytes()),
+			src_bytes: text.len(),
+			src,
 			annotations: Vec::new(),
+			opts: Opts::default(),
+			errors: Vec::new(),
+			warnings: Vec::new(),
+			plain: OnceCell::new(),
 		}
 	}
-	fn custom(&mut self, custom_color: Color, mut text: Text) -> AnnotationBuilder<'_> {
+	pub fn strictness(mut self, strictness: Strictness) -> Self {
+		self.opts.strictness = strictness;
+		self
+	}
+	/// Draw frame around the snippet, with title embedded into its top border
+	pub fn frame(mut self, title: Text) -> Self {
+		self.opts.frame = Some(title);
+		self
+	}
+	/// List colors and underlines of the drawn severities under the snippet, i.e `── error  ── warning`
+	pub fn legend(mut self, legend: bool) -> Self {
+		self.opts.legend = legend;
+		self
+	}
+	/// Terminate rendered lines with `\r\n` or `\n`
+	pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+		self.opts.line_ending = line_ending;
+		self
+	}
+	/// Don't terminate the last rendered line, i.e to embed the snippet into a message
+	pub fn final_line_ending(mut self, final_line_ending: bool) -> Self {
+		self.opts.final_line_ending = final_line_ending;
+		self
+	}
+	/// Display byte offsets of the line starts instead of, or together with the line numbers
+	pub fn gutter_numbers(mut self, gutter_numbers: GutterNumbers) -> Self {
+		self.opts.gutter_numbers = gutter_numbers;
+		self
+	}
+	/// Display icon after the line number of the lines with annotations of this severity,
+	/// the most severe one is displayed
+	pub fn gutter_icon(mut self, severity: Severity, icon: Text) -> Self {
+		self.opts.gutter_icons.insert(severity, icon);
+		self
+	}
+	/// Hide line numbers, leaving only a minimal gutter
+	pub fn line_numbers(mut self, line_numbers: bool) -> Self {
+		self.opts.line_numbers = line_numbers;
+		self
+	}
+	/// Separator between line numbers and source, i.e `" │ "`
+	pub fn gutter_separator(mut self, separator: impl Into<String>) -> Self {
+		self.opts.gutter_separator = Some(separator.into());
+		self
+	}
+	/// Display column numbers above the snippet
+	pub fn column_ruler(mut self, column_ruler: bool) -> Self {
+		self.opts.column_ruler = column_ruler;
+		self
+	}
+	/// Replace displayed line numbers, i.e to display lines of the original template
+	pub fn line_number_formatter(
+		mut self,
+		formatter: impl Fn(usize) -> String + Send + Sync + 'static,
+	) -> Self {
+		self.opts.line_number_formatter = Some(Arc::new(formatter));
+		self
+	}
+	/// Always display first and last lines of the source, even when they are far from annotations
+	pub fn anchor_lines(mut self, first: bool, last: bool) -> Self {
+		self.opts.fold.anchor_first_line = first;
+		self.opts.fold.anchor_last_line = last;
+		self
+	}
+	/// Indent every rendered line by the specified number of spaces
+	pub fn left_margin(mut self, margin: usize, position: MarginPosition) -> Self {
+		self.opts.left_margin = margin;
+		self.opts.margin_position = position;
+		self
+	}
+	/// Count tab stops from the rendered gutter, or the specified display column, instead of the start of the source line
+	pub fn tab_origin(mut self, tab_origin: TabOrigin) -> Self {
+		self.opts.tab_origin = tab_origin;
+		self
+	}
+	/// Display numbers of the folded lines after the gap marker, i.e `⋮ 120..287`
+	pub fn show_fold_range(mut self, show: bool) -> Self {
+		self.opts.fold.show_range = show;
+		self
+	}
+	/// Display only source lines with these numbers, connectors of the annotations crossing its edges end with arrows
+	pub fn window(mut self, lines: RangeInclusive<usize>) -> Self {
+		self.opts.fold.window = Some(lines);
+		self
+	}
+	/// Minimum number of consecutive hidden lines to be replaced with a gap
+	pub fn min_fold(mut self, min_fold: usize) -> Self {
+		self.opts.fold.min_fold = min_fold;
+		self
+	}
+	/// Make whitespace of the annotated lines visible
+	pub fn whitespace(mut self, whitespace: Whitespace) -> Self {
+		self.opts.whitespace = whitespace;
+		self
+	}
+	/// Don't display trailing space, which is reserved for the spans pointing to EOL, on the lines where no annotation covers it
+	pub fn trim_eol(mut self, trim_eol: bool) -> Self {
+		self.opts.trim_eol = trim_eol;
+		self
+	}
+	/// Display end of file as glyph, i.e `∎`, when annotation points at it
+	pub fn eof_marker(mut self, glyph: char) -> Self {
+		self.opts.eof_marker = Some(glyph);
+		self
+	}
+	/// Remove indentation shared by all the displayed lines
+	pub fn dedent(mut self, dedent: bool) -> Self {
+		self.opts.dedent = dedent;
+		self
+	}
+	/// How to treat repeated identical annotations
+	pub fn deduplicate(mut self, mode: Deduplicate) -> Self {
+		self.opts.deduplicate = mode;
+		self
+	}
+	/// Display the same label once, when it is attached to the consecutive lines
+	pub fn collapse_adjacent(mut self, collapse_adjacent: bool) -> Self {
+		self.opts.collapse_adjacent = collapse_adjacent;
+		self
+	}
+	/// Display only labels with the highest priority, replacing the rest with a summary row
+	pub fn max_labels_per_line(mut self, max_labels: usize) -> Self {
+		self.opts.max_labels_per_line = Some(max_labels);
+		self
+	}
+	/// Display only annotations with the highest priority, replacing the rest with a summary row
+	pub fn max_annotations_per_line(mut self, max_annotations: usize) -> Self {
+		self.opts.max_annotations_per_line = Some(max_annotations);
+		self
+	}
+	/// Resolve conflicts in favor of the annotation with the lowest priority
+	pub fn lower_priority_wins(mut self, lower_priority_wins: bool) -> Self {
+		self.opts.lower_priority_wins = lower_priority_wins;
+		self
+	}
+	/// Width of the rendered lines, labels with [`LabelAnchor::RightMargin`] are placed against it
+	pub fn max_width(mut self, max_width: usize) -> Self {
+		self.opts.max_width = Some(max_width);
+		self
+	}
+	/// Wrap source lines at [`SnippetBuilder::max_width`], marking the continuation rows with `↳` in the gutter
+	pub fn wrap(mut self, wrap: bool) -> Self {
+		self.opts.wrap = wrap;
+		self
+	}
+	/// Style of line numbers and other gutter markers
+	pub fn gutter_formatting(mut self, formatting: Formatting) -> Self {
+		self.opts.style.gutter = formatting;
+		self
+	}
+	/// Order of label rows for the annotations containing other annotations
+	pub fn label_stacking(mut self, stacking: LabelStacking) -> Self {
+		self.opts.label_stacking = stacking;
+		self
+	}
+	/// Style source lines, i.e with syntax highlighter, called with line number and text of the line with expanded tabs,
+	/// returned ranges are char offsets into that text
+	pub fn highlight(
+		mut self,
+		highlight: impl Fn(usize, &str) -> Vec<(std::ops::Range<usize>, Formatting)>
+			+ Send
+			+ Sync
+			+ 'static,
+	) -> Self {
+		self.opts.highlight = Some(Arc::new(highlight));
+		self
+	}
+	/// Point at the range starts with arrow heads, i.e `▼─╮`
+	pub fn range_pointers(mut self, range_pointers: bool) -> Self {
+		self.opts.range_pointers = range_pointers;
+		self
+	}
+	/// Colors of severities, only affect annotations added after this call.
+	/// Underline chars of the severities are picked when rendering, so they are changed for all annotations
+	pub fn palette(mut self, palette: Palette) -> Self {
+		self.opts.style.palette = palette;
+		self
+	}
+	/// Mark source line with the styled prefix, i.e `+`/`-` of the diff, displayed before the gutter
+	pub fn line_prefix(mut self, line: usize, prefix: Text) -> Self {
+		self.opts.line_prefixes.insert(line, prefix);
+		self
+	}
+	/// Replace the default rendering stages, i.e to add custom gutter markers
+	pub fn pipeline(mut self, pipeline: Pipeline) -> Self {
+		self.opts.pipeline = Some(Arc::new(pipeline));
+		self
+	}
+	fn custom(
+		&mut self,
+		severity: Severity,
+		custom_color: Color,
+		mut text: Text,
+	) -> AnnotationBuilder<'_> {
 		let mut color = self.generator.next();
 		color.hue(custom_color);
 		let formatting = Formatting::rgb(color.to_rgb_array());
@@ -725,54 +2534,90 @@ impl SnippetBuilder {
 		);
 		AnnotationBuilder {
 			snippet: self,
+			severity,
 			priority: 0,
 			formatting,
 			ranges: Vec::new(),
 			text,
+			connect_lines: true,
+			key: None,
+			connector_column: ConnectorColumn::Auto,
+			label_anchor: LabelAnchor::Range,
 		}
 	}
 	pub fn error(&mut self, text: Text) -> AnnotationBuilder<'_> {
-		self.custom(Color::Red, text)
+		match self.opts.style.palette {
+			Palette::Default => self.custom(Severity::Error, Color::Red, text),
+			Palette::ColorBlind => {
+				let mut builder = self.custom(Severity::Error, Color::Orange, text);
+				// Hue alone is not enough to distinguish errors from warnings
+				builder.formatting.bold = true;
+				builder
+			}
+		}
 	}
 	pub fn warning(&mut self, text: Text) -> AnnotationBuilder<'_> {
-		self.custom(Color::Orange, text)
+		let color = match self.opts.style.palette {
+			Palette::Default => Color::Orange,
+			Palette::ColorBlind => Color::Yellow,
+		};
+		self.custom(Severity::Warning, color, text)
 	}
 	pub fn note(&mut self, text: Text) -> AnnotationBuilder<'_> {
-		self.custom(Color::Green, text)
+		let color = match self.opts.style.palette {
+			Palette::Default => Color::Green,
+			Palette::ColorBlind => Color::Purple,
+		};
+		self.custom(Severity::Note, color, text)
 	}
 	pub fn info(&mut self, text: Text) -> AnnotationBuilder<'_> {
-		self.custom(Color::Blue, text)
+		self.custom(Severity::Info, Color::Blue, text)
 	}
+	/// # Panics
+	/// If any of the annotations is invalid, see [`SnippetBuilder::try_build`]
 	pub fn build(self) -> Source {
-		parse(
-			&self.src,
-			&self.annotations,
-			&Opts {
-				apply_to_orig: true,
-				fold: true,
-				tab_width: 4,
-				context_lines: 2,
-			},
-		)
+		self.try_build().expect("invalid annotation")
+	}
+	pub fn try_build(self) -> Result<Source, AnnotationError> {
+		self.prepare()?.render()
+	}
+	/// Split the source text once, to render it with different sets of annotations
+	pub fn prepare(self) -> Result<PreparedSource, AnnotationError> {
+		if let Some(error) = self.errors.into_iter().next() {
+			return Err(error);
+		}
+		let mut prepared = PreparedSource::new(&self.src, self.opts);
+		prepared.warnings = self.warnings;
+		for annotation in self.annotations {
+			prepared.add(annotation);
+		}
+		Ok(prepared)
 	}
 }
 
 #[must_use]
+#[derive(Debug)]
 pub struct AnnotationBuilder<'s> {
 	snippet: &'s mut SnippetBuilder,
+	severity: Severity,
 	priority: usize,
 	formatting: Formatting,
-	ranges: Vec<Range<usize>>,
+	ranges: Vec<RangeInclusive<usize>>,
 	text: Text,
+	connect_lines: bool,
+	key: Option<u64>,
+	connector_column: ConnectorColumn,
+	label_anchor: LabelAnchor,
 }
 
 impl<'s> AnnotationBuilder<'s> {
 	pub fn range(mut self, range: RangeInclusive<usize>) -> Self {
+		// End of source is allowed, as the last line has a reserved EOL char
 		assert!(
-			*range.end() < self.snippet.src.len(),
+			*range.start().max(range.end()) <= self.snippet.src_bytes,
 			"out of bounds annotation"
 		);
-		self.ranges.push(Range::new(*range.start(), *range.end()));
+		self.ranges.push(range);
 		self
 	}
 	pub fn ranges(mut self, ranges: impl IntoIterator<Item = RangeInclusive<usize>>) -> Self {
@@ -781,12 +2626,109 @@ impl<'s> AnnotationBuilder<'s> {
 		}
 		self
 	}
+	/// Range between positions, both inclusive, for the front-ends which have no byte offsets
+	///
+	/// ```
+	/// use ass_stroke::{LineCol, SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("fn main() {\n\tlet ñ = 1;\n}");
+	/// snippet
+	///     .error(Text::from("unused"))
+	///     .line_col(LineCol { line: 1, column: 5 }, LineCol { line: 1, column: 5 })
+	///     .build();
+	/// assert_eq!(snippet.build().summaries()[0].column, 9);
+	/// ```
+	///
+	/// Positions past the end of their line, or of the source, are reported as [`AnnotationError::PositionOutOfBounds`]
+	pub fn line_col(self, start: LineCol, end: LineCol) -> Self {
+		let (src, linestarts) = self.snippet.plain.get_or_init(|| {
+			let src = self.snippet.src.data().collect::<String>();
+			let linestarts = byte_linestarts(&src);
+			(src, linestarts)
+		});
+		let offset = |position| {
+			linecol_to_offset(src, linestarts, position).ok_or(
+				AnnotationError::PositionOutOfBounds {
+					annotation: self.snippet.annotations.len(),
+					position,
+				},
+			)
+		};
+		match offset(start).and_then(|start| Ok(start..=offset(end)?)) {
+			Ok(range) => self.range(range),
+			Err(e) => {
+				self.snippet.errors.push(e);
+				self
+			}
+		}
+	}
+	/// Higher priority annotations are drawn over the others, and their labels are kept by [`Opts::max_labels_per_line`],
+	/// unless [`Opts::lower_priority_wins`] is set
+	pub fn priority(mut self, priority: usize) -> Self {
+		self.priority = priority;
+		self
+	}
+	/// Visually quieter annotation, its ranges, connectors and label are displayed faint, and never bold
+	pub fn secondary(mut self) -> Self {
+		self.formatting.bold = false;
+		self.formatting = self.formatting.dim();
+		self.text
+			.apply_meta(0..self.text.len(), &Formatting::default().dim());
+		self
+	}
+	/// Don't draw connector for this annotation, even if it spans multiple lines
+	pub fn connect_lines(mut self, connect_lines: bool) -> Self {
+		self.connect_lines = connect_lines;
+		self
+	}
+	/// Stable identifier of the annotation, see [`Annotation::key`]
+	pub fn key(mut self, key: u64) -> Self {
+		self.key = Some(key);
+		self
+	}
+	/// Place connector of this annotation inside or outside of all the overlapping ones, regardless of its length
+	pub fn connector_column(mut self, column: ConnectorColumn) -> Self {
+		self.connector_column = column;
+		self
+	}
+	/// Place label flush against [`Opts::max_width`], see [`SnippetBuilder::max_width`]
+	pub fn label_anchor(mut self, anchor: LabelAnchor) -> Self {
+		self.label_anchor = anchor;
+		self
+	}
 	pub fn build(self) {
+		let annotation = self.snippet.annotations.len();
+		let mut ranges = RangeSet::new();
+		for range in self.ranges {
+			let (mut start, mut end) = range.into_inner();
+			if start > end {
+				if self.snippet.opts.strictness == Strictness::Strict {
+					self.snippet.errors.push(AnnotationError::ReversedRange {
+						annotation,
+						start,
+						end,
+					});
+				} else {
+					self.snippet.warnings.push(RenderWarning::ReversedRange {
+						annotation,
+						start,
+						end,
+					});
+				}
+				std::mem::swap(&mut start, &mut end);
+			}
+			ranges = ranges.union(&[Range::new(start, end)].into_iter().collect());
+		}
 		self.snippet.annotations.push(Annotation {
 			priority: self.priority,
 			formatting: self.formatting,
-			ranges: self.ranges.into_iter().collect(),
+			ranges,
 			text: self.text,
+			connect_lines: self.connect_lines,
+			key: self.key,
+			severity: Some(self.severity),
+			connector_column: self.connector_column,
+			label_anchor: self.label_anchor,
 		});
 	}
 }
@@ -799,49 +2741,88 @@ mod tests {
 		Default::default()
 	}
 
-	#[test]
-	fn readme() {
-		let mut snippet = SnippetBuilder::new(include_str!("../../../fixtures/std.jsonnet"));
-		snippet
-			.error(Text::single("Local defs".chars(), default()))
-			.ranges([4..=8, 3142..=3146])
-			.build();
+	fn try_parse(
+		txt: &str,
+		annotations: &[Annotation],
+		opts: &Opts,
+	) -> Result<Source, AnnotationError> {
+		let mut prepared = PreparedSource::new(&Text::from(txt), opts.clone());
+		for annotation in annotations {
+			prepared.add(annotation.clone());
+		}
+		prepared.render()
+	}
+
+	fn parse(txt: &str, annotations: &[Annotation], opts: &Opts) -> Source {
+		try_parse(txt, annotations, opts).expect("invalid annotation")
+	}
+
+	/// Rendered source without any formatting
+	fn plain(source: &Source) -> String {
+		source.plain().to_string()
+	}
+
+	/// Color of every char in the rendered line
+	fn colors(source: &Source, line: usize) -> Vec<Option<u32>> {
+		let data = source.lines[line].rendered();
+		(0..data.len())
+			.map(|i| data.get(i).expect("in bounds").1.color)
+			.collect()
+	}
+
+	fn annotation(range: (usize, usize), text: &str) -> Annotation {
+		Annotation::new(
+			[Range::new(range.0, range.1)].into_iter().collect(),
+			text.into(),
+			Formatting::color(0xff000000),
+		)
+	}
+
+	fn opts(apply_to_orig: bool) -> Opts {
+		Opts {
+			apply_to_orig,
+			..Opts::default()
+		}
+	}
+
+	#[test]
+	fn readme() {
+		let mut snippet = SnippetBuilder::new(include_str!("../../../fixtures/std.jsonnet"));
 		snippet
-			.warning(Text::single("Local name".chars(), default()))
-			.range(10..=12)
+			.error(Text::from("Local defs"))
+			.ranges([4..=8, 3142..=3146])
 			.build();
 		snippet
-			.info(Text::single("Equals".chars(), default()))
-			.range(14..=14)
+			.warning(Text::from("Local name"))
+			.range(10..=12)
 			.build();
+		snippet.info(Text::from("Equals")).range(14..=14).build();
 		snippet
-			.note(Text::single("Connected definition".chars(), default()))
+			.note(Text::from("Connected definition"))
 			.ranges([3133..=3135, 6155..=6157])
 			.build();
 		snippet
-			.note(Text::single(
-				"Another connected definition".chars(),
-				default(),
-			))
+			.note(Text::from("Another connected definition"))
 			.ranges([5909..=5913, 6062..=6066, 6242..=6244])
 			.build();
 		let s = snippet.build();
-		println!("{}", source_to_ansi(&s))
+		println!("{}", source_to_ansi(&s));
+		assert_eq!(s.rendered_width(), 134);
 	}
 
 	#[test]
 	fn test_fmt() {
 		let mut snippet = SnippetBuilder::new(include_str!("../../../fixtures/std.jsonnet"));
 		snippet
-			.info(Text::single("Hello world".chars(), default()))
+			.info(Text::from("Hello world"))
 			.range(2832..=3135)
 			.build();
 		snippet
-			.warning(Text::single("Conflict".chars(), default()))
+			.warning(Text::from("Conflict"))
 			.range(2838..=2847)
 			.build();
 		snippet
-			.error(Text::single("Still has text".chars(), default()))
+			.error(Text::from("Still has text"))
 			.range(2839..=2846)
 			.build();
 		let s = snippet.build();
@@ -851,18 +2832,9 @@ mod tests {
 	#[test]
 	fn fullwidth_marker() {
 		let mut snippet = SnippetBuilder::new("ＡＢＣ");
-		snippet
-			.info(Text::single("a".chars(), default()))
-			.range(0..=2)
-			.build();
-		snippet
-			.info(Text::single("b".chars(), default()))
-			.range(3..=5)
-			.build();
-		snippet
-			.info(Text::single("c".chars(), default()))
-			.range(6..=8)
-			.build();
+		snippet.info(Text::from("a")).range(0..=2).build();
+		snippet.info(Text::from("b")).range(3..=5).build();
+		snippet.info(Text::from("c")).range(6..=8).build();
 		let s = snippet.build();
 		println!("{}", source_to_ansi(&s))
 	}
@@ -872,31 +2844,23 @@ mod tests {
 		let s = parse(
 			"ＡＢＣ",
 			&[
-				Annotation {
-					priority: 0,
-					formatting: Formatting::color(0xff000000),
-					ranges: [Range::new(0, 2)].into_iter().collect(),
-					text: Text::single("a".chars(), default()),
-				},
-				Annotation {
-					priority: 0,
-					formatting: Formatting::color(0x00ff0000),
-					ranges: [Range::new(3, 5)].into_iter().collect(),
-					text: Text::single("b".chars(), default()),
-				},
-				Annotation {
-					priority: 0,
-					formatting: Formatting::color(0x0000ff00),
-					ranges: [Range::new(6, 8)].into_iter().collect(),
-					text: Text::single("c".chars(), default()),
-				},
+				Annotation::new(
+					[Range::new(0, 2)].into_iter().collect(),
+					Text::from("a"),
+					Formatting::color(0xff000000),
+				),
+				Annotation::new(
+					[Range::new(3, 5)].into_iter().collect(),
+					Text::from("b"),
+					Formatting::color(0x00ff0000),
+				),
+				Annotation::new(
+					[Range::new(6, 8)].into_iter().collect(),
+					Text::from("c"),
+					Formatting::color(0x0000ff00),
+				),
 			],
-			&Opts {
-				apply_to_orig: true,
-				fold: true,
-				tab_width: 4,
-				context_lines: 2,
-			},
+			&opts(true),
 		);
 		println!("{}", source_to_ansi(&s))
 	}
@@ -906,24 +2870,25 @@ mod tests {
 		let s = parse(
 			"Ａ\tＢ\n\tＢ\na\tb\n\tb",
 			&[
-				Annotation {
-					priority: 0,
-					formatting: Formatting::color(0xff000000),
-					ranges: [Range::new(17, 17)].into_iter().collect(),
-					text: Text::single("Line start".chars(), default()),
-				},
-				Annotation {
-					priority: 0,
-					formatting: Formatting::color(0x00ff0000),
-					ranges: [Range::new(18, 18)].into_iter().collect(),
-					text: Text::single("Aligned".chars(), default()),
-				},
+				Annotation::new(
+					[Range::new(17, 17)].into_iter().collect(),
+					Text::from("Line start"),
+					Formatting::color(0xff000000),
+				),
+				Annotation::new(
+					[Range::new(18, 18)].into_iter().collect(),
+					Text::from("Aligned"),
+					Formatting::color(0x00ff0000),
+				),
 			],
 			&Opts {
 				apply_to_orig: false,
-				fold: false,
-				tab_width: 4,
-				context_lines: 2,
+				inline_labels: false,
+				fold: FoldOpts {
+					enabled: false,
+					..FoldOpts::default()
+				},
+				..Opts::default()
 			},
 		);
 		println!("{}", source_to_ansi(&s))
@@ -954,20 +2919,3465 @@ mod tests {
 	}"#;
 		let mut snippet = SnippetBuilder::new(src);
 		snippet
-			.error(Text::single(
-				"expected `Option<String>` because of return type".chars(),
-				default(),
-			))
+			.error(
+				Text::build()
+					.str("expected ")
+					.styled("`Option<String>`", Formatting::color(0xfb493400))
+					.str(" because of return type")
+					.finish(),
+			)
 			.range(5..=18)
 			.build();
 		snippet
-			.note(Text::single(
-				"expected enum `std::option::Option`".chars(),
-				default(),
-			))
+			.note(Text::from("expected enum `std::option::Option`"))
 			.range(22..=508)
 			.build();
 		let s = snippet.build();
 		println!("{}", source_to_ansi(&s))
 	}
+
+	#[test]
+	fn blank_line_start() {
+		let s = parse("\nabc\ndef", &[annotation((0, 0), "here")], &opts(false));
+		assert_eq!(plain(&s), "· ╭── here\n1  \n2 abc \n3 def \n");
+	}
+
+	#[test]
+	fn blank_line_middle() {
+		let s = parse("abc\n\ndef", &[annotation((4, 4), "here")], &opts(false));
+		assert_eq!(plain(&s), "1 abc \n· ╭── here\n2  \n3 def \n");
+		let s = parse("abc\n\ndef", &[annotation((4, 4), "here")], &opts(true));
+		assert_eq!(plain(&s), "1 abc \n2  🢒 here\n3 def \n");
+	}
+
+	#[test]
+	fn blank_line_end() {
+		let s = parse("abc\ndef\n", &[annotation((8, 8), "here")], &opts(false));
+		assert_eq!(plain(&s), "1 abc \n2 def \n· ╭── here\n3  \n");
+	}
+
+	#[test]
+	fn blank_line_multiline_start() {
+		let s = parse("abc\n\ndef", &[annotation((4, 6), "here")], &opts(true));
+		assert_eq!(plain(&s), "1   abc \n2 ╭──\n3 ╰─def 🢒 here\n");
+	}
+
+	#[test]
+	fn blank_lines_trimmed() {
+		let s = parse("\n\nabc\n\n", &[annotation((2, 2), "here")], &opts(true));
+		assert_eq!(plain(&s), "3 abc 🢒 here\n");
+	}
+
+	#[test]
+	fn orig_is_not_restyled() {
+		let opts = Opts {
+			apply_to_orig: false,
+			..opts(false)
+		};
+		let s = parse("abc def", &[annotation((4, 6), "here")], &opts);
+		assert_eq!(plain(&s), "·     ╭──── here\n·     ├─╮\n1 abc def \n");
+		assert_eq!(colors(&s, 2)[6..9], [None; 3]);
+	}
+
+	#[test]
+	fn apply_to_orig_restyles_source() {
+		let opts = Opts {
+			inline_labels: false,
+			..opts(true)
+		};
+		let s = parse("abc def", &[annotation((4, 6), "here")], &opts);
+		// Layout is the same, only source chars are colored
+		assert_eq!(plain(&s), "·     ╭──── here\n·     ├─╮\n1 abc def \n");
+		assert_eq!(colors(&s, 2)[2..6], [None; 4]);
+		assert_eq!(colors(&s, 2)[6..9], [Some(0xff000000); 3]);
+	}
+
+	#[test]
+	fn inline_labels() {
+		let s = parse("abc def", &[annotation((4, 6), "here")], &opts(true));
+		assert_eq!(plain(&s), "1 abc def 🢒 here\n");
+		assert_eq!(colors(&s, 0)[6..9], [Some(0xff000000); 3]);
+	}
+
+	#[test]
+	fn empty_source() {
+		let s = parse("", &[], &opts(true));
+		assert_eq!(source_to_ansi(&s), "");
+		let s = parse("\n\n\n", &[], &opts(true));
+		assert_eq!(source_to_ansi(&s), "");
+		let s = parse(
+			"\n\n\n",
+			&[],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(true).fold
+				},
+				..opts(true)
+			},
+		);
+		assert_eq!(source_to_ansi(&s), "");
+	}
+
+	#[test]
+	fn empty_source_annotated() {
+		let s = parse("", &[annotation((0, 0), "here")], &opts(true));
+		assert_eq!(plain(&s), "1  🢒 here\n");
+		let s = parse("", &[annotation((0, 0), "here")], &opts(false));
+		assert_eq!(plain(&s), "· ╭── here\n1  \n");
+
+		let mut snippet = SnippetBuilder::new("");
+		snippet.error(Text::from("here")).range(0..=0).build();
+		assert_eq!(plain(&snippet.build()), "1  🢒 here\n");
+	}
+
+	#[test]
+	fn only_newlines_annotated() {
+		let s = parse("\n\n\n", &[annotation((1, 1), "here")], &opts(true));
+		assert_eq!(plain(&s), "2  🢒 here\n");
+	}
+
+	#[test]
+	fn range_ending_at_eol() {
+		let red = Some(0xff000000);
+		let s = parse("abc\ndef", &[annotation((1, 3), "here")], &opts(true));
+		assert_eq!(plain(&s), "1 abc 🢒 here\n2 def \n");
+		assert_eq!(colors(&s, 0)[3..6], [red; 3]);
+
+		let s = parse("abc\ndef", &[annotation((1, 7), "here")], &opts(true));
+		assert_eq!(plain(&s), "1 ╭─abc \n2 ╰─def 🢒 here\n");
+		assert_eq!(colors(&s, 0)[5..8], [red; 3]);
+		assert_eq!(colors(&s, 1)[4..8], [red; 4]);
+	}
+
+	#[test]
+	fn range_past_eol_is_clamped() {
+		let clamped = parse("abc\ndef", &[annotation((5, 100), "here")], &opts(true));
+		let exact = parse("abc\ndef", &[annotation((5, 7), "here")], &opts(true));
+		assert_eq!(source_to_ansi(&clamped), source_to_ansi(&exact));
+
+		let s = parse("abc\ndef", &[annotation((1, 100), "here")], &opts(true));
+		assert_eq!(plain(&s), "1 ╭─abc \n2 ╰─def 🢒 here\n");
+	}
+
+	#[test]
+	fn reversed_range_lenient() {
+		let build = |range| {
+			let mut snippet = SnippetBuilder::new("abc def");
+			snippet.error(Text::from("here")).range(range).build();
+			source_to_ansi(&snippet.build())
+		};
+		assert_eq!(build(RangeInclusive::new(6, 4)), build(4..=6));
+	}
+
+	#[test]
+	fn reversed_range_strict() {
+		let mut snippet = SnippetBuilder::new("abc def").strictness(Strictness::Strict);
+		snippet
+			.error(Text::from("here"))
+			.range(RangeInclusive::new(6, 4))
+			.build();
+		assert_eq!(
+			snippet.try_build().err(),
+			Some(AnnotationError::ReversedRange {
+				annotation: 0,
+				start: 6,
+				end: 4
+			})
+		);
+	}
+
+	#[test]
+	fn empty_ranges() {
+		let mut snippet = SnippetBuilder::new("abc def");
+		snippet.error(Text::from("here")).build();
+		assert_eq!(
+			snippet.try_build().err(),
+			Some(AnnotationError::EmptyRanges { annotation: 0 })
+		);
+	}
+
+	#[test]
+	fn duplicate_annotation() {
+		let result = try_parse(
+			"abc def",
+			&[annotation((4, 6), "here"), annotation((4, 6), "here")],
+			&opts(true),
+		);
+		assert_eq!(
+			result.err(),
+			Some(AnnotationError::Duplicate {
+				annotation: 1,
+				original: 0
+			})
+		);
+	}
+
+	#[test]
+	fn window_clips_connections() {
+		let render = |annotations: &[Annotation], window| {
+			plain(&parse(
+				"a\nb\nc\nd\ne\nf",
+				annotations,
+				&Opts {
+					fold: FoldOpts {
+						enabled: false,
+						window: Some(window),
+						..opts(true).fold
+					},
+					..opts(true)
+				},
+			))
+		};
+		assert_eq!(
+			render(&[annotation((0, 8), "span")], 2..=4),
+			"2 ▲ b \n3 │ c \n4 ▼ d \n"
+		);
+		assert_eq!(
+			render(&[annotation((0, 6), "span")], 2..=5),
+			"2 ▲ b \n3 │ c \n4 ╰─d 🢒 span\n5   e \n"
+		);
+		assert_eq!(
+			render(&[annotation((4, 10), "span")], 1..=4),
+			"1   a \n2   b \n3 ╭─c \n4 ▼ d \n"
+		);
+		assert_eq!(render(&[annotation((0, 8), "span")], 3..=3), "3 ↕ c \n");
+		assert_eq!(
+			render(&[annotation((4, 10), "span")], 8..=10),
+			"6 ▲─f 🢒 span\n"
+		);
+	}
+
+	#[test]
+	fn pinned_connector_column() {
+		let render = |column| {
+			let outer = Annotation {
+				connector_column: column,
+				..annotation((0, 6), "outer")
+			};
+			plain(&parse(
+				"a\nb\nc\nd",
+				&[outer, annotation((2, 4), "inner")],
+				&opts(true),
+			))
+		};
+		assert_eq!(
+			render(ConnectorColumn::Auto),
+			"1 ╭───a \n2 │ ╭─b \n3 │ ╰─c 🢒 inner\n4 ╰───d 🢒 outer\n"
+		);
+		assert_eq!(
+			render(ConnectorColumn::Innermost),
+			"1   ╭─a \n2 ╭─┼─b \n3 ╰─┼─c 🢒 inner\n4   ╰─d 🢒 outer\n"
+		);
+		// Outermost annotation is already placed outside
+		assert_eq!(
+			render(ConnectorColumn::Outermost),
+			render(ConnectorColumn::Auto)
+		);
+	}
+
+	#[test]
+	fn disjoint_connections_share_column() {
+		let s = parse(
+			"aa\nbb\ncc\ndd\nee\nff",
+			&[annotation((0, 3), "one"), annotation((9, 12), "two")],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(true).fold
+				},
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			"1 ╭─aa \n2 ╰─bb 🢒 one\n3   cc \n4 ╭─dd \n5 ╰─ee 🢒 two\n6   ff \n"
+		);
+	}
+
+	#[test]
+	fn connection_layout_is_stable() {
+		let render = || {
+			let annotations = [(8, 18), (0, 8), (2, 6), (6, 16), (6, 18)]
+				.into_iter()
+				.enumerate()
+				.map(|(i, range)| annotation(range, &format!("L{i}")))
+				.collect::<Vec<_>>();
+			let s = parse(
+				"a\nb\nc\nd\ne\nf\ng\nh\ni\nj",
+				&annotations,
+				&Opts {
+					fold: FoldOpts {
+						enabled: false,
+						..opts(true).fold
+					},
+					..opts(true)
+				},
+			);
+			plain(&s)
+		};
+		let first = render();
+		for _ in 0..16 {
+			assert_eq!(render(), first);
+		}
+	}
+
+	#[test]
+	fn column_zero_inside_connection() {
+		let s = parse(
+			"aa\nbbb\ncc",
+			&[annotation((0, 7), "multi"), annotation((3, 4), "first")],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(false).fold
+				},
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"· ╭─╮  ",
+				"· │ ├─╮",
+				"1 │ aa ",
+				"· │ ╭─── first",
+				"· │ ├╮",
+				"2 │ bbb ",
+				"· ╰─┬── multi",
+				"3   cc ",
+				"",
+			]
+			.join("\n")
+		);
+
+		let s = parse(
+			"aa\nbbb\ncc",
+			&[
+				annotation((0, 7), "multi"),
+				annotation((3, 4), "first"),
+				annotation((4, 5), "second"),
+			],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(true).fold
+				},
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 ╭─aa ",
+				"· │ ╭─── first",
+				"· │ ├╮",
+				"2 │ bbb 🢒 second",
+				"3 ╰─cc 🢒 multi",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn rendered_width() {
+		let s = parse("abc def", &[annotation((4, 6), "here")], &opts(true));
+		assert_eq!(s.rendered_width(), "1 abc def 🢒 here".chars().count());
+
+		let s = parse("ＡＢＣ\nabc", &[annotation((3, 5), "b")], &opts(true));
+		// Fullwidth chars take two cells each
+		assert_eq!(s.rendered_width(), "1 ".len() + 6 + " 🢒 b".chars().count());
+
+		assert_eq!(parse("", &[], &opts(true)).rendered_width(), 0);
+	}
+
+	#[test]
+	fn multiline_label() {
+		let mut label = annotation((4, 6), "");
+		label.text = Text::from_runs([
+			("one\n", default()),
+			("two", Formatting::color(0x00ff0000)),
+			("\nthree", default()),
+		]);
+		let s = parse(
+			"abc def\nghi",
+			&[label.clone()],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(true).fold
+				},
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"·     ╭──── one",
+				"·     │     two",
+				"·     │     three",
+				"1 abc def ",
+				"2 ghi ",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(colors(&s, 1)[12..15], [Some(0x00ff0000); 3]);
+
+		label.ranges = [Range::new(4, 9)].into_iter().collect();
+		let s = parse(
+			"abc def\nghi\njkl",
+			&[label],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(true).fold
+				},
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 ╭─abc def ",
+				"· ╰─┬─── one",
+				"·   │    two",
+				"·   │    three",
+				"2   ghi ",
+				"3   jkl ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn frame() {
+		let mut snippet = SnippetBuilder::new("abc def").frame(Text::from("file.rs"));
+		snippet.error(Text::from("here")).range(4..=6).build();
+		assert_eq!(
+			plain(&snippet.build()),
+			[
+				"╭─ file.rs ────────╮",
+				"│ 1 abc def 🢒 here │",
+				"╰──────────────────╯",
+				"",
+			]
+			.join("\n")
+		);
+
+		let s = parse(
+			"abc\nlong line",
+			&[annotation((0, 0), "a")],
+			&Opts {
+				frame: Some(Text::empty()),
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"╭──────────────╮",
+				"│ 1 abc 🢒 a    │",
+				"│ 2 long line  │",
+				"╰──────────────╯",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn annotation_without_text() {
+		let s = parse(
+			"abc def",
+			&[annotation((4, 6), "")],
+			&Opts {
+				fold: FoldOpts {
+					enabled: false,
+					..opts(false).fold
+				},
+				..opts(false)
+			},
+		);
+		assert_eq!(plain(&s), "·     ├─╮\n1 abc def \n");
+
+		let s = parse(
+			"abc def",
+			&[annotation((4, 6), ""), annotation((0, 1), "x")],
+			&opts(true),
+		);
+		assert_eq!(plain(&s), "1 abc def 🢒 x\n");
+		assert_eq!(colors(&s, 0)[6..9], [Some(0xff000000); 3]);
+	}
+
+	#[test]
+	fn line_numbers_off() {
+		let s = parse(
+			"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nfoo bar",
+			&[annotation((0, 0), "x"), annotation((30, 32), "y")],
+			&Opts {
+				inline_labels: false,
+				line_numbers: false,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"  ╭── x",
+				"  a ",
+				"  b ",
+				"  c ",
+				"⋮ ",
+				"  j ",
+				"  k ",
+				"         ╭── y",
+				"  foo bar ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn gutter_separator() {
+		let s = parse(
+			"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nfoo bar",
+			&[annotation((0, 0), "x"), annotation((30, 32), "y")],
+			&Opts {
+				inline_labels: false,
+				gutter_separator: Some(" │ ".to_owned()),
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"   │ ╭── x",
+				" 1 │ a ",
+				" 2 │ b ",
+				" 3 │ c ",
+				" ⋮ │ ",
+				"10 │ j ",
+				"11 │ k ",
+				"   │        ╭── y",
+				"12 │ foo bar ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn column_ruler() {
+		let s = parse(
+			"0123456789abc\ndef",
+			&[annotation((2, 15), "multi")],
+			&Opts {
+				column_ruler: true,
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"             1    ",
+				"    12345678901234",
+				"1 ╭─0123456789abc ",
+				"2 ╰─def 🢒 multi",
+				"",
+			]
+			.join("\n")
+		);
+		let plain = plain(&s);
+		let lines = plain
+			.lines()
+			.map(|l| l.chars().collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		let tick = lines[0].iter().position(|c| *c == '1').expect("has tick");
+		assert_eq!(lines[1][tick], '0');
+		assert_eq!(lines[2][tick], '9', "10th column");
+	}
+
+	#[test]
+	fn disjoint_ranges_on_one_line() {
+		let shared = Annotation {
+			ranges: [Range::new(0, 2), Range::new(8, 10)].into_iter().collect(),
+			..annotation((0, 0), "tok")
+		};
+		let s = parse(
+			"foo bar foo baz",
+			std::slice::from_ref(&shared),
+			&Opts {
+				inline_labels: false,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"· ╭───────┬──── tok",
+				"· ├─╮     ├─╮",
+				"1 foo bar foo baz ",
+				"",
+			]
+			.join("\n")
+		);
+
+		let s = parse("foo bar foo baz", &[shared], &opts(true));
+		assert_eq!(plain(&s), "1 foo bar foo baz 🢒 tok\n");
+		let colors = colors(&s, 0);
+		assert_eq!(colors[2..5], [Some(0xff000000); 3]);
+		assert_eq!(colors[5..10], [None; 5]);
+		assert_eq!(colors[10..13], [Some(0xff000000); 3]);
+	}
+
+	#[test]
+	fn line_number_formatter() {
+		let s = parse(
+			"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nfoo bar",
+			&[annotation((0, 0), "x"), annotation((30, 32), "y")],
+			&Opts {
+				inline_labels: false,
+				line_number_formatter: Some(Arc::new(|n| format!("0x{n:x}"))),
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"  · ╭── x",
+				"0x1 a ",
+				"0x2 b ",
+				"0x3 c ",
+				"  ⋮ ",
+				"0xa j ",
+				"0xb k ",
+				"  ·        ╭── y",
+				"0xc foo bar ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn anchor_lines() {
+		let src = (1..=12)
+			.map(|i| format!("l{i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let anchored = Opts {
+			fold: FoldOpts {
+				anchor_first_line: true,
+				anchor_last_line: true,
+				..opts(true).fold
+			},
+			..opts(true)
+		};
+
+		let s = parse(&src, &[annotation((19, 19), "x")], &anchored);
+		assert_eq!(
+			plain(&s),
+			[
+				" 1 l1 ",
+				" ⋮ ",
+				" 5 l5 ",
+				" 6 l6 ",
+				" 7 l7 🢒 x",
+				" 8 l8 ",
+				" 9 l9 ",
+				" ⋮ ",
+				"12 l12 ",
+				"",
+			]
+			.join("\n")
+		);
+
+		// First line is already displayed as the context
+		let s = parse(&src, &[annotation((3, 3), "x")], &anchored);
+		assert_eq!(
+			plain(&s),
+			[
+				" 1 l1 ",
+				" 2 l2 🢒 x",
+				" 3 l3 ",
+				" 4 l4 ",
+				" ⋮ ",
+				"12 l12 ",
+				"",
+			]
+			.join("\n")
+		);
+
+		let s = parse(&src, &[annotation((39, 39), "x")], &anchored);
+		assert_eq!(
+			plain(&s),
+			[" 1 l1 ", " ⋮ ", "10 l10 ", "11 l11 ", "12 l12 🢒 x", "",].join("\n")
+		);
+	}
+
+	#[test]
+	fn left_margin() {
+		let anns = [annotation((0, 2), "a"), annotation((4, 6), "b")];
+		let default = plain(&parse("abc def", &anns, &opts(false)));
+		let before = plain(&parse(
+			"abc def",
+			&anns,
+			&Opts {
+				left_margin: 4,
+				..opts(false)
+			},
+		));
+		assert_eq!(
+			before,
+			default
+				.lines()
+				.map(|l| format!("    {l}\n"))
+				.collect::<String>()
+		);
+
+		let after = plain(&parse(
+			"abc def",
+			&anns,
+			&Opts {
+				left_margin: 4,
+				margin_position: MarginPosition::AfterGutter,
+				..opts(false)
+			},
+		));
+		assert_eq!(
+			after,
+			default
+				.lines()
+				.map(|l| {
+					let (gutter, rest) = l.split_at(l.char_indices().nth(2).expect("has gutter").0);
+					format!("{gutter}    {rest}\n")
+				})
+				.collect::<String>()
+		);
+	}
+
+	#[test]
+	fn tab_stops_after_gutter() {
+		let src = "a\t\tb\n\t\tc";
+		let mut snippet = SnippetBuilder::new(src).tab_origin(TabOrigin::Column(2));
+		snippet.error(Text::from("b")).range(3..=3).build();
+		snippet.error(Text::from("c")).range(7..=7).build();
+		let out = snippet.build().plain().to_string();
+		// Gutter is `1 `, so the source starts at the 3rd column, and both tabs end at the multiple of 4
+		assert_eq!(out, "1 a     b 🢒 b\n2       c 🢒 c\n");
+		for line in out.lines() {
+			assert_eq!(line.chars().position(|c| c == 'b' || c == 'c'), Some(8));
+		}
+
+		// Same stops are found from the rendered gutter, which is wider with the margin and the connector
+		let render = |origin| {
+			let mut snippet = SnippetBuilder::new(src)
+				.tab_origin(origin)
+				.left_margin(1, MarginPosition::BeforeGutter);
+			snippet.error(Text::from("b")).range(3..=3).build();
+			snippet.error(Text::from("both")).range(3..=7).build();
+			snippet.build().plain().to_string()
+		};
+		let out = render(TabOrigin::Gutter);
+		assert_eq!(
+			out,
+			" ·          ╭── b\n\
+			 \x201 ╭─a      b \n\
+			 \x202 ╰────────c 🢒 both\n"
+		);
+		for line in out.lines().skip(1) {
+			assert_eq!(line.chars().position(|c| c == 'b' || c == 'c'), Some(12));
+		}
+		assert_eq!(out, render(TabOrigin::Column(5)));
+	}
+
+	#[test]
+	fn fold_range() {
+		let src = (1..=14)
+			.map(|i| format!("l{i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let offset = |line: usize| src.find(&format!("l{line}\n")).expect("exists");
+		let render = |annotations: &[Annotation]| {
+			plain(&parse(
+				&src,
+				annotations,
+				&Opts {
+					fold: FoldOpts {
+						show_range: true,
+						..opts(true).fold
+					},
+					..opts(true)
+				},
+			))
+		};
+
+		let (a, b) = (offset(2), offset(12));
+		assert_eq!(
+			render(&[annotation((a, a), "a"), annotation((b, b), "b")]),
+			" 1 l1 \n\
+			 \x202 l2 🢒 a\n\
+			 \x203 l3 \n\
+			 \x204 l4 \n\
+			 \x20⋮ 5..9\n\
+			 10 l10 \n\
+			 11 l11 \n\
+			 12 l12 🢒 b\n\
+			 13 l13 \n\
+			 14 l14 \n"
+		);
+		assert_eq!(
+			render(&[annotation((a, a), "a")]),
+			"1 l1 \n\
+			 2 l2 🢒 a\n\
+			 3 l3 \n\
+			 4 l4 \n\
+			 ⋮ 5..14\n"
+		);
+	}
+
+	#[test]
+	fn folded_ranges() {
+		let src = (1..=14)
+			.map(|i| format!("l{i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let offset = |line: usize| src.find(&format!("l{line}\n")).expect("exists");
+		let (a, b) = (offset(2), offset(12));
+		let s = parse(
+			&src,
+			&[annotation((a, a), "a"), annotation((b, b), "b")],
+			&opts(true),
+		);
+		assert_eq!(s.folded(), [5..=9]);
+		// Hidden lines are exactly the ones not displayed
+		let out = plain(&s);
+		for line in 1..=14 {
+			let displayed = out.contains(&format!(" l{line} "));
+			assert_eq!(displayed, !(5..=9).contains(&line), "line {line}");
+		}
+
+		let s = parse(&src, &[annotation((a, a), "a")], &opts(true));
+		assert_eq!(s.folded(), [5..=14]);
+	}
+
+	#[test]
+	fn expand_gap() {
+		let src = (1..=50).map(|i| format!("line {i}\n")).collect::<String>();
+		let render = |context_lines: usize| {
+			let mut prepared = PreparedSource::new(
+				&Text::from(src.as_str()),
+				Opts {
+					fold: FoldOpts {
+						context_lines,
+						..FoldOpts::default()
+					},
+					..opts(false)
+				},
+			);
+			let start = src.find("line 7").expect("exists");
+			let end = src.find("line 43").expect("exists");
+			prepared.add(annotation((start, start + 3), "first"));
+			prepared.add(annotation((end, end + 3), "second"));
+			prepared.render().expect("valid annotations")
+		};
+		let source = render(2);
+		let gaps = source
+			.lines()
+			.iter()
+			.filter_map(|l| l.as_gap())
+			.collect::<Vec<_>>();
+		// Separate gaps of every line are merged by the cleanup
+		assert_eq!(gaps.len(), 3);
+		let gap = gaps[1];
+		assert_eq!(gap.folded_lines(), 10..=40);
+		let bytes = gap.folded_bytes();
+		assert_eq!(
+			&src[bytes],
+			(10..=40).map(|i| format!("line {i}\n")).collect::<String>()
+		);
+		assert_eq!(source.folded()[1], 10..=40);
+
+		// Expanded by re-rendering with the context reaching the gap
+		let expanded = plain(&render(17));
+		for i in gap.folded_lines() {
+			assert!(expanded.contains(&format!("{i} line {i} ")), "{expanded}");
+		}
+	}
+
+	#[test]
+	fn min_fold() {
+		let src = (1..=14)
+			.map(|i| format!("l{i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let offset = |line: usize| {
+			src.split('\n')
+				.take(line - 1)
+				.map(|l| l.len() + 1)
+				.sum::<usize>()
+		};
+		let apart = |distance: usize, min_fold: usize| {
+			let a = offset(2);
+			let b = offset(2 + distance);
+			plain(&parse(
+				&src,
+				&[annotation((a, a), "a"), annotation((b, b), "b")],
+				&Opts {
+					fold: FoldOpts {
+						context_lines: 1,
+						min_fold,
+						..opts(true).fold
+					},
+					..opts(true)
+				},
+			))
+		};
+		let shown = |lines: &[&str]| lines.iter().map(|l| format!("{l}\n")).collect::<String>();
+
+		assert_eq!(
+			apart(3, 3),
+			shown(&[
+				"1 l1 ",
+				"2 l2 🢒 a",
+				"3 l3 ",
+				"4 l4 ",
+				"5 l5 🢒 b",
+				"6 l6 ",
+				"⋮ "
+			])
+		);
+		// Two lines between contexts are not worth a gap
+		assert_eq!(
+			apart(5, 3),
+			shown(&[
+				"1 l1 ",
+				"2 l2 🢒 a",
+				"3 l3 ",
+				"4 l4 ",
+				"5 l5 ",
+				"6 l6 ",
+				"7 l7 🢒 b",
+				"8 l8 ",
+				"⋮ ",
+			])
+		);
+		assert_eq!(
+			apart(5, 0),
+			shown(&[
+				"1 l1 ",
+				"2 l2 🢒 a",
+				"3 l3 ",
+				"⋮ ",
+				"6 l6 ",
+				"7 l7 🢒 b",
+				"8 l8 ",
+				"⋮ "
+			])
+		);
+		assert_eq!(
+			apart(10, 3),
+			shown(&[
+				" 1 l1 ",
+				" 2 l2 🢒 a",
+				" 3 l3 ",
+				" ⋮ ",
+				"11 l11 ",
+				"12 l12 🢒 b",
+				"13 l13 ",
+				"14 l14 ",
+			])
+		);
+	}
+
+	#[test]
+	fn trailing_whitespace() {
+		let anns = [annotation((3, 5), "trailing space")];
+		let s = parse(
+			"abc   \ndef",
+			&anns,
+			&Opts {
+				whitespace: Whitespace::Trailing('␠'),
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"·    ╭──── trailing space",
+				"·    ├─╮",
+				"1 abc␠␠␠ ",
+				"2 def ",
+				"",
+			]
+			.join("\n")
+		);
+
+		// Spaces before the text and not annotated spaces are kept
+		let s = parse(
+			"  abc   ",
+			&[annotation((0, 6), "line")],
+			&Opts {
+				whitespace: Whitespace::Trailing('·'),
+				..opts(true)
+			},
+		);
+		assert_eq!(plain(&s), "1   abc··  🢒 line\n");
+	}
+
+	#[test]
+	fn deduplicate() {
+		let repeated = annotation((4, 6), "unused");
+		let anns = [
+			repeated.clone(),
+			Annotation {
+				priority: 1,
+				..repeated.clone()
+			},
+			repeated.clone(),
+			annotation((4, 6), "unused twice"),
+		];
+		let dedup = |mode| Opts {
+			inline_labels: false,
+			deduplicate: mode,
+			..opts(false)
+		};
+
+		assert!(matches!(
+			try_parse("abc def", &anns, &dedup(Deduplicate::Reject)),
+			Err(AnnotationError::Duplicate {
+				annotation: 2,
+				original: 0
+			})
+		));
+		assert_eq!(
+			plain(&parse("abc def", &anns, &dedup(Deduplicate::Collapse))),
+			[
+				"·     ╭──── unused twice",
+				"·     ┟──── unused",
+				"·     ┠─╮",
+				"·     ├─╮",
+				"1 abc def ",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(
+			plain(&parse("abc def", &anns, &dedup(Deduplicate::Count))),
+			[
+				"·     ╭──── unused twice",
+				"·     ┟──── unused (×3)",
+				"·     ┠─╮",
+				"·     ├─╮",
+				"1 abc def ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn prepared_source() {
+		let mut snippet = SnippetBuilder::new("abc def");
+		snippet.error(Text::from("first")).range(0..=2).build();
+		let mut prepared = snippet.prepare().expect("valid");
+		assert_eq!(
+			plain(&prepared.render().expect("valid")),
+			"1 abc def 🢒 first\n"
+		);
+
+		prepared.add(annotation((4, 6), "second"));
+		assert_eq!(
+			plain(&prepared.render().expect("valid")),
+			["· ╭──── first", "1 abc def 🢒 second", "",].join("\n")
+		);
+
+		prepared.add(annotation((4, 6), "second"));
+		assert!(matches!(
+			prepared.render(),
+			Err(AnnotationError::Duplicate { .. })
+		));
+	}
+
+	#[test]
+	fn connector_crossing() {
+		let s = parse(
+			"ab\ncd\nef\ngh\nij",
+			&[
+				annotation((0, 7), "A"),
+				Annotation {
+					formatting: Formatting::color(0x00ff0000),
+					..annotation((4, 10), "B")
+				},
+			],
+			&opts(true),
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1   ╭─ab ",
+				"2 ╭─┼─cd ",
+				"3 │ ╰─ef 🢒 A",
+				"4 ╰───gh 🢒 B",
+				"5     ij ",
+				"",
+			]
+			.join("\n")
+		);
+		// Connection of B is drawn over the vertical line of A
+		assert_eq!(colors(&s, 1)[2..6], [Some(0x00ff0000); 4]);
+		assert_eq!(colors(&s, 2)[4], Some(0xff000000));
+	}
+
+	#[test]
+	fn connector_through_label_rows() {
+		let src = "fn main(arg: u32) {\n    let x = foo(a, b);\n    bar();\n}";
+		let s = parse(
+			src,
+			&[
+				annotation((3, 40), "multi"),
+				annotation((0, 1), "kw"),
+				annotation((8, 10), "arg"),
+				annotation((28, 30), "foo"),
+			],
+			&Opts {
+				inline_labels: false,
+				..opts(true)
+			},
+		);
+		let out = plain(&s);
+		// Every horizontal line meeting a vertical connector is drawn as a junction or a crossing
+		for line in out.lines() {
+			assert!(!line.contains("│─"), "broken junction in {line:?}");
+			assert!(!line.contains("─│"), "broken junction in {line:?}");
+		}
+		assert_eq!(
+			out,
+			[
+				"· ╭────╮                ",
+				"· │ ╭──┼────────────────── kw",
+				"· │ │  │    ╭───────────── arg",
+				"· │ │  ├────┼──────────╮",
+				"· │ ├╮      ├─╮         ",
+				"1 │ fn main(arg: u32) { ",
+				"· │         ╭────────────── foo",
+				"· ╰─┬───────┼────────────── multi",
+				"·   ├───────┼───────────╮",
+				"·           ├─╮          ",
+				"2       let x = foo(a, b); ",
+				"3       bar(); ",
+				"4   } ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn connector_keeps_labels() {
+		let src = "fn main(arg: u32) {\n    let x = foo(a, b);\n    bar(baz, qux);\n}";
+		let anns = [
+			annotation((0, 60), "outer annotation with a long label"),
+			annotation((8, 40), "inner"),
+			annotation((24, 26), "a very long label of the single-line annotation"),
+		];
+		let out = plain(&parse(
+			src,
+			&anns,
+			&Opts {
+				inline_labels: false,
+				..opts(true)
+			},
+		));
+		for annotation in &anns {
+			let label = annotation.text.data().collect::<String>();
+			assert!(out.contains(&label), "{label:?} is clobbered");
+		}
+		assert_eq!(
+			out,
+			[
+				"· ╭───╮                   ",
+				"· │ ╭─┼───────╮           ",
+				"· │ │ ├───────┼──────────╮",
+				"· │ │         ├──────────╮",
+				"1 │ │ fn main(arg: u32) { ",
+				"· │ │     ╭────────────────── a very long label of the single-line annotation",
+				"· │ ╰─┬───┼────────────────── inner",
+				"· │   ├───┼───────────────╮",
+				"· │       ├─╮              ",
+				"2 │       let x = foo(a, b); ",
+				"· ╰───┬─────────────────── outer annotation with a long label",
+				"·     ├────────────────╮",
+				"3         bar(baz, qux); ",
+				"4     } ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn max_labels_per_line() {
+		let anns = (0..5)
+			.map(|i| Annotation {
+				priority: i + 1,
+				..annotation((i * 2, i * 2), &format!("l{i}"))
+			})
+			.collect::<Vec<_>>();
+		let limited = |apply_to_orig| Opts {
+			inline_labels: apply_to_orig,
+			max_labels_per_line: Some(2),
+			..opts(apply_to_orig)
+		};
+
+		assert_eq!(
+			plain(&parse("a b c d e f", &anns, &limited(false))),
+			[
+				"· … and 3 more",
+				"·       ╭──── l3",
+				"·       │ ╭── l4",
+				"· │ │ │ │ │",
+				"1 a b c d e f ",
+				"",
+			]
+			.join("\n")
+		);
+		let s = parse("a b c d e f", &anns, &limited(true));
+		assert_eq!(
+			plain(&s),
+			["· … and 3 more", "·       ╭── l3", "1 a b c d e f 🢒 l4", "",].join("\n")
+		);
+		// Elided annotations are still highlighted
+		assert!((0..5).all(|i| colors(&s, 2)[2 + i * 2].is_some()));
+
+		// Multi-line annotation is never elided, even with the lowest priority
+		let mut anns = (0..5)
+			.map(|i| Annotation {
+				priority: i + 1,
+				..annotation((4 + i * 2, 4 + i * 2), &format!("l{i}"))
+			})
+			.collect::<Vec<_>>();
+		anns.push(annotation((1, 14), "multi"));
+		assert_eq!(
+			plain(&parse("xyz\na b c d e f", &anns, &limited(false))),
+			[
+				"· ╭──╮  ",
+				"· │  ├─╮",
+				"1 │ xyz ",
+				"· │ … and 4 more",
+				"· │         ╭──── l4",
+				"· ╰─┬───────┼──── multi",
+				"·   ┠─┼─┼─┼─┼─╮",
+				"·   │ │ │ │ │  ",
+				"2   a b c d e f ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn gutter_formatting() {
+		let gray = Formatting::color(0x80808000);
+		let s = parse(
+			"a\nb\nc\nd\ne\nf\ng",
+			&[annotation((0, 0), "x"), annotation((12, 12), "y")],
+			&Opts {
+				inline_labels: false,
+				style: StyleOpts {
+					gutter: gray.clone(),
+					..opts(false).style
+				},
+				fold: FoldOpts {
+					min_fold: 0,
+					..opts(false).fold
+				},
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"· ╭── x",
+				"1 a ",
+				"2 b ",
+				"3 c ",
+				"⋮ ",
+				"5 e ",
+				"6 f ",
+				"· ╭── y",
+				"7 g ",
+				"",
+			]
+			.join("\n")
+		);
+		assert!((0..s.lines.len()).all(|i| colors(&s, i)[..2] == [gray.color; 2]));
+	}
+
+	#[test]
+	fn label_rows_order() {
+		let src = "0123456789".repeat(5);
+		let expected = [
+			"·      ╭─────────────────────────────────────── five",
+			"·      │              ╭──────────────────────── twenty",
+			"·      │              │                   ╭──── forty",
+			"·      ├─╮            ├─╮                 ├─╮",
+			"1 01234567890123456789012345678901234567890123456789 ",
+			"",
+		]
+		.join("\n");
+		// Priority does not affect rows order of the non-overlapping ranges
+		for priorities in [[0, 0, 0], [2, 1, 0], [0, 1, 2]] {
+			let anns = [((5, 7), "five"), ((20, 22), "twenty"), ((40, 42), "forty")]
+				.into_iter()
+				.zip(priorities)
+				.map(|((range, text), priority)| Annotation {
+					priority,
+					..annotation(range, text)
+				})
+				.collect::<Vec<_>>();
+			let s = parse(
+				&src,
+				&anns,
+				&Opts {
+					inline_labels: false,
+					..opts(false)
+				},
+			);
+			let out = plain(&s);
+			assert_eq!(out, expected);
+			for label in ["five", "twenty", "forty"] {
+				let row = out
+					.lines()
+					.find(|l| l.contains(label))
+					.expect("label is displayed");
+				assert!(row.ends_with(&format!("── {label}")), "{row}");
+			}
+		}
+	}
+
+	#[test]
+	fn deduplicate_builder() {
+		let build = |mode| {
+			let mut snippet = SnippetBuilder::new("abc def").deduplicate(mode);
+			for _ in 0..3 {
+				snippet.error(Text::from("same")).range(4..=6).build();
+			}
+			snippet.error(Text::from("other")).range(4..=6).build();
+			plain(&snippet.build())
+		};
+		assert_eq!(
+			build(Deduplicate::Count),
+			[
+				"·     ╭──── same (×3)",
+				"·     ├─╮",
+				"1 abc def 🢒 other",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(
+			build(Deduplicate::Collapse),
+			["·     ╭──── same", "·     ├─╮", "1 abc def 🢒 other", ""].join("\n")
+		);
+	}
+
+	#[test]
+	fn lower_priority_wins() {
+		let red = Formatting::color(0xff000000);
+		let blue = Formatting::color(0x0000ff00);
+		let render = |lower_priority_wins| {
+			let s = parse(
+				"abc\ndef",
+				&[
+					Annotation {
+						priority: 1,
+						formatting: red.clone(),
+						..annotation((0, 4), "red")
+					},
+					Annotation {
+						priority: 0,
+						formatting: blue.clone(),
+						..annotation((1, 4), "blue")
+					},
+				],
+				&Opts {
+					inline_labels: false,
+					lower_priority_wins,
+					..opts(true)
+				},
+			);
+			// Source text starts after the gutter and two connector columns
+			let colors = colors(&s, 4)[6..9]
+				.iter()
+				.map(|c| match c {
+					Some(0xff000000) => 'R',
+					Some(0x0000ff00) => 'B',
+					_ => '.',
+				})
+				.collect::<String>();
+			(plain(&s), colors)
+		};
+
+		let (out, colors) = render(false);
+		assert_eq!(colors, "RRR");
+		assert_eq!(
+			out,
+			[
+				"· ╭───╮   ",
+				"· │ ╭─┼╮  ",
+				"· │ │ ├┼─╮",
+				"· │ │  ├─╮",
+				"1 │ │ abc ",
+				"· │ ╰─┬── blue",
+				"· ╰───╁── red",
+				"2     def ",
+				"",
+			]
+			.join("\n")
+		);
+		// Blue wins the overlap, and the order of labels and connectors is flipped
+		let (out, colors) = render(true);
+		assert_eq!(colors, "RBB");
+		assert_eq!(
+			out,
+			[
+				"·   ╭─╮   ",
+				"· ╭─┼─┼╮  ",
+				"· │ │ ├┼─╮",
+				"· │ │  ├─╮",
+				"1 │ │ abc ",
+				"· │ ╰─┬── red",
+				"· ╰───╁── blue",
+				"2     def ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn overlap_priority() {
+		let red = Formatting::color(0xff000000);
+		let blue = Formatting::color(0x0000ff00);
+		// Rendered rows and colors of the source line, `R`/`B` for annotated chars, `.` for the others
+		let render = |red_range, blue_range, red_priority, inline_labels| {
+			let s = parse(
+				"abcdefghijk",
+				&[
+					Annotation {
+						priority: red_priority,
+						formatting: red.clone(),
+						..annotation(red_range, "red")
+					},
+					Annotation {
+						priority: 1 - red_priority,
+						formatting: blue.clone(),
+						..annotation(blue_range, "blue")
+					},
+				],
+				&Opts {
+					inline_labels,
+					..opts(true)
+				},
+			);
+			let out = plain(&s);
+			let source_row = out
+				.lines()
+				.position(|l| l.starts_with('1'))
+				.expect("has source");
+			let colors = colors(&s, source_row)[2..13]
+				.iter()
+				.map(|c| match c {
+					Some(0xff000000) => 'R',
+					Some(0x0000ff00) => 'B',
+					_ => '.',
+				})
+				.collect::<String>();
+			(out, colors)
+		};
+
+		// Full containment, loser still has its own underline
+		let (out, colors) = render((0, 10), (3, 5), 1, true);
+		assert_eq!(out, "·    ╭──── blue\n·    ├─╮\n1 abcdefghijk 🢒 red\n");
+		assert_eq!(colors, "RRRRRRRRRRR");
+		let (_, colors) = render((0, 10), (3, 5), 0, true);
+		assert_eq!(colors, "...BBB.....");
+		let (out, colors) = render((0, 10), (3, 5), 1, false);
+		assert_eq!(
+			out,
+			[
+				"· ╭──────────── red",
+				"· │  ╭───────── blue",
+				"· ├──┼──────╮",
+				"·    ├─╮     ",
+				"1 abcdefghijk ",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(colors, "RRRRRRRRRRR");
+		let (_, colors) = render((0, 10), (3, 5), 0, false);
+		assert_eq!(colors, "RRRBBBRRRRR");
+
+		// Partial overlap
+		let (_, colors) = render((0, 5), (3, 8), 1, false);
+		assert_eq!(colors, "RRRRRRBBB..");
+		let (_, colors) = render((0, 5), (3, 8), 0, false);
+		assert_eq!(colors, "RRRBBBBBB..");
+
+		// Identical ranges
+		let (out, colors) = render((2, 6), (2, 6), 1, false);
+		assert_eq!(
+			out,
+			[
+				"·   ╭────── blue",
+				"·   ┟────── red",
+				"·   ┠───╮",
+				"·   ├───╮",
+				"1 abcdefghijk ",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(colors, "..RRRRR....");
+		let (_, colors) = render((2, 6), (2, 6), 0, false);
+		assert_eq!(colors, "..BBBBB....");
+	}
+
+	#[test]
+	fn nested_ranges() {
+		let src = "foo(bar + baz)";
+		let nested = |anns: &[Annotation]| {
+			plain(&parse(
+				src,
+				anns,
+				&Opts {
+					inline_labels: false,
+					..opts(false)
+				},
+			))
+		};
+		assert_eq!(
+			nested(&[annotation((0, 13), "call"), annotation((4, 6), "bar")]),
+			[
+				"· ╭─────────────── call",
+				"· │   ╭─────────── bar",
+				"· ├───┼────────╮",
+				"·     ├─╮       ",
+				"1 foo(bar + baz) ",
+				"",
+			]
+			.join("\n")
+		);
+		// Order of definition doesn't matter
+		assert_eq!(
+			nested(&[
+				annotation((10, 12), "baz"),
+				annotation((0, 13), "call"),
+				annotation((4, 12), "sum"),
+			]),
+			[
+				"· ╭─────────────── call",
+				"· │   ╭─────────── sum",
+				"· │   │     ╭───── baz",
+				"· ├───┼─────┼──╮",
+				"·     ├─────┼─╮ ",
+				"·           ├─╮ ",
+				"1 foo(bar + baz) ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn warnings() {
+		let s = parse("abc", &[annotation((1, 10), "past")], &opts(true));
+		assert_eq!(s.warnings(), [RenderWarning::Clamped { annotation: 0 }]);
+
+		let s = parse(
+			"abc",
+			&[annotation((0, 1), "a"), annotation((0, 1), "a")],
+			&Opts {
+				deduplicate: Deduplicate::Collapse,
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			s.warnings(),
+			[RenderWarning::Collapsed {
+				annotation: 1,
+				original: 0
+			}]
+		);
+
+		let mut snippet = SnippetBuilder::new("abc def");
+		snippet
+			.error(Text::from("reversed"))
+			.range(RangeInclusive::new(6, 4))
+			.build();
+		let s = snippet.build();
+		assert_eq!(
+			s.warnings(),
+			[RenderWarning::ReversedRange {
+				annotation: 0,
+				start: 6,
+				end: 4
+			}]
+		);
+		assert_eq!(
+			s.warnings()[0].to_string(),
+			"annotation #0 had reversed range 6..=4, bounds were swapped"
+		);
+
+		let s = parse("abc", &[annotation((0, 1), "a")], &opts(true));
+		assert!(s.warnings().is_empty());
+	}
+
+	#[test]
+	fn summaries() {
+		let s = parse(
+			"fn main() {\n\tlet x = 1;\n}",
+			&[
+				annotation((17, 17), "unused"),
+				annotation((0, 1), "keyword"),
+			],
+			&Opts {
+				inline_labels: false,
+				..opts(false)
+			},
+		);
+		let summaries = s
+			.summaries()
+			.iter()
+			.map(|s| (s.line, s.column, s.text.data().collect::<String>()))
+			.collect::<Vec<_>>();
+		// Tab is expanded
+		assert_eq!(
+			summaries,
+			[(2, 9, "unused".to_owned()), (1, 1, "keyword".to_owned())]
+		);
+		let out = plain(&s);
+		let row = out
+			.lines()
+			.find(|l| l.starts_with("2 "))
+			.expect("line is displayed");
+		assert_eq!(row.chars().nth(2 + 8), Some('x'));
+	}
+
+	#[test]
+	fn label_stacking() {
+		let src = "foo(bar + baz)";
+		let stacked = |stacking| {
+			plain(&parse(
+				src,
+				&[
+					annotation((10, 12), "baz"),
+					annotation((0, 13), "call"),
+					annotation((4, 12), "sum"),
+				],
+				&Opts {
+					inline_labels: false,
+					label_stacking: stacking,
+					..opts(false)
+				},
+			))
+		};
+		assert_eq!(
+			stacked(LabelStacking::OutermostFarthest),
+			[
+				"· ╭─────────────── call",
+				"· │   ╭─────────── sum",
+				"· │   │     ╭───── baz",
+				"· ├───┼─────┼──╮",
+				"·     ├─────┼─╮ ",
+				"·           ├─╮ ",
+				"1 foo(bar + baz) ",
+				"",
+			]
+			.join("\n")
+		);
+		assert_eq!(
+			stacked(LabelStacking::OutermostNearest),
+			[
+				"·           ╭───── baz",
+				"·     ╭─────┼───── sum",
+				"· ╭───┼─────┼───── call",
+				"· ├───┼─────┼──╮",
+				"·     ├─────┼─╮ ",
+				"·           ├─╮ ",
+				"1 foo(bar + baz) ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn styled_source() {
+		let blue = Some(0x0000ff00);
+		let green = Some(0x00ff0000);
+		let red = Some(0xff000000);
+		let src = Text::build()
+			.styled("let\t", Formatting::color(0x0000ff00))
+			.styled("x = 1", Formatting::color(0x00ff0000))
+			.finish();
+		let styled = |apply_to_orig| {
+			let mut prepared = PreparedSource::new(
+				&src,
+				Opts {
+					inline_labels: false,
+					..opts(apply_to_orig)
+				},
+			);
+			// Covers both colors, `t\tx`
+			prepared.add(annotation((2, 4), "here"));
+			prepared.render().expect("valid")
+		};
+
+		let s = styled(false);
+		assert_eq!(plain(&s), "·   ╭──── here\n·   ├─╮\n1 let x = 1 \n");
+		assert_eq!(
+			colors(&s, 2)[2..],
+			[blue, blue, blue, blue, green, green, green, green, green, None]
+		);
+
+		let s = styled(true);
+		assert_eq!(
+			colors(&s, 2)[2..],
+			[blue, blue, red, red, red, green, green, green, green, None]
+		);
+	}
+
+	#[test]
+	fn connector_keeps_indentation() {
+		let s = parse(
+			"def f():\n        if x:\n            return 1\n",
+			&[annotation((17, 42), "block")],
+			&Opts {
+				inline_labels: false,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1   def f(): ",
+				"· ╭─────────╮     ",
+				"· │         ├────╮",
+				"2 │         if x: ",
+				"· ╰─┬───────────────────── block",
+				"·   ├──────────────────╮",
+				"3               return 1 ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn tab_inside_range() {
+		let tabbed = |range| {
+			plain(&parse(
+				"ab\tcd",
+				&[annotation(range, "x")],
+				&Opts {
+					inline_labels: false,
+					..opts(false)
+				},
+			))
+		};
+		// Tab is expanded to two spaces, both are covered
+		assert_eq!(tabbed((1, 3)), "·  ╭───── x\n·  ├──╮\n1 ab  cd \n");
+		assert_eq!(tabbed((1, 2)), "·  ╭──── x\n·  ├─╮\n1 ab  cd \n");
+		assert_eq!(tabbed((2, 2)), "·   ╭─── x\n·   ├╮\n1 ab  cd \n");
+	}
+
+	#[test]
+	fn highlight() {
+		let blue = Some(0x0000ff00);
+		let red = Some(0xff000000);
+		let keywords = |_line: usize, text: &str| {
+			text.match_indices("let")
+				.map(|(i, kw)| (i..i + kw.len(), Formatting::color(0x0000ff00)))
+				.collect()
+		};
+		let s = parse(
+			"let a = 1;\nlet b = a;",
+			&[annotation((19, 19), "here")],
+			&Opts {
+				inline_labels: false,
+				highlight: Some(Arc::new(keywords)),
+				..opts(true)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			"1 let a = 1; \n·         ╭── here\n2 let b = a; \n"
+		);
+		assert_eq!(colors(&s, 0)[2..6], [blue, blue, blue, None]);
+		// Annotation colors are applied over the highlighting
+		assert_eq!(
+			colors(&s, 2)[2..13],
+			[blue, blue, blue, None, None, None, None, None, red, None, None]
+		);
+
+		// Folded lines are never highlighted
+		let highlighted = Arc::new(std::sync::Mutex::new(Vec::new()));
+		let src = (1..=10)
+			.map(|i| format!("line {i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let mut snippet = SnippetBuilder::new(&src).highlight({
+			let highlighted = highlighted.clone();
+			move |line, _| {
+				highlighted.lock().expect("not poisoned").push(line);
+				Vec::new()
+			}
+		});
+		snippet.error(Text::from("here")).range(0..=3).build();
+		snippet.build();
+		assert_eq!(*highlighted.lock().expect("not poisoned"), [1, 2, 3]);
+	}
+
+	#[test]
+	fn custom_stage() {
+		use pipeline::{Ctx, LineNumbers, Stage};
+
+		struct MarkEven;
+		impl Stage for MarkEven {
+			fn run(&self, source: &mut Source, _ctx: &Ctx) {
+				for line in source.lines_mut() {
+					let even = matches!(line, Line::Text(t) if t.line_num() % 2 == 0);
+					let prefix = line.prefix_mut().expect("no raw lines yet");
+					prefix.splice(0..0, Some(Text::from(if even { "*" } else { " " })));
+				}
+			}
+		}
+		let mut pipeline = Pipeline::default();
+		assert!(pipeline.insert_after::<LineNumbers>(MarkEven));
+
+		let s = parse(
+			"a\nb\nc",
+			&[annotation((2, 2), "here")],
+			&Opts {
+				inline_labels: false,
+				pipeline: Some(Arc::new(pipeline)),
+				..opts(false)
+			},
+		);
+		assert_eq!(plain(&s), " 1 a \n · ╭── here\n*2 b \n 3 c \n");
+	}
+
+	#[test]
+	fn raw_footer() {
+		use pipeline::{Ctx, Frame, Stage};
+
+		fn column(rendered: &str, needle: &str) -> usize {
+			let line = rendered
+				.lines()
+				.find(|l| l.contains(needle))
+				.expect("line is rendered");
+			line[..line.find(needle).expect("found")].chars().count()
+		}
+
+		let src = (1..=12).map(|i| format!("line {i}\n")).collect::<String>();
+		let start = src.find("line 12").expect("exists");
+		let mut snippet = SnippetBuilder::new(&src);
+		snippet
+			.error(Text::from("here"))
+			.range(start..=start + 3)
+			.build();
+		let mut source = snippet.build();
+		assert_eq!(source.gutter_width(), 3);
+		let footer = format!("{}footer", " ".repeat(source.gutter_width()));
+		source.push_raw(Text::from(footer.as_str()));
+		source.insert_raw(0, Text::from("header"));
+		let rendered = plain(&source);
+		assert!(rendered.starts_with("header\n"));
+		assert_eq!(column(&rendered, "footer"), column(&rendered, "line 12"));
+
+		// Added by a stage, so the frame is drawn around it
+		struct Footer;
+		impl Stage for Footer {
+			fn run(&self, source: &mut Source, _ctx: &Ctx) {
+				let footer = format!("{}footer", " ".repeat(source.gutter_width()));
+				source.push_raw(Text::from(footer.as_str()));
+			}
+		}
+		let mut pipeline = Pipeline::default();
+		assert!(pipeline.insert_before::<Frame>(Footer));
+
+		let src = (1..=1005)
+			.map(|i| format!("line {i}\n"))
+			.collect::<String>();
+		let start = src.find("line 1003").expect("exists");
+		let mut snippet = SnippetBuilder::new(&src)
+			.left_margin(2, MarginPosition::BeforeGutter)
+			.frame(Text::from("title"))
+			.pipeline(pipeline);
+		snippet
+			.error(Text::from("here"))
+			.range(start..=start + 3)
+			.build();
+		let source = snippet.build();
+		assert_eq!(source.gutter_width(), 2 + 5 + 2);
+		let rendered = plain(&source);
+		assert_eq!(column(&rendered, "footer"), column(&rendered, "line 1003"));
+		let footer = rendered
+			.lines()
+			.find(|l| l.contains("footer"))
+			.expect("rendered");
+		assert!(
+			footer.starts_with('│') && footer.ends_with('│'),
+			"{rendered}"
+		);
+	}
+
+	#[test]
+	fn gutter_width_connectors() {
+		let mut snippet = SnippetBuilder::new("let a = 1;\nlet b = 2;");
+		snippet.error(Text::from("here")).range(4..=15).build();
+		let mut source = snippet.build();
+		let footer = format!("{}footer", " ".repeat(source.gutter_width()));
+		source.push_raw(Text::from(footer.as_str()));
+		assert_eq!(source.gutter_width(), 4);
+		assert_eq!(
+			plain(&source),
+			"1 ╭─let a = 1; \n\
+			 2 ╰─let b = 2; 🢒 here\n\
+			 \x20   footer\n"
+		);
+	}
+
+	#[test]
+	fn incremental_annotations() {
+		let txt = "fn main() {\n\tlet a: i32 = \"abc\";\n}";
+		let annotations = [
+			annotation((0, 1), "function"),
+			annotation((17, 19), "type"),
+			annotation((23, 27), "string"),
+		];
+		for opts in [opts(false), opts(true)] {
+			let expected = plain(&parse(txt, &annotations, &opts));
+
+			let mut prepared = PreparedSource::new(&Text::from(txt), opts.clone());
+			prepared.add(annotations[0].clone());
+			let first = prepared.render().expect("valid");
+			assert_eq!(plain(&first), plain(&parse(txt, &annotations[..1], &opts)));
+			for annotation in &annotations[1..] {
+				prepared.add(annotation.clone());
+			}
+			// Every render is independent of the previous ones
+			assert_eq!(plain(&prepared.render().expect("valid")), expected);
+			assert_eq!(plain(&prepared.render().expect("valid")), expected);
+		}
+	}
+
+	#[test]
+	fn collapse_adjacent() {
+		let txt = "return;\nb();\nc();\nd();\n\nf();\n";
+		let anns = [
+			annotation((8, 10), "unreachable"),
+			annotation((13, 15), "unreachable"),
+			annotation((18, 20), "unreachable"),
+			annotation((24, 26), "unreachable"),
+		];
+		let o = Opts {
+			collapse_adjacent: true,
+			inline_labels: false,
+			..opts(false)
+		};
+		let s = parse(txt, &anns, &o);
+		assert_eq!(
+			plain(&s),
+			"1   return; \n\
+			 · ╭─╮  \n\
+			 · │ ├─╮\n\
+			 2 │ b(); \n\
+			 · ├─╮  \n\
+			 · │ ├─╮\n\
+			 3 │ c(); \n\
+			 · ╰─┬──── unreachable\n\
+			 ·   ├─╮\n\
+			 4   d(); \n\
+			 5    \n\
+			 ·   ╭──── unreachable\n\
+			 ·   ├─╮\n\
+			 6   f(); \n"
+		);
+		// Line after the blank one is not adjacent
+		assert_eq!(s.summaries().len(), 2);
+
+		let s = parse(
+			txt,
+			&anns,
+			&Opts {
+				collapse_adjacent: false,
+				..o
+			},
+		);
+		assert_eq!(s.summaries().len(), 4);
+	}
+
+	#[test]
+	fn collapse_adjacent_builder() {
+		let mut snippet = SnippetBuilder::new("a();\nb();\nc();").collapse_adjacent(true);
+		for start in [0, 5, 10] {
+			snippet
+				.warning(Text::from("unused"))
+				.range(start..=start + 2)
+				.build();
+		}
+		snippet.error(Text::from("unused")).range(12..=12).build();
+		let source = snippet.build();
+		assert_eq!(source.summaries().len(), 2);
+		// Error with the same text is kept separate
+		assert_eq!(
+			plain(&source),
+			"1 ╭─a(); \n\
+			 2 ├─b(); \n\
+			 · ╰─┬──── unused\n\
+			 ·   ├─╮\n\
+			 3   c(); 🢒 unused\n"
+		);
+	}
+
+	#[test]
+	fn byte_offset_gutter() {
+		// CRLF and multibyte char are counted in bytes of the original text
+		let txt = "ab\r\nπ=1;\n\tc\n";
+		let render = |numbers| {
+			let mut snippet = SnippetBuilder::new(txt).gutter_numbers(numbers);
+			snippet.error(Text::from("here")).range(11..=11).build();
+			plain(&snippet.build())
+		};
+		assert_eq!(
+			render(GutterNumbers::ByteOffsets),
+			"\x200 ab \n\
+			 \x204 π=1; \n\
+			 10     c 🢒 here\n"
+		);
+		assert_eq!(
+			render(GutterNumbers::Both),
+			"\x201@0 ab \n\
+			 \x202@4 π=1; \n\
+			 3@10     c 🢒 here\n"
+		);
+	}
+
+	#[test]
+	fn gutter_icons() {
+		let mut snippet = SnippetBuilder::new("let a = 1;\nlet b = 2;\nlet c = 3;\n")
+			.gutter_icon(Severity::Error, Text::from("✖"))
+			.gutter_icon(Severity::Warning, Text::from("⚠"));
+		snippet.warning(Text::from("unused")).range(4..=4).build();
+		snippet.error(Text::from("mismatch")).range(19..=19).build();
+		// Less severe annotation on the same line doesn't replace the icon
+		snippet.note(Text::from("note")).range(17..=17).build();
+		let s = snippet.build();
+		assert_eq!(
+			plain(&s),
+			"1 ⚠ let a = 1; 🢒 unused\n\
+			 ·         ╭── note\n\
+			 2 ✖ let b = 2; 🢒 mismatch\n\
+			 3   let c = 3; \n"
+		);
+		assert_eq!(s.gutter_width(), 4);
+
+		let mut snippet =
+			SnippetBuilder::new("let a = 1;").gutter_icon(Severity::Error, Text::from("✖\n"));
+		snippet.error(Text::from("here")).range(4..=4).build();
+		assert_eq!(
+			snippet.try_build().map(|_| ()),
+			Err(AnnotationError::InvalidOpts(OptsError::Multiline {
+				setting: "gutter icon"
+			}))
+		);
+	}
+
+	#[test]
+	fn reuse_prepared_source() {
+		let txt = "fn main() {\n\tlet a: i32 = \"abc\";\n}";
+		let first = [annotation((0, 1), "function"), annotation((17, 19), "type")];
+		let second = [annotation((23, 27), "string")];
+		let o = opts(true);
+
+		let prepared = PreparedSource::new(&Text::from(txt), o.clone());
+		for set in [&first[..], &second[..]] {
+			let mut annotated = prepared.clone();
+			annotated.extend(set.iter().cloned());
+			assert_eq!(
+				plain(&annotated.render().expect("valid")),
+				plain(&parse(txt, set, &o))
+			);
+		}
+		// Clones don't share annotations
+		assert_eq!(prepared.render().expect("valid").summaries().len(), 0);
+	}
+
+	#[test]
+	fn connect_lines() {
+		let mut unconnected = annotation((5, 12), "unconnected");
+		unconnected.connect_lines = false;
+		let s = parse(
+			"abc def\nghi jkl\nmno",
+			&[annotation((0, 10), "connected"), unconnected],
+			&Opts {
+				inline_labels: false,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"· ╭─╮       ",
+				"· │ ├────┼─╮",
+				"· │      ├─╮",
+				"1 │ abc def ",
+				"· │ ╭────── unconnected",
+				"· ╰─╁────── connected",
+				"·   ┠───╮",
+				"·   ├─╮  ",
+				"2   ghi jkl ",
+				"3   mno ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn line_prefixes() {
+		let added = Formatting::color(0x00ff0000);
+		let removed = Formatting::color(0xff000000);
+		let s = parse(
+			"a\nb\nc",
+			&[annotation((2, 2), "here")],
+			&Opts {
+				inline_labels: false,
+				line_prefixes: [
+					(2, Text::from_str("+", added.clone())),
+					(3, Text::from_str("-", removed.clone())),
+				]
+				.into_iter()
+				.collect(),
+				left_margin: 1,
+				..opts(false)
+			},
+		);
+		assert_eq!(plain(&s), "  1 a \n  · ╭── here\n +2 b \n -3 c \n");
+		assert_eq!(colors(&s, 2)[1], added.color);
+		assert_eq!(colors(&s, 3)[1], removed.color);
+	}
+
+	#[test]
+	fn annotation_keys() {
+		let keyed = |key| Annotation {
+			key: Some(key),
+			..annotation((4, 6), "here")
+		};
+		let s = parse("abc def", &[keyed(42), keyed(7)], &opts(false));
+		assert_eq!(
+			s.summaries().iter().map(|s| s.key).collect::<Vec<_>>(),
+			[Some(42), Some(7)]
+		);
+		// Different keys prevent deduplication
+		assert_eq!(
+			try_parse(
+				"abc def",
+				&[keyed(42), keyed(7)],
+				&Opts {
+					deduplicate: Deduplicate::Collapse,
+					..opts(false)
+				}
+			)
+			.expect("valid")
+			.summaries()
+			.len(),
+			2
+		);
+
+		let err = try_parse(
+			"abc def",
+			&[
+				keyed(42),
+				annotation((0, 2), "other"),
+				Annotation {
+					key: Some(42),
+					..annotation((0, 0), "reused")
+				},
+			],
+			&opts(false),
+		)
+		.err();
+		assert_eq!(
+			err,
+			Some(AnnotationError::DuplicateKey {
+				key: 42,
+				annotation: 2,
+				original: 0,
+			})
+		);
+	}
+
+	#[test]
+	fn max_annotations_per_line() {
+		let annotations = (0..10)
+			.map(|i| Annotation {
+				priority: i,
+				..annotation((i * 2, i * 2), &format!("a{i}"))
+			})
+			.collect::<Vec<_>>();
+		let s = parse(
+			"a b c d e f g h i j",
+			&annotations,
+			&Opts {
+				inline_labels: false,
+				max_annotations_per_line: Some(3),
+				..opts(false)
+			},
+		);
+		// Unlike with elided labels, ranges of the removed annotations are not displayed
+		assert_eq!(
+			plain(&s),
+			[
+				"· … and 7 more",
+				"·               ╭────── a7",
+				"·               │ ╭──── a8",
+				"·               │ │ ╭── a9",
+				"1 a b c d e f g h i j ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn palette() {
+		let mut snippet = SnippetBuilder::new("abc def ghi jkl").palette(Palette::ColorBlind);
+		snippet.error(Text::from("error")).range(0..=2).build();
+		snippet.warning(Text::from("warning")).range(4..=6).build();
+		snippet.note(Text::from("note")).range(8..=10).build();
+		snippet.info(Text::from("info")).range(12..=14).build();
+		let prepared = snippet.clone().prepare().expect("valid");
+		let formats = prepared
+			.annotations
+			.iter()
+			.map(|a| a.formatting.clone())
+			.collect::<Vec<_>>();
+		for (i, a) in formats.iter().enumerate() {
+			for b in &formats[i + 1..] {
+				assert_ne!(a.color, b.color);
+			}
+		}
+		// Errors are distinguishable without hue
+		assert_eq!(
+			formats.iter().map(|f| f.bold).collect::<Vec<_>>(),
+			[true, false, false, false]
+		);
+		assert!(source_to_ansi(&prepared.render().expect("valid")).contains("\x1b[1m"));
+
+		// Severities are distinguishable without hue by their underline
+		let mut prepared = snippet.prepare().expect("valid");
+		prepared.opts.apply_to_orig = false;
+		prepared.opts.inline_labels = false;
+		assert_eq!(
+			plain(&prepared.render().expect("valid")),
+			[
+				"· ╭──────────────── error",
+				"· │   ╭──────────── warning",
+				"· │   │   ╭──────── note",
+				"· │   │   │   ╭──── info",
+				"· ├━╮ ├─╮ ├╌╮ ├┈╮",
+				"1 abc def ghi jkl ",
+				"",
+			]
+			.join("\n")
+		);
+
+		// Colors are picked when the annotation is added, but underlines are picked when rendering
+		let mut snippet = SnippetBuilder::new("abc");
+		snippet.error(Text::from("error")).range(0..=2).build();
+		let mut prepared = snippet
+			.palette(Palette::ColorBlind)
+			.prepare()
+			.expect("valid");
+		prepared.opts.inline_labels = false;
+		assert!(plain(&prepared.render().expect("valid")).contains("━"));
+	}
+
+	#[test]
+	fn text_debug() {
+		let text = Text::build()
+			.str("let ")
+			.styled(
+				"a",
+				Formatting {
+					bold: true,
+					..Formatting::color(0xff000000)
+				},
+			)
+			.finish();
+		assert_eq!(
+			format!("{text:?}"),
+			r#"["let "[default], "a"[#ff000000 bold]]"#
+		);
+		assert_eq!(text.clone(), text);
+	}
+
+	#[test]
+	fn source_debug() {
+		use pipeline::{ApplyAnnotations, GenerateAnnotations};
+
+		let mut pipeline = Pipeline::new();
+		pipeline.push(GenerateAnnotations);
+		pipeline.push(ApplyAnnotations);
+		let s = parse(
+			"a\nb",
+			&[annotation((0, 0), "x")],
+			&Opts {
+				inline_labels: false,
+				pipeline: Some(Arc::new(pipeline)),
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			format!("{:?}", s.lines()),
+			r#"[Annotation("╭── x"), Text(1, "a "), Text(2, "b ")]"#
+		);
+		let debug = format!("{s:?}");
+		assert!(debug.starts_with(r#"Source { lines: [Annotation("╭── x"), "#));
+	}
+
+	#[test]
+	fn range_pointers() {
+		let s = parse(
+			"let abc = 1;",
+			&[annotation((4, 6), "name"), annotation((8, 8), "eq")],
+			&Opts {
+				inline_labels: false,
+				range_pointers: true,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"·     ╭────── name",
+				"·     │   ╭── eq",
+				"·     ▼─╮ ▼",
+				"1 let abc = 1; ",
+				"",
+			]
+			.join("\n")
+		);
+	}
+
+	#[test]
+	fn invalid_opts() {
+		let result = try_parse(
+			"abc",
+			&[annotation((0, 0), "a")],
+			&Opts {
+				tab_width: 0,
+				tab_origin: TabOrigin::Line,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			result.err(),
+			Some(AnnotationError::InvalidOpts(OptsError::ZeroTabWidth))
+		);
+
+		// Gutter is `10 ` with two columns of margin
+		let src = "a\n".repeat(10);
+		let narrow = |max_width, frame: Option<Text>| Opts {
+			max_width: Some(max_width),
+			left_margin: 2,
+			frame,
+			..opts(false)
+		};
+		assert_eq!(
+			try_parse(&src, &[annotation((18, 18), "a")], &narrow(6, None)).err(),
+			Some(AnnotationError::InvalidOpts(OptsError::MaxWidthTooSmall {
+				max_width: 6,
+				gutter_width: 5
+			}))
+		);
+		assert!(try_parse(&src, &[annotation((18, 18), "a")], &narrow(7, None)).is_ok());
+		assert!(try_parse(
+			&src,
+			&[annotation((18, 18), "a")],
+			&narrow(7, Some("f".into()))
+		)
+		.is_ok());
+	}
+
+	#[test]
+	fn counting_stage() {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+
+		use pipeline::{Ctx, Finalize, Stage};
+
+		struct CountAnnotations(Arc<AtomicUsize>);
+		impl Stage for CountAnnotations {
+			fn run(&self, source: &mut Source, _ctx: &Ctx) {
+				let count = source
+					.lines()
+					.iter()
+					.filter(|l| matches!(l, Line::Annotation(_)))
+					.count();
+				self.0.store(count, Ordering::Relaxed);
+			}
+		}
+		let count = Arc::new(AtomicUsize::new(0));
+		let mut pipeline = Pipeline::default();
+		assert!(pipeline.insert_before::<Finalize>(CountAnnotations(count.clone())));
+
+		parse(
+			"abc\ndef",
+			&[annotation((0, 0), "a"), annotation((4, 6), "b")],
+			&Opts {
+				inline_labels: false,
+				pipeline: Some(Arc::new(pipeline)),
+				..opts(false)
+			},
+		);
+		// Label rows of both annotations, and the range row of the second one
+		assert_eq!(count.load(Ordering::Relaxed), 3);
+	}
+
+	#[test]
+	fn show_trailing_whitespace() {
+		let s = parse(
+			"abc  \ndef \t\nghi  ",
+			&[annotation((3, 4), "spaces"), annotation((9, 10), "tab")],
+			&Opts {
+				whitespace: Whitespace::TrailingTouched,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"·    ╭─── spaces",
+				"·    ├╮",
+				"1 abc·· ",
+				"·    ╭────── tab",
+				"·    ├───╮",
+				"2 def·→    ",
+				"3 ghi   ",
+				"",
+			]
+			.join("\n")
+		);
+		// Glyphs are dimmed, and take column of the replaced space
+		assert_eq!(colors(&s, 2)[5..7], [Some(0x92837400); 2]);
+	}
+
+	#[test]
+	fn dedent() {
+		let src = "\t\tif x {\n\t\t\tfoo(bar);\n\n\t\t}";
+		let anns = [annotation((12, 14), "call"), annotation((16, 18), "arg")];
+		let s = parse(
+			src,
+			&anns,
+			&Opts {
+				inline_labels: false,
+				dedent: true,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 if x { ",
+				"·     ╭──────── call",
+				"·     │   ╭──── arg",
+				"·     ├─╮ ├─╮",
+				"2     foo(bar); ",
+				"3  ",
+				"4 } ",
+				"",
+			]
+			.join("\n")
+		);
+
+		// Ruler keeps columns of the original source
+		let s = parse(
+			src,
+			&anns,
+			&Opts {
+				inline_labels: false,
+				dedent: true,
+				column_ruler: true,
+				..opts(false)
+			},
+		);
+		assert!(plain(&s).lines().any(|l| l == "  901234567890123456"));
+	}
+
+	#[test]
+	fn line_separators() {
+		for src in ["ab\rcd", "ab\u{2028}cd", "ab\u{2029}cd", "ab\r\ncd"] {
+			let d = src.find('d').expect("exists");
+			let s = parse(src, &[annotation((d, d), "d")], &opts(true));
+			assert_eq!(plain(&s), "1 ab \n2 cd 🢒 d\n", "{src:?}");
+			let summary = &s.summaries()[0];
+			assert_eq!((summary.line, summary.column), (2, 2), "{src:?}");
+		}
+		// Vertical tab is not a line break, and is displayed as an escape
+		let src = "ab\x0bcd";
+		let d = src.find('d').expect("exists");
+		let s = parse(src, &[annotation((d, d), "d")], &opts(true));
+		assert_eq!(plain(&s), "1 ab<U+000B>cd 🢒 d\n");
+	}
+
+	#[test]
+	fn line_col_ranges() {
+		let src = "fn main() {\n\tlet ñ = \"日本\";\r\n}";
+		let by_offsets = {
+			let mut snippet = SnippetBuilder::new(src);
+			let start = src.find('ñ').expect("exists");
+			let end = src.find('本').expect("exists");
+			snippet.error(Text::from("here")).range(start..=end).build();
+			snippet.error(Text::from("eol")).range(11..=11).build();
+			snippet.build()
+		};
+		let by_positions = {
+			let mut snippet = SnippetBuilder::new(src);
+			snippet
+				.error(Text::from("here"))
+				.line_col(
+					LineCol { line: 1, column: 5 },
+					LineCol {
+						line: 1,
+						column: 11,
+					},
+				)
+				.build();
+			snippet
+				.error(Text::from("eol"))
+				.line_col(
+					LineCol {
+						line: 0,
+						column: 11,
+					},
+					LineCol {
+						line: 0,
+						column: 11,
+					},
+				)
+				.build();
+			snippet.build()
+		};
+		assert_eq!(
+			by_positions.plain().to_string(),
+			by_offsets.plain().to_string()
+		);
+		assert_eq!(
+			by_positions.summaries()[0].column,
+			by_offsets.summaries()[0].column
+		);
+		for line in 0..by_offsets.lines().len() {
+			assert_eq!(colors(&by_positions, line), colors(&by_offsets, line));
+		}
+
+		let linestarts = byte_linestarts(src);
+		let offset = |line, column| linecol_to_offset(src, &linestarts, LineCol { line, column });
+		assert_eq!(offset(2, 0), src.find('}'));
+		assert_eq!(offset(2, 1), Some(src.len()));
+		assert_eq!(offset(1, 14), src.find('\r'));
+		assert_eq!(offset(1, 15), None);
+		assert_eq!(offset(0, 12), None);
+		assert_eq!(offset(3, 0), None);
+
+		let mut snippet = SnippetBuilder::new(src);
+		snippet
+			.error(Text::from("here"))
+			.line_col(
+				LineCol { line: 0, column: 0 },
+				LineCol { line: 5, column: 0 },
+			)
+			.build();
+		assert_eq!(
+			snippet.try_build().err(),
+			Some(AnnotationError::PositionOutOfBounds {
+				annotation: 0,
+				position: LineCol { line: 5, column: 0 },
+			})
+		);
+	}
+
+	#[test]
+	fn legend() {
+		let render = |legend: bool| {
+			let mut snippet = SnippetBuilder::new("let a = b;").legend(legend);
+			snippet.warning(Text::from("unused")).range(4..=4).build();
+			snippet.error(Text::from("undefined")).range(8..=8).build();
+			snippet.warning(Text::from("unused")).range(0..=2).build();
+			snippet.build()
+		};
+		let s = render(true);
+		let out = plain(&s);
+		// Only used severities are listed, once each, from the most severe
+		assert_eq!(out.lines().last(), Some("── error  ── warning"));
+		let legend = s.lines().last().expect("not empty").rendered();
+		let color = |c: usize| legend.get(c).expect("in bounds").1.color;
+		assert_ne!(color(0), color("── error  ".chars().count()));
+
+		assert!(!plain(&render(false)).contains("── error"));
+
+		let mut snippet = SnippetBuilder::new("let a = b;")
+			.legend(true)
+			.frame(Text::from("title"));
+		snippet.note(Text::from("note")).range(4..=4).build();
+		let out = plain(&snippet.build());
+		// Placed under the frame
+		assert!(out.ends_with("╯\n── note\n"), "{out}");
+
+		// Annotations added after building are listed too
+		let mut prepared = SnippetBuilder::new("let a = b;")
+			.legend(true)
+			.prepare()
+			.expect("no annotations");
+		assert!(!plain(&prepared.render().expect("valid")).contains("──"));
+		prepared.add(Annotation {
+			severity: Some(Severity::Info),
+			..annotation((4, 4), "info")
+		});
+		let out = plain(&prepared.render().expect("valid"));
+		assert_eq!(out.lines().last(), Some("── info"));
+
+		// Annotations outside of the window are not listed
+		let src = (1..=20).map(|i| format!("line {i}\n")).collect::<String>();
+		let mut snippet = SnippetBuilder::new(&src).legend(true).window(1..=3);
+		snippet.error(Text::from("error")).range(0..=3).build();
+		let line_15 = src.find("line 15").expect("exists");
+		snippet
+			.warning(Text::from("warning"))
+			.range(line_15..=line_15 + 3)
+			.build();
+		assert_eq!(plain(&snippet.build()).lines().last(), Some("── error"));
+
+		// Neither are the annotations over the limit
+		let mut snippet = SnippetBuilder::new("let a = b;")
+			.legend(true)
+			.max_annotations_per_line(1);
+		snippet
+			.error(Text::from("error"))
+			.range(4..=4)
+			.priority(1)
+			.build();
+		snippet.note(Text::from("note")).range(8..=8).build();
+		assert_eq!(plain(&snippet.build()).lines().last(), Some("── error"));
+
+		// Every severity has its own underline in the color-blind palette
+		let mut snippet = SnippetBuilder::new("let a = b;")
+			.legend(true)
+			.palette(Palette::ColorBlind);
+		snippet.error(Text::from("error")).range(0..=2).build();
+		snippet.warning(Text::from("warning")).range(4..=4).build();
+		snippet.note(Text::from("note")).range(8..=8).build();
+		assert_eq!(
+			plain(&snippet.build()).lines().last(),
+			Some("━━ error  ── warning  ╌╌ note")
+		);
+	}
+
+	#[test]
+	fn trim_eol() {
+		let src = "ab\ncd\nef";
+		let anns = [annotation((2, 2), "eol"), annotation((3, 3), "c")];
+		let trimmed = |inline_labels| Opts {
+			trim_eol: true,
+			inline_labels,
+			..opts(true)
+		};
+		let out = plain(&parse(src, &anns, &trimmed(false)));
+		// Reserved char is kept where the caret points at it
+		assert_eq!(out, "·   ╭── eol\n1 ab \n· ╭── c\n2 cd\n3 ef\n");
+		assert_eq!(
+			out.lines().filter(|l| l.ends_with(' ')).collect::<Vec<_>>(),
+			["1 ab "]
+		);
+		let s = parse(src, &[annotation((4, 4), "d")], &trimmed(false));
+		assert_eq!(plain(&s), "1 ab\n·  ╭── d\n2 cd\n3 ef\n");
+		assert_eq!(s.rendered_width(), "·  ╭── d".chars().count());
+		assert_eq!(
+			plain(&parse(src, &anns, &trimmed(true))),
+			"1 ab 🢒 eol\n2 cd 🢒 c\n3 ef\n"
+		);
+	}
+
+	#[test]
+	fn eof_marker() {
+		let eof = Opts {
+			inline_labels: false,
+			eof_marker: Some('∎'),
+			..opts(false)
+		};
+		let s = parse("abc\ndef", &[annotation((7, 7), "unexpected end")], &eof);
+		assert_eq!(
+			plain(&s),
+			["1 abc ", "·    ╭── unexpected end", "2 def∎", ""].join("\n")
+		);
+
+		// EOL of the line before trimmed blank lines is not the end of file
+		let s = parse("abc\n\n", &[annotation((3, 3), "eol")], &eof);
+		assert_eq!(plain(&s), ["·    ╭── eol", "1 abc ", ""].join("\n"));
+	}
+
+	#[test]
+	fn visible_whitespace() {
+		let s = parse(
+			"fn a() {\n  \tfoo();\n    bar();\n}",
+			&[annotation((9, 11), "mixed indentation")],
+			&Opts {
+				inline_labels: false,
+				whitespace: Whitespace::Annotated,
+				..opts(false)
+			},
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 fn a() { ",
+				"· ╭───── mixed indentation",
+				"· ├──╮",
+				"2 ··→·foo(); ",
+				"3     bar(); ",
+				"4 } ",
+				"",
+			]
+			.join("\n")
+		);
+		assert!(s.lines()[3].rendered().get(4).expect("in bounds").1.dim);
+	}
+
+	#[derive(Default, Clone, PartialEq, Debug)]
+	struct Sgr {
+		fg: Option<(u8, u8, u8)>,
+		bg: Option<(u8, u8, u8)>,
+		bold: bool,
+		dim: bool,
+		underline: bool,
+	}
+
+	/// Simulates SGR state over the output, returns every displayed char of the line with its attributes,
+	/// and attributes left active at the end of the line
+	fn interpret_ansi(ansi: &str) -> Vec<(Vec<(char, Sgr)>, Sgr)> {
+		let mut out = Vec::new();
+		let mut state = Sgr::default();
+		for line in ansi.lines() {
+			let mut chars = Vec::new();
+			let mut rest = line;
+			while !rest.is_empty() {
+				let Some(sgr) = rest.strip_prefix("\x1b[") else {
+					let c = rest.chars().next().expect("not empty");
+					chars.push((c, state.clone()));
+					rest = &rest[c.len_utf8()..];
+					continue;
+				};
+				let (params, after) = sgr.split_once('m').expect("only SGR is emitted");
+				rest = after;
+				let params = params
+					.split(';')
+					.map(|p| p.parse::<u8>().expect("numeric"))
+					.collect::<Vec<_>>();
+				match params[..] {
+					[0] => state = Sgr::default(),
+					[1] => state.bold = true,
+					[2] => state.dim = true,
+					[4] => state.underline = true,
+					[22] => {
+						state.bold = false;
+						state.dim = false;
+					}
+					[24] => state.underline = false,
+					[39] => state.fg = None,
+					[49] => state.bg = None,
+					[38, 2, r, g, b] => state.fg = Some((r, g, b)),
+					[48, 2, r, g, b] => state.bg = Some((r, g, b)),
+					_ => panic!("unknown SGR {params:?}"),
+				}
+			}
+			out.push((chars, state.clone()));
+		}
+		out
+	}
+
+	/// Every segment is styled on its own, and reset after
+	fn naive_ansi(source: &Source) -> String {
+		let mut out = String::new();
+		for line in source.lines() {
+			for frag in line.rendered().segments() {
+				let meta = frag.meta();
+				let mut codes = Vec::new();
+				if let Some(color) = meta.color {
+					let [r, g, b, _a] = u32::to_be_bytes(color);
+					codes.push(format!("38;2;{r};{g};{b}"));
+				}
+				if let Some(color) = meta.bg_color {
+					let [r, g, b, _a] = u32::to_be_bytes(color);
+					codes.push(format!("48;2;{r};{g};{b}"));
+				}
+				for (set, code) in [(meta.bold, "1"), (meta.underline, "4"), (meta.dim, "2")] {
+					if set {
+						codes.push(code.to_owned());
+					}
+				}
+				for code in &codes {
+					out.push_str(&format!("\x1b[{code}m"));
+				}
+				out.extend(frag.iter());
+				if !codes.is_empty() {
+					out.push_str("\x1b[0m");
+				}
+			}
+			out.push('\n');
+		}
+		out
+	}
+
+	#[test]
+	fn ansi_size() {
+		let src = (0..100)
+			.map(|i| format!("let value_{i} = compute({i}, other_{i});"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let mut snippet = SnippetBuilder::new(&src).highlight(|_, line| {
+			line.match_indices("let")
+				.map(|(i, m)| (i..i + m.len(), Formatting::PURPLE))
+				.collect()
+		});
+		let mut offset = 0;
+		for (i, line) in src.lines().enumerate() {
+			if i % 3 == 0 {
+				let start = offset + line.find("compute").expect("exists");
+				snippet
+					.warning(Text::from("call"))
+					.range(start..=start + 6)
+					.build();
+			}
+			if i % 10 == 0 {
+				let start = offset + line.find("other").expect("exists");
+				snippet
+					.error(Text::from("spans lines"))
+					.range(start..=start + 60)
+					.build();
+			}
+			offset += line.len() + 1;
+		}
+		let source = snippet.build();
+
+		let naive = naive_ansi(&source);
+		let ansi = source_to_ansi(&source);
+		let displayed = |ansi: &str| {
+			interpret_ansi(ansi)
+				.into_iter()
+				.map(|(chars, _)| chars)
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(displayed(&ansi), displayed(&naive));
+		assert!(ansi.len() < naive.len());
+
+		// Runs which only differ by invisible properties are not merged by compaction,
+		// but are emitted without escapes between them
+		let runs = Text::new((0..100).map(|i| {
+			let formatting = if i % 2 == 0 {
+				Formatting::RED
+			} else {
+				Formatting::RED.decoration()
+			};
+			Segment::new("──".chars(), formatting)
+		}));
+		let source = Source {
+			lines: vec![Line::Raw(RawLine::new(runs))],
+			warnings: Vec::new(),
+			summaries: Vec::new(),
+			paddings: Vec::new(),
+			wrap_prefixes: Vec::new(),
+			dedent: 0,
+			folded: Vec::new(),
+			line_ending: LineEnding::Lf,
+			final_line_ending: true,
+			gutter_width: 0,
+			continued_above: HashSet::new(),
+			continued_below: HashSet::new(),
+			finalized: false,
+		};
+		let naive = naive_ansi(&source);
+		let ansi = source_to_ansi(&source);
+		assert_eq!(displayed(&ansi), displayed(&naive));
+		assert!(
+			ansi.len() * 2 < naive.len(),
+			"{} is not much smaller than {}",
+			ansi.len(),
+			naive.len()
+		);
+	}
+
+	#[test]
+	fn ansi_state_is_reset() {
+		let mut snippet = SnippetBuilder::new("fn main() {\n\tlet a = 1;\n}")
+			.frame(Text::from("title"))
+			.line_prefix(2, Text::single(['+'], Formatting::color(0x00ff0000)));
+		snippet.error(Text::from("error")).range(0..=1).build();
+		snippet
+			.warning(Text::from("warning"))
+			.range(13..=24)
+			.build();
+		let ansi = source_to_ansi(&snippet.build());
+		for (i, (_, active)) in interpret_ansi(&ansi).into_iter().enumerate() {
+			assert_eq!(active, Sgr::default(), "line {i} leaves attributes active");
+		}
+	}
+
+	#[test]
+	fn gutter_without_text_lines() {
+		let mut source = Source {
+			lines: vec![
+				Line::Annotation(AnnotationLine {
+					prefix: Text::empty(),
+					line: Text::from("label"),
+					annotation: None,
+					anchor: None,
+				}),
+				Line::Gap(GapLine {
+					prefix: Text::empty(),
+					line: Text::empty(),
+					line_nums: 3..=5,
+					bytes: 0..0,
+				}),
+			],
+			warnings: Vec::new(),
+			summaries: Vec::new(),
+			paddings: Vec::new(),
+			wrap_prefixes: Vec::new(),
+			dedent: 0,
+			folded: Vec::new(),
+			line_ending: LineEnding::Lf,
+			final_line_ending: true,
+			gutter_width: 0,
+			continued_above: HashSet::new(),
+			continued_below: HashSet::new(),
+			finalized: false,
+		};
+		draw_line_numbers(&mut source, &opts(false));
+		finalize(&mut source, &opts(false));
+		assert_eq!(plain(&source), "· label\n⋮ \n");
+
+		// Formatter producing empty numbers still leaves room for the markers
+		let mut snippet =
+			SnippetBuilder::new("let a = 1;").line_number_formatter(|_| String::new());
+		snippet.error(Text::from("here")).range(4..=4).build();
+		snippet.note(Text::from("there")).range(8..=8).build();
+		assert_eq!(
+			plain(&snippet.build()),
+			"·     ╭── here\n\
+			 \x20 let a = 1; 🢒 there\n"
+		);
+	}
+
+	#[test]
+	fn rendered_line_kinds() {
+		let src = (1..=12)
+			.map(|i| format!("line {i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let mut snippet = SnippetBuilder::new(&src).frame(Text::from("title"));
+		let third = src.find("line 3").expect("exists");
+		snippet
+			.error(Text::from("whole"))
+			.range(0..=third + 5)
+			.build();
+		let second = src.find("line 2").expect("exists");
+		snippet
+			.warning(Text::from("word"))
+			.range(second..=second + 3)
+			.build();
+		snippet
+			.note(Text::from("number"))
+			.range(second + 5..=second + 5)
+			.build();
+		let last = src.find("line 12").expect("exists");
+		snippet
+			.note(Text::from("last"))
+			.range(last..=last + 3)
+			.build();
+		let source = snippet.build();
+		// Rendered by the emitter joining the lines, same as when they were joined by the pipeline
+		assert_eq!(
+			source_to_ansi(&source),
+			include_str!("../../../fixtures/snippet.ansi")
+		);
+
+		let plain = |text: &Text| text.data().collect::<String>();
+		let lines = source
+			.rendered_lines()
+			.map(|line| match line {
+				RenderedLine::Source {
+					number,
+					annotation,
+					gutter,
+					text,
+				} => format!(
+					"source {number} {annotation:?} [{}] {}",
+					plain(gutter),
+					plain(text)
+				),
+				RenderedLine::Continuation {
+					number,
+					gutter,
+					text,
+				} => format!("continuation {number} [{}] {}", plain(gutter), plain(text)),
+				RenderedLine::Label {
+					annotation,
+					gutter,
+					text,
+				} => format!("label {annotation:?} [{}] {}", plain(gutter), plain(text)),
+				RenderedLine::Gap {
+					folded,
+					bytes,
+					gutter,
+					text,
+				} => format!(
+					"gap {folded:?} {bytes:?} [{}] {}",
+					plain(gutter),
+					plain(text)
+				),
+				RenderedLine::Raw { text } => format!("raw {}", plain(text)),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			lines,
+			[
+				"raw ╭─ title ──────────────╮",
+				"source 1 Some(0) [│  1 ] ╭─line 1          │",
+				"label None [│  · ] │ ╭───── word     │",
+				"source 2 None [│  2 ] │ line 2 🢒 number │",
+				"source 3 Some(0) [│  3 ] ╰─line 3 🢒 whole  │",
+				"source 4 None [│  4 ]   line 4          │",
+				"source 5 None [│  5 ]   line 5          │",
+				"gap 6..=9 35..63 [│  ⋮ ]                   │",
+				"source 10 None [│ 10 ]   line 10         │",
+				"source 11 None [│ 11 ]   line 11         │",
+				"source 12 None [│ 12 ]   line 12 🢒 last  │",
+				"raw ╰──────────────────────╯",
+			]
+		);
+	}
+
+	#[test]
+	fn without_decoration() {
+		let source_lines = |source: &Source| {
+			source
+				.rendered_lines()
+				.filter_map(|line| match line {
+					RenderedLine::Source { text, .. } => {
+						let text = text.without_decoration().data().collect::<String>();
+						// Only the reserved EOL char is left after the source text
+						Some(text.trim_end().to_owned())
+					}
+					_ => None,
+				})
+				.collect::<Vec<_>>()
+		};
+		let src = "fn main() {\n    let a = 1;\n}";
+		let source = parse(src, &[annotation((0, 27), "body")], &opts(false));
+		assert_eq!(source_lines(&source), src.lines().collect::<Vec<_>>());
+		// Connectors are still there before stripping
+		assert!(plain(&source).contains("1 │ fn main() {"));
+
+		let mut snippet = SnippetBuilder::new("    let a = 1;").frame(Text::from("title"));
+		snippet.error(Text::from("var")).range(8..=8).build();
+		let source = snippet.build();
+		assert_eq!(source_lines(&source), ["    let a = 1;"]);
+		// Inline label and frame are still there before stripping
+		assert_eq!(
+			plain(&source),
+			"╭─ title ────────────────╮\n\
+			 │ 1     let a = 1; 🢒 var │\n\
+			 ╰────────────────────────╯\n"
+		);
+	}
+
+	#[test]
+	fn render_window() {
+		let src = (1..=20)
+			.map(|i| format!("line {i}"))
+			.collect::<Vec<_>>()
+			.join("\n");
+		let mut snippet = SnippetBuilder::new(&src);
+		let start = src.find("line 3").expect("exists");
+		let end = src.find("line 8").expect("exists");
+		snippet
+			.error(Text::from("long"))
+			.range(start..=end + 5)
+			.build();
+		let last = src.find("line 20").expect("exists");
+		snippet
+			.note(Text::from("last"))
+			.range(last..=last + 3)
+			.build();
+		let source = snippet.build();
+		let window = plain(&source.render_window(5, 5));
+		assert_eq!(
+			window,
+			" 6 │ line 6 \n\
+			 \x207 │ line 7 \n\
+			 \x208 ╰─line 8 🢒 long\n\
+			 \x209   line 9 \n\
+			 10   line 10 \n"
+		);
+		// Same lines as in the full snippet, with the gutter of the widest line number
+		let full = plain(&source);
+		assert_eq!(
+			window.lines().collect::<Vec<_>>(),
+			full.lines().skip(5).take(5).collect::<Vec<_>>()
+		);
+		assert_eq!(plain(&source.render_window(25, 5)), "");
+	}
+
+	#[test]
+	fn rendered_lines_are_compact() {
+		let src = (0..50)
+			.map(|i| format!("let value_{i} = compute({i}, \"{}\");\n", "x".repeat(i % 7)))
+			.collect::<String>();
+		let mut snippet = SnippetBuilder::new(&src).frame(Text::from("title"));
+		for i in 0..50 {
+			let start = src.match_indices("compute").nth(i).expect("line exists").0;
+			snippet
+				.error(Text::from(format!("call {i}").as_str()))
+				.range(start..=start + 6)
+				.build();
+		}
+		let source = snippet.build();
+		let mut segments = 0;
+		for line in source.lines() {
+			let data = line.rendered();
+			let mut compacted = data.clone().into_owned();
+			compacted.compact();
+			assert_eq!(
+				data.segments().count(),
+				compacted.segments().count(),
+				"{data:?}"
+			);
+			segments += data.segments().count();
+		}
+		assert!(segments < source.lines().len() * 12, "{segments} segments");
+	}
+
+	#[test]
+	fn line_endings() {
+		let build = |mut snippet: SnippetBuilder| {
+			snippet.error(Text::from("here")).range(4..=4).build();
+			snippet.build()
+		};
+		let source =
+			build(SnippetBuilder::new("let a = 1;\nlet b = 2;").line_ending(LineEnding::CrLf));
+		assert_eq!(plain(&source), "1 let a = 1; 🢒 here\r\n2 let b = 2; \r\n");
+		assert!(source_to_ansi(&source).ends_with("\r\n"));
+		assert_eq!(source_to_ansi(&source).matches("\r\n").count(), 2);
+
+		let source = build(
+			SnippetBuilder::new("let a = 1;\nlet b = 2;")
+				.line_ending(LineEnding::CrLf)
+				.final_line_ending(false),
+		);
+		assert_eq!(plain(&source), "1 let a = 1; 🢒 here\r\n2 let b = 2; ");
+		let mut written = Vec::new();
+		source.write_ansi(&mut written).expect("vec write");
+		assert!(!written.ends_with(b"\n"));
+	}
+
+	#[test]
+	fn display_paths_agree() {
+		let mut snippet = SnippetBuilder::new("let a = 1;\nlet b = 2;");
+		snippet.error(Text::from("error")).range(4..=4).build();
+		snippet.note(Text::from("note")).range(15..=15).build();
+		let source = snippet.build();
+
+		let mut written = Vec::new();
+		source.write_ansi(&mut written).expect("vec write");
+		assert_eq!(
+			String::from_utf8(written).expect("utf-8"),
+			source_to_ansi(&source)
+		);
+		assert_eq!(source.to_string(), source.plain().to_string());
+		let stripped = interpret_ansi(&source_to_ansi(&source))
+			.into_iter()
+			.map(|(chars, _)| chars.into_iter().map(|(c, _)| c).collect::<String>() + "\n")
+			.collect::<String>();
+		assert_eq!(source.to_string(), stripped);
+	}
+
+	#[test]
+	fn ansi_minimal_escapes() {
+		let red = Formatting::color(0xff000000);
+		let text = Text::new([
+			Segment::new("ab".chars(), red.clone()),
+			Segment::new("cd".chars(), red.clone().decoration()),
+			Segment::new("ef".chars(), Formatting::default()),
+		]);
+		let mut out = String::new();
+		formatting::text_to_ansi(&text, &mut out);
+		assert_eq!(out, "\x1b[38;2;255;0;0mabcd\x1b[0mef");
+	}
+
+	#[test]
+	fn from_ansi_colors() {
+		let text = Text::from_ansi(
+			"\x1b[31ma\x1b[1;92mb\x1b[38;5;196mc\x1b[38;2;1;2;3;48;5;240md\x1b[22;39;49me\x1b[mf",
+		);
+		let bold = |f: Formatting| Formatting { bold: true, ..f };
+		assert_eq!(
+			text.chars_with_format()
+				.map(|(c, f)| (c, f.clone()))
+				.collect::<Vec<_>>(),
+			[
+				('a', Formatting::color(0xcd000000)),
+				('b', bold(Formatting::color(0x00ff0000))),
+				('c', bold(Formatting::color(0xff000000))),
+				(
+					'd',
+					bold(Formatting {
+						bg_color: Some(0x58585800),
+						..Formatting::color(0x01020300)
+					})
+				),
+				('e', Formatting::default()),
+				('f', Formatting::default()),
+			],
+		);
+	}
+
+	#[test]
+	fn from_ansi_strips_unknown() {
+		let text = Text::from_ansi(
+			"\x1b[2Ka\x1b]8;;https://example.com\x1b\\b\x1b]8;;\x07c\x1b[3md\x1b(Be\x1b",
+		);
+		assert_eq!(text.data().collect::<String>(), "abcde");
+		assert!(text.segments().all(|s| *s.meta() == Formatting::default()));
+	}
+
+	#[test]
+	fn from_ansi_offsets() {
+		let raw = "\x1b[1;31merror\x1b[0m: unused \x1b[33mfoo\x1b[0m\nnext";
+		let (text, offsets) = Text::from_ansi_with_offsets(raw);
+		let src = text.data().collect::<String>();
+		assert_eq!(src, "error: unused foo\nnext");
+
+		let foo = raw.find("foo").expect("exists");
+		let range = offsets.range(foo..=foo + 2);
+		assert_eq!(&src[range.clone()], "foo");
+		// Escape sequence is mapped to the following visible char
+		assert_eq!(
+			offsets.offset(raw.find("\x1b[0m\n").expect("exists")),
+			src.find('\n').expect("exists")
+		);
+		assert_eq!(offsets.offset(raw.len()), src.len());
+
+		let mut snippet = SnippetBuilder::styled(text);
+		snippet.error(Text::from("here")).range(range).build();
+		let out = snippet.build().plain().to_string();
+		assert!(out.contains("here"));
+	}
+
+	#[test]
+	fn from_ansi_roundtrip() {
+		let text = Text::build()
+			.str("plain ")
+			.styled("red", Formatting::color(0xff000000))
+			.styled(
+				" bold on gray",
+				Formatting {
+					bold: true,
+					bg_color: Some(0x28282800),
+					..Formatting::color(0x83a59800)
+				},
+			)
+			.styled(
+				" dim underline",
+				Formatting {
+					dim: true,
+					underline: true,
+					..Default::default()
+				},
+			)
+			.str(" tail")
+			.finish();
+		let mut ansi = String::new();
+		formatting::text_to_ansi(&text, &mut ansi);
+		let parsed = Text::from_ansi(&ansi);
+		assert_eq!(
+			parsed.chars_with_format().collect::<Vec<_>>(),
+			text.chars_with_format().collect::<Vec<_>>(),
+		);
+
+		let mut again = String::new();
+		formatting::text_to_ansi(&parsed, &mut again);
+		assert_eq!(again, ansi);
+	}
+
+	#[test]
+	fn secondary_is_faint() {
+		let mut snippet = SnippetBuilder::new("let a = b + c;").palette(Palette::ColorBlind);
+		snippet.error(Text::from("primary")).range(8..=8).build();
+		// Errors are bold in this palette, unless they are secondary
+		snippet
+			.error(Text::from("secondary"))
+			.range(12..=12)
+			.secondary()
+			.build();
+		let lines = interpret_ansi(&snippet.build().display_ansi().to_string());
+		let label = |text: &str| {
+			lines
+				.iter()
+				.find_map(|(chars, _)| {
+					let line = chars.iter().map(|(c, _)| *c).collect::<String>();
+					let pos = line.find(text)?;
+					Some(chars[line[..pos].chars().count()].1.clone())
+				})
+				.expect("label is displayed")
+		};
+		assert!(!label("primary").dim);
+		assert!(label("secondary").dim);
+
+		let (chars, _) = &lines[1];
+		let source = |c: char| {
+			chars
+				.iter()
+				.find(|(ch, _)| *ch == c)
+				.expect("displayed")
+				.1
+				.clone()
+		};
+		let secondary = source('c');
+		assert!(secondary.dim && !secondary.bold);
+		let primary = source('b');
+		assert!(primary.bold && !primary.dim);
+	}
+
+	#[test]
+	fn distant_annotations_are_not_folded() {
+		let src = (1..=12).map(|i| format!("line {i}")).collect::<Vec<_>>();
+		let src = src.join("\n");
+		let second = src.find("line 6").expect("exists");
+		let s = parse(
+			&src,
+			&[
+				annotation((0, 3), "primary"),
+				annotation((second, second + 3), "secondary"),
+			],
+			&Opts {
+				inline_labels: false,
+				..opts(false)
+			},
+		);
+		let out = plain(&s);
+		assert!(out.contains("secondary"));
+		assert!(out.lines().any(|l| l == "6 line 6 "));
+		// Untouched tail is folded
+		assert!(!out.contains("line 12"));
+	}
+
+	#[test]
+	fn right_anchored_label() {
+		let right = |range, text| Annotation {
+			label_anchor: LabelAnchor::RightMargin,
+			..annotation(range, text)
+		};
+		let wide = Opts {
+			max_width: Some(80),
+			..opts(true)
+		};
+		let s = parse("let a = b + c;", &[right((4, 4), "note: here")], &wide);
+		assert_eq!(
+			plain(&s),
+			format!("·     ╭{} note: here\n1 let a = b + c; \n", "─".repeat(62))
+		);
+		assert_eq!(s.rendered_width(), 80);
+
+		// Overlapping labels are stacked, continuation rows are aligned with the first one
+		let s = parse(
+			"let a = b + c;",
+			&[
+				right((4, 4), "defined"),
+				right((8, 12), "first\nsecond row"),
+				annotation((0, 2), "keyword"),
+			],
+			&wide,
+		);
+		assert_eq!(
+			plain(&s),
+			[
+				format!("·     ╭{} defined", "─".repeat(65)),
+				format!("·     │   ╭{} first", "─".repeat(58)),
+				format!("·     │   │{}second row", " ".repeat(59)),
+				"1 let a = b + c; 🢒 keyword".to_owned(),
+				String::new(),
+			]
+			.join("\n")
+		);
+
+		assert_eq!(
+			try_parse("a", &[right((0, 0), "label")], &opts(true)).err(),
+			Some(AnnotationError::NoMaxWidth { annotation: 0 })
+		);
+		assert_eq!(
+			opts(true).validate_annotation(1, &right((0, 0), "label")),
+			Err(AnnotationError::NoMaxWidth { annotation: 1 })
+		);
+		assert_eq!(wide.validate_annotation(1, &right((0, 0), "label")), Ok(()));
+	}
+
+	#[test]
+	fn wrap() {
+		let src = "fn main() {\n\tlet value = first_argument + second_argument;\n}";
+		let wrapped = Opts {
+			max_width: Some(24),
+			wrap: true,
+			..opts(true)
+		};
+		let s = parse(src, &[annotation((13, 58), "whole block")], &wrapped);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 fn main() { ",
+				"2     let value = first_",
+				"↳ argument + second_argu",
+				"↳ ment; 🢒 whole block",
+				"3 } ",
+				"",
+			]
+			.join("\n")
+		);
+
+		// Connector of the multi-line annotation is continued through the wrapped rows
+		let s = parse(src, &[annotation((10, 60), "body")], &wrapped);
+		assert_eq!(
+			plain(&s),
+			[
+				"1 ╭─fn main() { ",
+				"2 │     let value = firs",
+				"↳ │ t_argument + second_",
+				"↳ │ argument; ",
+				"3 ╰─} 🢒 body",
+				"",
+			]
+			.join("\n")
+		);
+		assert!(matches!(
+			s.rendered_lines().nth(2),
+			Some(RenderedLine::Continuation { number: 2, .. })
+		));
+	}
 }
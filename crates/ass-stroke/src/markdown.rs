@@ -0,0 +1,176 @@
+//! Markdown output, i.e for posting diagnostics into issues or chats
+
+use std::fmt;
+
+use crate::{Severity, Source};
+
+/// Rendered snippet as a fenced code block, see [`Source::markdown`]
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayMarkdown<'s> {
+	source: &'s Source,
+	language: &'s str,
+	header: Option<(Option<Severity>, &'s str)>,
+}
+impl<'s> DisplayMarkdown<'s> {
+	/// Info string of the fence, i.e `text`, no language is specified by default
+	///
+	/// # Panics
+	/// If the language contains backticks or line breaks, as they would end the info string
+	pub fn language(mut self, language: &'s str) -> Self {
+		assert!(
+			!language.contains(['`', '\n', '\r']),
+			"backtick or line break in the fence language"
+		);
+		self.language = language;
+		self
+	}
+	/// Bold line displayed before the code block, prefixed with the severity emoji
+	pub fn header(mut self, severity: Option<Severity>, header: &'s str) -> Self {
+		self.header = Some((severity, header));
+		self
+	}
+}
+
+fn emoji(severity: Severity) -> &'static str {
+	match severity {
+		Severity::Error => "❌",
+		Severity::Warning => "⚠️",
+		Severity::Note => "📝",
+		Severity::Info => "ℹ️",
+	}
+}
+
+/// Backslash-escape chars which have meaning in inline markdown
+fn escape(text: &str, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+	for c in text.chars() {
+		if matches!(
+			c,
+			'\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '!' | '|' | '~'
+		) {
+			out.write_str("\\")?;
+		}
+		write!(out, "{c}")?;
+	}
+	Ok(())
+}
+
+/// Fence is longer than any backtick run of the contents, so labels can't close it
+fn fence(contents: &str) -> String {
+	let mut longest = 0;
+	let mut run = 0;
+	for c in contents.chars() {
+		run = if c == '`' { run + 1 } else { 0 };
+		longest = longest.max(run);
+	}
+	"`".repeat((longest + 1).max(3))
+}
+
+impl fmt::Display for DisplayMarkdown<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let eol = self.source.line_ending.as_str();
+		if let Some((severity, header)) = self.header {
+			if let Some(severity) = severity {
+				write!(f, "{} ", emoji(severity))?;
+			}
+			f.write_str("**")?;
+			escape(header, f)?;
+			write!(f, "**{eol}{eol}")?;
+		}
+		let contents = self.source.plain().to_string();
+		let fence = fence(&contents);
+		write!(f, "{fence}{}{eol}", self.language)?;
+		f.write_str(&contents)?;
+		if !contents.ends_with('\n') {
+			f.write_str(eol)?;
+		}
+		write!(f, "{fence}{eol}")
+	}
+}
+
+impl Source {
+	/// Display snippet as a markdown code block, plain text is used, so the alignment is kept
+	///
+	/// ```
+	/// use ass_stroke::{Severity, SnippetBuilder, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let source = snippet.build();
+	/// assert_eq!(
+	///     source.markdown().language("text").header(Some(Severity::Error), "unused_variables").to_string(),
+	///     "❌ **unused\\_variables**\n\n```text\n1 let a = 1; 🢒 here\n```\n",
+	/// );
+	/// ```
+	pub fn markdown(&self) -> DisplayMarkdown<'_> {
+		DisplayMarkdown {
+			source: self,
+			language: "",
+			header: None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{LineEnding, Severity, SnippetBuilder, Text};
+
+	#[test]
+	fn backticks_in_labels() {
+		let mut snippet = SnippetBuilder::new("let a = 1;");
+		snippet
+			.error(Text::from("wrap it in ```rust"))
+			.range(4..=4)
+			.build();
+		let source = snippet.build();
+		assert_eq!(
+			source.markdown().language("rust").to_string(),
+			"````rust\n1 let a = 1; 🢒 wrap it in ```rust\n````\n"
+		);
+	}
+
+	#[test]
+	fn header_escaping() {
+		let mut snippet = SnippetBuilder::new("let a = 1;");
+		snippet.warning(Text::from("here")).range(4..=4).build();
+		let source = snippet.build();
+		let markdown = source
+			.markdown()
+			.header(Some(Severity::Warning), "expected `i32`, found *str*")
+			.to_string();
+		assert!(markdown.starts_with("⚠️ **expected \\`i32\\`, found \\*str\\***\n\n```\n"));
+		let markdown = source.markdown().header(None, "plain").to_string();
+		assert!(markdown.starts_with("**plain**\n\n```\n"));
+	}
+
+	#[test]
+	fn without_final_line_ending() {
+		let mut snippet = SnippetBuilder::new("let a = 1;").final_line_ending(false);
+		snippet.note(Text::from("here")).range(4..=4).build();
+		assert_eq!(
+			snippet.build().markdown().to_string(),
+			"```\n1 let a = 1; 🢒 here\n```\n"
+		);
+	}
+
+	#[test]
+	fn crlf() {
+		let mut snippet = SnippetBuilder::new("let a = 1;").line_ending(LineEnding::CrLf);
+		snippet.note(Text::from("here")).range(4..=4).build();
+		assert_eq!(
+			snippet
+				.build()
+				.markdown()
+				.header(None, "title")
+				.language("text")
+				.to_string(),
+			"**title**\r\n\r\n```text\r\n1 let a = 1; 🢒 here\r\n```\r\n"
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "backtick or line break in the fence language")]
+	fn backtick_in_language() {
+		let snippet = SnippetBuilder::new("let a = 1;");
+		let _ = snippet.build().markdown().language("rust```");
+	}
+}
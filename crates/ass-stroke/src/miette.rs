@@ -0,0 +1,211 @@
+//! [`miette`] report handler, which draws labels of the diagnostics using [`SnippetBuilder`]
+//!
+//! ```
+//! use ass_stroke::miette::SnippetHandler;
+//! use miette::{LabeledSpan, MietteDiagnostic, Report};
+//!
+//! let diagnostic = MietteDiagnostic::new("mismatched types")
+//!     .with_labels([LabeledSpan::new_primary_with_span(Some("expected i32".into()), (13, 5))]);
+//! let report = Report::new(diagnostic).with_source_code("let a: i32 = \"abc\";");
+//!
+//! let rendered = SnippetHandler::plain().display(report.as_ref()).to_string();
+//! assert!(rendered.starts_with("error: mismatched types"));
+//! assert!(rendered.contains("expected i32"));
+//! ```
+
+use std::fmt;
+
+use ::miette::{Diagnostic, ReportHandler, Severity};
+
+use crate::{AnnotationError, Palette, SnippetBuilder, Source, Text};
+
+/// Renders the diagnostic header, the source with every labeled span annotated, and the help message.
+///
+/// Primary label is displayed with the diagnostic severity, the other labels are displayed as infos
+#[derive(Clone, Copy, Debug)]
+pub struct SnippetHandler {
+	palette: Palette,
+	ansi: bool,
+}
+impl SnippetHandler {
+	/// Colored output
+	pub fn new() -> Self {
+		Self {
+			palette: Palette::Default,
+			ansi: true,
+		}
+	}
+	/// Output without any escape sequences
+	pub fn plain() -> Self {
+		Self {
+			ansi: false,
+			..Self::new()
+		}
+	}
+	pub fn palette(mut self, palette: Palette) -> Self {
+		self.palette = palette;
+		self
+	}
+	/// Install this handler as the global [`miette`] hook
+	pub fn install(self) -> Result<(), ::miette::InstallError> {
+		::miette::set_hook(Box::new(move |_| Box::new(self)))
+	}
+
+	/// Annotated source of the diagnostic, `None` if it has no source code or labels
+	pub fn render(&self, diagnostic: &dyn Diagnostic) -> Option<Result<Source, AnnotationError>> {
+		let source = diagnostic.source_code()?;
+		let labels = diagnostic.labels()?.collect::<Vec<_>>();
+		let contents = source.read_span(&(0, 0).into(), 0, usize::MAX).ok()?;
+		let text = String::from_utf8_lossy(contents.data());
+
+		let mut snippet = SnippetBuilder::new(&text).palette(self.palette);
+		// With no label marked as primary, the first one is considered primary
+		let primary = labels.iter().position(|l| l.primary()).unwrap_or_default();
+		for (i, label) in labels.iter().enumerate() {
+			let label_text = Text::from(label.label().unwrap_or_default());
+			let builder = if i == primary {
+				match severity(diagnostic) {
+					Severity::Error => snippet.error(label_text),
+					Severity::Warning => snippet.warning(label_text),
+					Severity::Advice => snippet.note(label_text),
+				}
+				.priority(1)
+			} else {
+				snippet.info(label_text)
+			};
+			let start = label.offset();
+			let end = start + label.len().max(1) - 1;
+			if end > text.len() {
+				// Span doesn't belong to this source
+				continue;
+			}
+			builder.range(start..=end).build();
+		}
+		Some(snippet.try_build())
+	}
+	/// [`fmt::Display`] adapter, which renders the diagnostic using this handler
+	pub fn display<'d>(&'d self, diagnostic: &'d dyn Diagnostic) -> impl fmt::Display + 'd {
+		struct Display<'d>(&'d SnippetHandler, &'d dyn Diagnostic);
+		impl fmt::Display for Display<'_> {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				self.0.debug(self.1, f)
+			}
+		}
+		Display(self, diagnostic)
+	}
+
+	fn write_text(&self, text: &Text, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.ansi {
+			let mut out = String::new();
+			crate::formatting::text_to_ansi(text, &mut out);
+			write!(f, "{out}")
+		} else {
+			write!(f, "{}", text.data().collect::<String>())
+		}
+	}
+}
+impl Default for SnippetHandler {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+fn severity(diagnostic: &dyn Diagnostic) -> Severity {
+	diagnostic.severity().unwrap_or(Severity::Error)
+}
+
+impl ReportHandler for SnippetHandler {
+	fn debug(&self, diagnostic: &dyn Diagnostic, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let (name, severity) = match severity(diagnostic) {
+			Severity::Error => ("error", crate::Severity::Error),
+			Severity::Warning => ("warning", crate::Severity::Warning),
+			Severity::Advice => ("advice", crate::Severity::Note),
+		};
+		let formatting = self.palette.formatting(severity);
+		let mut header = Text::from_str(name, formatting.clone());
+		if let Some(code) = diagnostic.code() {
+			header.append_styled(&format!("[{code}]"), formatting);
+		}
+		header.push_str(&format!(": {diagnostic}"));
+		self.write_text(&header, f)?;
+		writeln!(f)?;
+
+		match self.render(diagnostic) {
+			Some(Ok(source)) => {
+				if self.ansi {
+					write!(f, "{}", source.display_ansi())?;
+				} else {
+					write!(f, "{}", source.plain())?;
+				}
+			}
+			// Failing here would panic in `to_string`, so the error is reported instead of the source
+			Some(Err(e)) => {
+				let mut error_text =
+					Text::from_str("note", self.palette.formatting(crate::Severity::Note));
+				error_text.push_str(&format!(": labels can't be displayed, {e}"));
+				self.write_text(&error_text, f)?;
+				writeln!(f)?;
+			}
+			None => {}
+		}
+
+		if let Some(help) = diagnostic.help() {
+			let mut help_text =
+				Text::from_str("help", self.palette.formatting(crate::Severity::Info));
+			help_text.push_str(&format!(": {help}"));
+			self.write_text(&help_text, f)?;
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ::miette::{LabeledSpan, MietteDiagnostic, Report, Severity};
+
+	use super::SnippetHandler;
+	use crate::Palette;
+
+	#[test]
+	fn two_labels() {
+		let src = "fn main() {\n    let a: i32 = \"abc\";\n}\n";
+		let diagnostic = MietteDiagnostic::new("mismatched types")
+			.with_severity(Severity::Warning)
+			.with_help("try parsing the string")
+			.with_labels([
+				LabeledSpan::new_primary_with_span(Some("expected String".into()), (29, 5)),
+				LabeledSpan::new_with_span(Some("expected due to this".into()), (23, 3)),
+			]);
+		let report = Report::new(diagnostic).with_source_code(src);
+
+		let handler = SnippetHandler::plain();
+		let source = handler
+			.render(report.as_ref())
+			.expect("has labels")
+			.expect("valid annotations");
+		let summaries = source.summaries();
+		assert_eq!(summaries.len(), 2);
+
+		let rendered = handler.display(report.as_ref()).to_string();
+		assert_eq!(
+			rendered,
+			"warning: mismatched types\n\
+			 1 fn main() { \n\
+			 ·            ╭──── expected due to this\n\
+			 2     let a: i32 = \"abc\"; 🢒 expected String\n\
+			 3 } \n\
+			 help: try parsing the string\n"
+		);
+
+		let colored = SnippetHandler::new().display(report.as_ref()).to_string();
+		assert!(colored.starts_with("\x1b[38;2;254;128;25mwarning\x1b[0m: mismatched types"));
+
+		// Header has the same hue as the primary label
+		let colored = SnippetHandler::new()
+			.palette(Palette::ColorBlind)
+			.display(report.as_ref())
+			.to_string();
+		assert!(colored.starts_with("\x1b[38;2;250;189;47mwarning\x1b[0m: mismatched types"));
+	}
+}
@@ -0,0 +1,299 @@
+//! Rendering of the parsed source is split into stages, every stage is a pass over all the lines.
+//!
+//! Lines keep their structure until the end, the gutter is drawn into the line prefixes,
+//! and is joined with the text only by the outputs, see [`crate::Line::rendered`].
+
+use std::{any::Any, collections::HashMap, fmt};
+
+use crate::{
+	annotation::{AnnotationId, ConnectorColumn, Opts, Whitespace},
+	Formatting, Source,
+};
+
+/// Data shared by all the stages of a single render
+#[derive(Debug)]
+pub struct Ctx<'a> {
+	pub(crate) opts: &'a Opts,
+	pub(crate) annotation_formats: HashMap<AnnotationId, Formatting>,
+	/// Annotations with [`ConnectorColumn`] other than [`ConnectorColumn::Auto`]
+	pub(crate) connector_columns: HashMap<AnnotationId, ConnectorColumn>,
+}
+impl Ctx<'_> {
+	pub fn opts(&self) -> &Opts {
+		self.opts
+	}
+	/// Formatting of the annotation, by its index in order of addition
+	pub fn annotation_formatting(&self, annotation: usize) -> Option<&Formatting> {
+		self.annotation_formats.get(&AnnotationId(annotation))
+	}
+}
+
+/// Stages are shared between threads along with [`crate::Opts`]
+pub trait Stage: Any + Send + Sync {
+	fn run(&self, source: &mut Source, ctx: &Ctx);
+	/// Displayed in [`Pipeline`] debug output
+	fn name(&self) -> &'static str {
+		std::any::type_name::<Self>()
+	}
+}
+
+/// Ordered list of stages, the default one is used when no other is set with [`crate::SnippetBuilder::pipeline`]
+///
+/// ```
+/// use ass_stroke::{pipeline::{Ctx, LineNumbers, Pipeline, Stage}, Source};
+///
+/// struct Nothing;
+/// impl Stage for Nothing {
+///     fn run(&self, _source: &mut Source, _ctx: &Ctx) {}
+/// }
+///
+/// let mut pipeline = Pipeline::default();
+/// assert!(pipeline.insert_after::<LineNumbers>(Nothing));
+/// ```
+pub struct Pipeline {
+	stages: Vec<Box<dyn Stage>>,
+}
+impl Pipeline {
+	/// Pipeline without any stages
+	pub fn new() -> Self {
+		Self { stages: Vec::new() }
+	}
+	pub fn push(&mut self, stage: impl Stage) {
+		self.stages.push(Box::new(stage));
+	}
+	fn position<S: Stage>(&self) -> Option<usize> {
+		self.stages
+			.iter()
+			.position(|s| Any::type_id(s.as_ref()) == std::any::TypeId::of::<S>())
+	}
+	/// Insert stage after the first stage of type `S`, returns `false` if there is no such stage
+	pub fn insert_after<S: Stage>(&mut self, stage: impl Stage) -> bool {
+		let Some(i) = self.position::<S>() else {
+			return false;
+		};
+		self.stages.insert(i + 1, Box::new(stage));
+		true
+	}
+	/// Insert stage before the first stage of type `S`, returns `false` if there is no such stage
+	pub fn insert_before<S: Stage>(&mut self, stage: impl Stage) -> bool {
+		let Some(i) = self.position::<S>() else {
+			return false;
+		};
+		self.stages.insert(i, Box::new(stage));
+		true
+	}
+	/// Replace the first stage of type `S`, returns `false` if there is no such stage
+	pub fn replace<S: Stage>(&mut self, stage: impl Stage) -> bool {
+		let Some(i) = self.position::<S>() else {
+			return false;
+		};
+		self.stages[i] = Box::new(stage);
+		true
+	}
+	pub fn run(&self, source: &mut Source, ctx: &Ctx) {
+		for stage in &self.stages {
+			stage.run(source, ctx);
+		}
+	}
+}
+/// Names of the stages in order
+impl fmt::Debug for Pipeline {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list()
+			.entries(self.stages.iter().map(|s| s.name()))
+			.finish()
+	}
+}
+impl Default for Pipeline {
+	fn default() -> Self {
+		let mut pipeline = Self::new();
+		pipeline.push(Cleanup);
+		pipeline.push(Fold);
+		pipeline.push(Highlight);
+		pipeline.push(VisibleWhitespace);
+		pipeline.push(GenerateAnnotations);
+		pipeline.push(ApplyAnnotations);
+		pipeline.push(Dedent);
+		pipeline.push(LineConnections);
+		pipeline.push(LineNumbers);
+		pipeline.push(LinePrefixes);
+		pipeline.push(LeftMargin);
+		pipeline.push(RightAnchoredLabels);
+		pipeline.push(ColumnRuler);
+		pipeline.push(Finalize);
+		pipeline.push(Wrap);
+		pipeline.push(Cleanup);
+		pipeline.push(Frame);
+		pipeline
+	}
+}
+
+/// Remove blank lines from the snippet edges, and merge consecutive gaps
+#[derive(Clone, Copy, Debug)]
+pub struct Cleanup;
+impl Stage for Cleanup {
+	fn run(&self, source: &mut Source, _ctx: &Ctx) {
+		crate::cleanup(source)
+	}
+}
+
+/// Make gaps in files, with [`Opts::fold`]
+#[derive(Clone, Copy, Debug)]
+pub struct Fold;
+impl Stage for Fold {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if ctx.opts.fold.enabled {
+			crate::fold(source, ctx.opts)
+		}
+	}
+}
+
+/// Apply [`Opts::highlight`], after [`Fold`], so the folded lines are never highlighted
+#[derive(Clone, Copy, Debug)]
+pub struct Highlight;
+impl Stage for Highlight {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if let Some(highlight) = &ctx.opts.highlight {
+			crate::highlight_lines(source, highlight.as_ref());
+		}
+	}
+}
+
+/// Apply [`Opts::whitespace`]
+#[derive(Clone, Copy, Debug)]
+pub struct VisibleWhitespace;
+impl Stage for VisibleWhitespace {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		match ctx.opts.whitespace {
+			Whitespace::Hidden => {}
+			Whitespace::Trailing(glyph) => crate::mark_trailing_whitespace(source, glyph),
+			Whitespace::TrailingTouched => crate::show_trailing_whitespace(source, ctx.opts),
+			Whitespace::Annotated => crate::show_visible_whitespace(source),
+		}
+	}
+}
+
+/// Format inline annotations, and generate annotation buffers for every line
+#[derive(Clone, Copy, Debug)]
+pub struct GenerateAnnotations;
+impl Stage for GenerateAnnotations {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		crate::generate_annotations(source, ctx.opts)
+	}
+}
+
+/// Expand annotation buffers into the separate lines
+#[derive(Clone, Copy, Debug)]
+pub struct ApplyAnnotations;
+impl Stage for ApplyAnnotations {
+	fn run(&self, source: &mut Source, _ctx: &Ctx) {
+		crate::apply_annotations(source)
+	}
+}
+
+/// Apply [`Opts::dedent`], should run after [`ApplyAnnotations`], and before [`LineConnections`]
+#[derive(Clone, Copy, Debug)]
+pub struct Dedent;
+impl Stage for Dedent {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if ctx.opts.dedent {
+			crate::dedent(source);
+		}
+	}
+}
+
+/// Connect annotation lines of the multi-line annotations
+#[derive(Clone, Copy, Debug)]
+pub struct LineConnections;
+impl Stage for LineConnections {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		source.paddings =
+			crate::draw_line_connections(source, &ctx.annotation_formats, &ctx.connector_columns);
+	}
+}
+
+/// Draw the gutter
+#[derive(Clone, Copy, Debug)]
+pub struct LineNumbers;
+impl Stage for LineNumbers {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		crate::draw_line_numbers(source, ctx.opts)
+	}
+}
+
+/// Apply [`Opts::line_prefixes`], should run after [`LineNumbers`]
+#[derive(Clone, Copy, Debug)]
+pub struct LinePrefixes;
+impl Stage for LinePrefixes {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if !ctx.opts.line_prefixes.is_empty() {
+			crate::draw_line_prefixes(source, ctx.opts);
+		}
+	}
+}
+
+/// Apply [`Opts::left_margin`]
+#[derive(Clone, Copy, Debug)]
+pub struct LeftMargin;
+impl Stage for LeftMargin {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if ctx.opts.left_margin != 0 {
+			crate::apply_left_margin(source, ctx.opts);
+		}
+	}
+}
+
+/// Move labels with [`crate::LabelAnchor::RightMargin`] to [`Opts::max_width`], should run after the gutter and margin are drawn
+#[derive(Clone, Copy, Debug)]
+pub struct RightAnchoredLabels;
+impl Stage for RightAnchoredLabels {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if let Some(max_width) = ctx.opts.max_width {
+			crate::anchor_labels_right(source, max_width);
+		}
+	}
+}
+
+/// Apply [`Opts::column_ruler`], should run after [`LineConnections`]
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnRuler;
+impl Stage for ColumnRuler {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if ctx.opts.column_ruler {
+			let paddings = std::mem::take(&mut source.paddings);
+			crate::draw_column_ruler(source, &paddings, ctx.opts);
+			source.paddings = paddings;
+		}
+	}
+}
+
+/// Apply [`Opts::trim_eol`], and collect [`Source::folded`], after it the lines are only wrapped by [`Frame`]
+#[derive(Clone, Copy, Debug)]
+pub struct Finalize;
+impl Stage for Finalize {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		crate::finalize(source, ctx.opts)
+	}
+}
+
+/// Apply [`Opts::wrap`], should run after [`Finalize`], so the trimmed lines are wrapped with their final gutter
+#[derive(Clone, Copy, Debug)]
+pub struct Wrap;
+impl Stage for Wrap {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if let (true, Some(max_width)) = (ctx.opts.wrap, ctx.opts.max_width) {
+			crate::wrap_lines(source, max_width);
+		}
+	}
+}
+
+/// Apply [`Opts::frame`], should run after [`Finalize`]
+#[derive(Clone, Copy, Debug)]
+pub struct Frame;
+impl Stage for Frame {
+	fn run(&self, source: &mut Source, ctx: &Ctx) {
+		if let Some(title) = &ctx.opts.frame {
+			crate::draw_frame(source, title);
+		}
+	}
+}
@@ -1,6 +1,7 @@
 /// Mutable rich text implementation
 use std::{
-	fmt::Debug,
+	any::Any,
+	fmt::{self, Debug},
 	ops::{Bound, Deref, DerefMut, RangeBounds},
 };
 
@@ -22,7 +23,7 @@ pub trait MetaApply<T> {
 	fn apply(&mut self, change: &T);
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct Segment<D, M> {
 	meta: M,
 	data: SmallVec<[D; 16]>,
@@ -39,6 +40,17 @@ impl<D, M> Segment<D, M> {
 		&self.meta
 	}
 }
+/// `"data"[meta]` for chars, `[data][meta]` otherwise
+impl<D: Debug + 'static, M: Debug> Debug for Segment<D, M> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if let Some(chars) = (&self.data as &dyn Any).downcast_ref::<SmallVec<[char; 16]>>() {
+			Debug::fmt(&chars.iter().collect::<String>(), f)?;
+		} else {
+			f.debug_list().entries(&self.data).finish()?;
+		}
+		write!(f, "[{:?}]", self.meta)
+	}
+}
 impl<D, M> Deref for Segment<D, M> {
 	type Target = SmallVec<[D; 16]>;
 
@@ -52,13 +64,19 @@ impl<D, M> DerefMut for Segment<D, M> {
 	}
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct SegmentBuffer<D, M> {
 	// Can be replaced with Vec<u8> and segments to (UserId, Range<usize>), instead of keeping every buffer inside of segment,
 	// But it only would be faster for compaction, inserts would be slower
 	segments: SmallVec<[Segment<D, M>; 1]>,
 	len: usize,
 }
+/// List of segments, without the cached length
+impl<D: Debug + 'static, M: Debug> Debug for SegmentBuffer<D, M> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list().entries(&self.segments).finish()
+	}
+}
 impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 	pub fn empty() -> Self {
 		Self {
@@ -74,7 +92,24 @@ impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 	pub fn single(data: impl IntoIterator<Item = D>, meta: M) -> Self {
 		Self::new([Segment::new(data, meta)])
 	}
+	/// Merge adjacent segments with identical metadata, and remove the empty ones. For [`crate::Formatting`] runs
+	/// are only merged when every style property is equal, displayed text is not affected
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Segment, Text};
+	///
+	/// let mut label = Text::new([
+	///     Segment::new("unused ".chars(), Formatting::default()),
+	///     Segment::new("variable".chars(), Formatting::default()),
+	///     Segment::new(" x".chars(), Formatting::color(0xfabd2f00)),
+	/// ]);
+	/// label.compact();
+	/// assert_eq!(label.segments().count(), 2);
+	/// assert_eq!(label.data().collect::<String>(), "unused variable x");
+	/// ```
 	pub fn compact(&mut self) {
+		// Empty segments would otherwise separate the runs which should be merged
+		self.segments.retain(|s| !s.is_empty());
 		if self.segments.len() <= 1 {
 			return;
 		}
@@ -101,6 +136,20 @@ impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 			self.segments.drain(range);
 		}
 	}
+	/// Copy part of the buffer, every item keeps its metadata
+	///
+	/// ```
+	/// use ass_stroke::{Formatting, Text};
+	///
+	/// let red = Formatting::color(0xfb493400);
+	/// let label = Text::build().str("expected ").styled("i32", red.clone()).finish();
+	/// let ty = label.slice(9..);
+	/// assert_eq!(ty, Text::from_str("i32", red));
+	/// ```
+	///
+	/// # Panics
+	///
+	/// If range ends past the end of the buffer
 	pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
 		let mut segments = SmallVec::new();
 		let mut len = 0;
@@ -190,7 +239,10 @@ impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 						if insert_at.is_none() {
 							insert_at = Some(segment_idx);
 						}
-						segment_idx = segment_idx.saturating_sub(1);
+						// Next segment is now at the same index
+						end = end.saturating_sub(segment_length);
+						start = start.saturating_sub(segment_length);
+						continue;
 					}
 				} else {
 					// Inside of segment
@@ -239,11 +291,12 @@ impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 		self.compact()
 	}
 
+	/// Number of items in all the segments, for [`crate::Text`] this is number of chars, not their display width
 	pub fn len(&self) -> usize {
 		self.len
 	}
 	pub fn is_empty(&self) -> bool {
-		self.segments.is_empty()
+		self.len == 0
 	}
 
 	pub fn segments(&self) -> impl Iterator<Item = &Segment<D, M>> {
@@ -262,13 +315,26 @@ impl<D: Clone + Debug, M: Meta + Debug> SegmentBuffer<D, M> {
 		}
 		self.splice(range, Some(slice));
 	}
+	/// Append segment, it is merged into the last one if their metadata is the same,
+	/// so buffers built by appending are always compact. Empty segments are skipped
 	pub fn push(&mut self, segment: Segment<D, M>) {
+		if segment.is_empty() {
+			return;
+		}
 		self.len += segment.len();
+		if let Some(last) = self.segments.last_mut() {
+			if last.meta.try_merge(&segment.meta) {
+				last.data.extend(segment.data);
+				return;
+			}
+		}
 		self.segments.push(segment);
 	}
+	/// Append all segments of the other buffer, merging them the same way as [`SegmentBuffer::push`]
 	pub fn extend(&mut self, other: SegmentBuffer<D, M>) {
-		self.len += other.len;
-		self.segments.extend(other.segments);
+		for segment in other.segments {
+			self.push(segment);
+		}
 	}
 	pub fn resize(&mut self, size: usize, fill: D, meta: M) {
 		if self.len() > size {
@@ -331,7 +397,134 @@ mod tests {
 		}
 	}
 
+	mod merge {
+		use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+		use crate::{formatting::text_to_ansi, Formatting, Segment, Text};
+
+		type Chars = Vec<(char, Formatting)>;
+
+		fn random_text(rng: &mut SmallRng, styles: &[Formatting]) -> Text {
+			let mut segments = Vec::new();
+			for _ in 0..rng.gen_range(0..4) {
+				let data = (0..rng.gen_range(0..4))
+					.map(|_| rng.gen_range('a'..='e'))
+					.collect::<Vec<_>>();
+				segments.push(Segment::new(
+					data,
+					styles[rng.gen_range(0..styles.len())].clone(),
+				));
+			}
+			Text::new(segments)
+		}
+		fn chars(text: &Text) -> Chars {
+			text.chars_with_format()
+				.map(|(c, f)| (c, f.clone()))
+				.collect()
+		}
+		/// Every char is kept in its own segment
+		fn ansi(chars: &Chars) -> String {
+			let text = Text::new(chars.iter().map(|(c, f)| Segment::new([*c], f.clone())));
+			let mut out = String::new();
+			text_to_ansi(&text, &mut out);
+			out
+		}
+
+		#[test]
+		fn mutations_keep_output() {
+			let styles = [
+				Formatting::default(),
+				Formatting::RED,
+				Formatting::RED.decoration(),
+				Formatting::color(0x00ff0000),
+			];
+			let bold = Formatting {
+				bold: true,
+				..Formatting::default()
+			};
+			let mut rng = SmallRng::seed_from_u64(0);
+			for _ in 0..200 {
+				let mut text = Text::empty();
+				let mut reference = Chars::new();
+				for _ in 0..20 {
+					let len = text.len();
+					let (a, b) = (rng.gen_range(0..=len), rng.gen_range(0..=len));
+					let range = a.min(b)..a.max(b);
+					match rng.gen_range(0..5) {
+						0 => {
+							let other = random_text(&mut rng, &styles);
+							reference.extend(chars(&other));
+							for segment in other.segments() {
+								text.push(segment.clone());
+							}
+						}
+						1 => {
+							let other = random_text(&mut rng, &styles);
+							reference.extend(chars(&other));
+							text.extend(other);
+						}
+						2 => {
+							let insert = random_text(&mut rng, &styles);
+							reference.splice(range.clone(), chars(&insert));
+							text.splice(range, Some(insert));
+						}
+						3 => {
+							let size = rng.gen_range(0..len + 3);
+							reference.resize(size, (' ', Formatting::default()));
+							text.resize(size, ' ', Formatting::default());
+						}
+						_ => {
+							for (_, f) in &mut reference[range.clone()] {
+								f.bold = true;
+							}
+							text.apply_meta(range, &bold);
+						}
+					}
+					assert_eq!(chars(&text), reference);
+					let mut out = String::new();
+					text_to_ansi(&text, &mut out);
+					assert_eq!(out, ansi(&reference));
+
+					let segments = text.segments().collect::<Vec<_>>();
+					assert!(
+						segments.windows(2).all(|w| w[0].meta() != w[1].meta()),
+						"adjacent segments with the same style: {text:?}"
+					);
+				}
+			}
+		}
+	}
+
+	mod is_empty {
+		use crate::segment::Segment;
+		type SegmentBuffer = crate::segment::SegmentBuffer<u8, usize>;
+
+		#[test]
+		fn empty_segment() {
+			assert!(SegmentBuffer::empty().is_empty());
+			assert!(SegmentBuffer::new([Segment::new([], 1)]).is_empty());
+			assert!(!SegmentBuffer::new([Segment::new([], 1), Segment::new([1], 1)]).is_empty());
+		}
+	}
+
+	mod debug {
+		use crate::segment::{Segment, SegmentBuffer};
+
+		#[test]
+		fn any_data() {
+			#[derive(Debug, Clone)]
+			struct Cell;
+
+			let chars = SegmentBuffer::new([Segment::new(['a', 'b'], 1usize)]);
+			assert_eq!(format!("{chars:?}"), "[\"ab\"[1]]");
+			let cells = SegmentBuffer::new([Segment::new([Cell], 2usize)]);
+			assert_eq!(format!("{cells:?}"), "[[Cell][2]]");
+		}
+	}
+
 	mod slice {
+		use rand::{rngs::SmallRng, Rng, SeedableRng};
+
 		use crate::segment::{Segment, SegmentBuffer};
 
 		#[test]
@@ -371,6 +564,36 @@ mod tests {
 				SegmentBuffer::new([Segment::new([1, 2, 3, 4], 1), Segment::new([5, 6, 7, 8], 1)]);
 			assert_eq!(input.slice(0..=7), input);
 		}
+
+		fn items(buf: &SegmentBuffer<u8, usize>) -> Vec<(u8, usize)> {
+			buf.segments()
+				.flat_map(|s| s.iter().map(|d| (*d, *s.meta())))
+				.collect()
+		}
+
+		#[test]
+		fn roundtrip() {
+			let mut rng = SmallRng::seed_from_u64(0);
+			for _ in 0..200 {
+				let buf = SegmentBuffer::new((0..rng.gen_range(0..6)).map(|_| {
+					Segment::new(
+						(0..rng.gen_range(0..5))
+							.map(|_| rng.gen())
+							.collect::<Vec<u8>>(),
+						rng.gen_range(0..3),
+					)
+				}));
+				let (a, b) = (rng.gen_range(0..=buf.len()), rng.gen_range(0..=buf.len()));
+				let (a, b) = (a.min(b), a.max(b));
+
+				let mut out = buf.slice(..a);
+				out.extend(buf.slice(a..b));
+				out.extend(buf.slice(b..));
+				assert_eq!(out.len(), buf.len());
+				assert_eq!(items(&out), items(&buf));
+				assert_eq!(items(&buf.slice(a..b)), items(&buf)[a..b]);
+			}
+		}
 	}
 
 	mod splice {
@@ -427,6 +650,20 @@ mod tests {
 			)
 		}
 
+		#[test]
+		fn replace_first_segments() {
+			let mut buf = SegmentBuffer::new([
+				Segment::new([1, 2], 1),
+				Segment::new([3, 4], 2),
+				Segment::new([5, 6], 1),
+			]);
+			buf.splice(0..5, Some(SegmentBuffer::new([Segment::new([7; 5], 3)])));
+			assert_eq!(
+				buf,
+				SegmentBuffer::new([Segment::new([7; 5], 3), Segment::new([6], 1)])
+			)
+		}
+
 		#[test]
 		fn replace_middle_overlap() {
 			let mut buf = SegmentBuffer::new([Segment::new([1, 2], 1), Segment::new([3, 4], 1)]);
@@ -2,13 +2,14 @@ use core::fmt;
 use std::{
 	cmp::Reverse,
 	collections::{BTreeMap, HashMap, HashSet},
+	hash::Hash,
 };
 
 use num_traits::PrimInt;
 use range_map::RangeSet;
 
 use crate::{
-	annotation::AnnotationId,
+	annotation::{AnnotationId, LabelStacking},
 	anomaly_fixer::apply_fixup,
 	segment::{Segment, SegmentBuffer},
 	Formatting, Text,
@@ -21,21 +22,61 @@ pub(crate) struct LineAnnotation {
 	pub priority: usize,
 	pub ranges: RangeSet<usize>,
 	pub formatting: Formatting,
+	/// Char used to draw the continuation of ranges, see [`crate::Palette`]
+	pub underline: char,
 	/// Should this annotation have a line pointing to the left
 	/// This option is used to make places for interconnecting lines
 	pub left: bool,
 	/// What text to display to the right of annotated line
 	pub right: Text,
+	/// Label is moved to the right margin, see [`crate::LabelAnchor::RightMargin`]
+	pub right_anchored: bool,
+}
+impl LineAnnotation {
+	/// Annotations without text and connections only highlight the range, no arrow row is emitted for them
+	fn has_arrow(&self) -> bool {
+		self.left || !self.right.is_empty()
+	}
+}
+
+/// Label at the end of the generated row, which is moved to [`crate::Opts::max_width`] after the gutter is drawn
+#[derive(Debug, Clone)]
+pub(crate) struct RightAnchor {
+	/// Rows of the same label are moved together
+	pub id: AnnotationId,
+	/// Number of the moved chars at the end of the row
+	pub len: usize,
+	/// Char and formatting of the leader, continuation rows of the label are padded with spaces instead
+	pub leader: Option<(char, Formatting)>,
 }
 
-/// Distribute annotations per layers
-/// In single layer, no annotation range conflicts will occur
+/// Generated row, with the connected annotation, see [`crate::AnnotationLine`]
+pub(crate) type AnnotationRow = (Option<AnnotationId>, Text, Option<RightAnchor>);
+
+/// Distribute items per groups, so that ranges of items in the same group never overlap
+///
+/// Grouping is greedy: every item, which is not yet grouped, starts a new group, and then every
+/// following not yet grouped item, which doesn't overlap with the group, is added to it.
+/// Thus both groups and items inside of them keep the order of the input, and the first group
+/// is the longest possible one, but the total number of groups is not guaranteed to be minimal.
 ///
-/// Sorting order is not determined, and depends on sorting of original array
-pub(crate) fn group_nonconflicting<T: PrimInt + fmt::Debug>(
-	annotations: &[(AnnotationId, RangeSet<T>)],
-	exclude: &HashSet<AnnotationId>,
-) -> Vec<Vec<AnnotationId>> {
+/// Keys from `exclude`, and repeated keys are skipped.
+///
+/// ```
+/// use ass_stroke::group_nonconflicting;
+/// use range_map::{Range, RangeSet};
+///
+/// let set = |start: u32, end: u32| [Range::new(start, end)].into_iter().collect::<RangeSet<u32>>();
+/// let groups = group_nonconflicting(
+///     &[('a', set(0, 2)), ('b', set(2, 4)), ('c', set(4, 6))],
+///     &Default::default(),
+/// );
+/// assert_eq!(groups, [vec!['a', 'c'], vec!['b']]);
+/// ```
+pub fn group_nonconflicting<K: Copy + Eq + Hash, T: PrimInt + fmt::Debug>(
+	annotations: &[(K, RangeSet<T>)],
+	exclude: &HashSet<K>,
+) -> Vec<Vec<K>> {
 	let mut layers = vec![];
 	let mut processed = exclude.clone();
 
@@ -65,11 +106,13 @@ pub(crate) fn group_nonconflicting<T: PrimInt + fmt::Debug>(
 
 #[allow(dead_code)]
 pub(crate) fn generate_range_annotations(
-	mut annotations: Vec<LineAnnotation>,
+	annotations: Vec<LineAnnotation>,
 	char_to_display_fixup: &BTreeMap<usize, isize>,
 	hide_ranges_for: &HashSet<AnnotationId>,
 	bottom: bool,
-) -> Vec<(Option<AnnotationId>, Text)> {
+	stacking: LabelStacking,
+	pointers: bool,
+) -> Vec<AnnotationRow> {
 	if annotations.is_empty() {
 		return Vec::new();
 	}
@@ -79,7 +122,29 @@ pub(crate) fn generate_range_annotations(
 		offset
 	};
 
-	annotations.sort_by_key(|ann| (Reverse(ann.priority), Reverse(ann.ranges.num_elements())));
+	// Nested ranges are drawn closer to the text, than the ones containing them
+	let contained = annotations
+		.iter()
+		.map(|a| {
+			annotations
+				.iter()
+				.filter(|b| b.ranges != a.ranges && b.ranges.intersection(&a.ranges) == b.ranges)
+				.count()
+		})
+		.collect::<Vec<_>>();
+	let mut annotations = annotations.into_iter().zip(contained).collect::<Vec<_>>();
+	annotations.sort_by_key(|(ann, contained)| {
+		(
+			*contained,
+			Reverse(ann.priority),
+			Reverse(ann.ranges.num_elements()),
+		)
+	});
+	let contained = annotations
+		.iter()
+		.map(|(a, contained)| (a.id, *contained))
+		.collect::<HashMap<_, _>>();
+	let mut annotations = annotations.into_iter().map(|(a, _)| a).collect::<Vec<_>>();
 
 	let per_line_ranges = group_nonconflicting(
 		&annotations
@@ -128,6 +193,9 @@ pub(crate) fn generate_range_annotations(
 				.iter()
 				.map(|i| annotations_by_id.get(i).expect("exists"))
 			{
+				if !annotation.has_arrow() {
+					useless = false;
+				}
 				for range in annotation.ranges.ranges() {
 					let data = if range.start == range.end {
 						vec![chars.cont]
@@ -135,7 +203,7 @@ pub(crate) fn generate_range_annotations(
 						let mut out = vec![chars.range_start];
 						out.resize(
 							char_to_display(range.end) - char_to_display(range.start),
-							chars.range_cont,
+							annotation.underline,
 						);
 						out.push(chars.range_end);
 						useless = false;
@@ -187,11 +255,27 @@ pub(crate) fn generate_range_annotations(
 		}
 	}
 
+	// Rows are emitted from the source line outwards, labels are placed after the rightmost range,
+	// so rows with rightmost ranges go first, to make connections of the following rows only pass
+	// above (or below) them, never crossing the label text.
+	// Connected annotations go before everything else, as their connection has to reach the line start.
+	// Same position is resolved by nesting, by priority, and then by the order of definition.
+	// With [`LabelStacking::OutermostNearest`] nesting is checked first, and connections of the
+	// nested annotations are crossing labels of the containing ones
 	annotations.sort_by_key(|a| {
+		let contained = contained[&a.id];
+		let nesting = match stacking {
+			LabelStacking::OutermostFarthest => (Reverse(0), contained),
+			LabelStacking::OutermostNearest => (Reverse(contained), 0),
+		};
 		(
 			a.right.is_empty(),
 			!a.left,
+			nesting.0,
 			Reverse(a.ranges.ranges().next().expect("not empty").start),
+			nesting.1,
+			Reverse(a.priority),
+			a.id.0,
 		)
 	});
 
@@ -226,11 +310,16 @@ pub(crate) fn generate_range_annotations(
 		use crate::chars::arrow::*;
 		let chars = if bottom { &BOTTOM } else { &TOP };
 		for annotation in &annotations {
+			if !annotation.has_arrow() {
+				layers.push(Vec::new());
+				continue;
+			}
 			let mut fmtlayer = SegmentBuffer::new([Segment::new(
 				vec![' '; max_range_display + 1],
 				Formatting::default(),
 			)]);
 			let mut extralayers = Vec::new();
+			let mut anchor = None;
 
 			let starts = annotation
 				.ranges
@@ -291,15 +380,41 @@ pub(crate) fn generate_range_annotations(
 					)])),
 				);
 				fmtlayer.extend(Text::single([' '], Default::default()));
+				// Continuation rows are aligned with the first row of text
+				let text_start = fmtlayer.len();
 				let lines = right.split('\n');
 				fmtlayer.extend(lines[0].clone());
+				if annotation.right_anchored {
+					anchor = Some(RightAnchor {
+						id: annotation.id,
+						len: lines[0].len() + 1,
+						leader: Some((chars.arrow_cont, annotation.formatting.clone())),
+					});
+				}
 				for right in lines.iter().skip(1) {
 					let mut fmtlayer = SegmentBuffer::new([Segment::new(
-						vec![' '; max_range_display],
+						vec![' '; text_start],
 						Formatting::default(),
 					)]);
+					if !bottom {
+						// First row is placed above the continuation rows, arrows should go through them
+						for start in &starts {
+							fmtlayer.splice(
+								char_to_display(*start)..=char_to_display(*start),
+								Some(SegmentBuffer::new([Segment::new(
+									[chars.cont],
+									annotation.formatting.clone(),
+								)])),
+							);
+						}
+					}
 					fmtlayer.extend(right.clone());
-					extralayers.push((None, fmtlayer));
+					let continued = annotation.right_anchored.then(|| RightAnchor {
+						id: annotation.id,
+						len: right.len(),
+						leader: None,
+					});
+					extralayers.push((None, fmtlayer, continued));
 				}
 			}
 			for i in char_to_display(min)..=char_to_display(max) {
@@ -315,15 +430,26 @@ pub(crate) fn generate_range_annotations(
 				);
 			}
 
-			extralayers.insert(0, (annotation_id, fmtlayer));
-			// layers.extend(extralayers);
+			if bottom {
+				extralayers.insert(0, (annotation_id, fmtlayer, anchor));
+			} else {
+				// Top layers are displayed in reverse, but text rows should still be read from top to bottom
+				extralayers.reverse();
+				extralayers.push((annotation_id, fmtlayer, anchor));
+			}
 			layers.push(extralayers);
 		}
 		// Cross lines for earlier displayed annotations
 		for (i, annotation) in annotations.iter().enumerate() {
+			if !annotation.has_arrow() {
+				continue;
+			}
 			for affected in layers[..i].iter_mut().flatten() {
 				for start in annotation.ranges.ranges().map(|r| r.start) {
-					let (c, orig_fmt) = affected.1.get(start).expect("extended to max");
+					let (c, orig_fmt) = affected
+						.1
+						.get(char_to_display(start))
+						.expect("extended to max");
 					if let Some((keep_style, replacement)) = cross(chars, c) {
 						affected.1.splice(
 							char_to_display(start)..=char_to_display(start),
@@ -347,11 +473,109 @@ pub(crate) fn generate_range_annotations(
 		if useless_range_fmt_layers.contains(&idx) {
 			continue;
 		}
-		out.push((None, layer.clone()));
+		out.push((None, layer.clone(), None));
 	}
 	for layer in layers.iter().flatten() {
 		out.push(layer.clone())
 	}
 
+	if pointers {
+		let chars = if bottom {
+			&crate::chars::single::BOTTOM
+		} else {
+			&crate::chars::single::TOP
+		};
+		// Rows are ordered from the source line outwards
+		if let Some((_, nearest, _)) = out.first_mut() {
+			for annotation in annotations
+				.iter()
+				.filter(|a| !hide_ranges_for.contains(&a.id))
+			{
+				for start in annotation.ranges.ranges().map(|r| char_to_display(r.start)) {
+					let Some((c, fmt)) = nearest.get(start) else {
+						continue;
+					};
+					if c == ' ' {
+						continue;
+					}
+					nearest.splice(
+						start..=start,
+						Some(SegmentBuffer::new([Segment::new([chars.pointer], fmt)])),
+					);
+				}
+			}
+		}
+	}
+
 	out
 }
+
+#[cfg(test)]
+mod tests {
+	mod group_nonconflicting {
+		use std::collections::HashSet;
+
+		use range_map::{Range, RangeSet};
+
+		use crate::single_line::group_nonconflicting;
+
+		fn items(ranges: &[(usize, usize)]) -> Vec<(usize, RangeSet<usize>)> {
+			ranges
+				.iter()
+				.enumerate()
+				.map(|(i, (start, end))| (i, [Range::new(*start, *end)].into_iter().collect()))
+				.collect()
+		}
+
+		#[test]
+		fn all_overlapping() {
+			let groups = group_nonconflicting(&items(&[(0, 5), (1, 6), (2, 3)]), &HashSet::new());
+			assert_eq!(groups, [vec![0], vec![1], vec![2]]);
+		}
+
+		#[test]
+		fn all_disjoint() {
+			let groups = group_nonconflicting(&items(&[(6, 7), (0, 1), (3, 4)]), &HashSet::new());
+			assert_eq!(groups, [vec![0, 1, 2]]);
+		}
+
+		#[test]
+		fn chain() {
+			let groups =
+				group_nonconflicting(&items(&[(0, 2), (2, 4), (4, 6), (6, 8)]), &HashSet::new());
+			assert_eq!(groups, [vec![0, 2], vec![1, 3]]);
+		}
+
+		#[test]
+		fn not_minimal() {
+			// Two groups are enough: {0, 3}, {1, 2}
+			let groups =
+				group_nonconflicting(&items(&[(7, 9), (4, 7), (3, 3), (3, 5)]), &HashSet::new());
+			assert_eq!(groups, [vec![0, 2], vec![1], vec![3]]);
+		}
+
+		#[test]
+		fn excluded_and_repeated() {
+			let mut items = items(&[(0, 1), (2, 3), (4, 5)]);
+			items.push((0, [Range::new(6, 7)].into_iter().collect()));
+			let groups = group_nonconflicting(&items, &HashSet::from([1]));
+			assert_eq!(groups, [vec![0, 2]]);
+		}
+
+		#[test]
+		fn multiple_ranges() {
+			let items = vec![
+				(
+					'a',
+					[Range::new(0, 1), Range::new(6, 7)]
+						.into_iter()
+						.collect::<RangeSet<usize>>(),
+				),
+				('b', [Range::new(3, 4)].into_iter().collect()),
+				('c', [Range::new(7, 8)].into_iter().collect()),
+			];
+			let groups = group_nonconflicting(&items, &HashSet::new());
+			assert_eq!(groups, [vec!['a', 'b'], vec!['c']]);
+		}
+	}
+}
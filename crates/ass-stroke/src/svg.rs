@@ -0,0 +1,226 @@
+//! Standalone SVG output, i.e for embedding diagnostics into documentation and blog posts
+
+use std::fmt::Write;
+
+use crate::{anomaly_fixer::char_width, Formatting, Source};
+
+/// Appearance of the image produced by [`Source::to_svg`], sizes are in pixels
+#[derive(Clone, PartialEq, Debug)]
+pub struct SvgOpts {
+	pub font_size: f64,
+	pub font_family: String,
+	/// Space between the text grid and the image border
+	pub padding: f64,
+	/// Color of the text without its own color, in the same format as [`Formatting::color`]
+	pub foreground: u32,
+	/// Transparent if not set
+	pub background: Option<u32>,
+	/// Draw rounded window with the title bar buttons, like a screenshot of the terminal
+	pub window_chrome: bool,
+}
+/// 14px monospace text, light gray on the same background as the gutter, without window chrome
+impl Default for SvgOpts {
+	fn default() -> Self {
+		Self {
+			font_size: 14.0,
+			font_family: "monospace".to_owned(),
+			padding: 16.0,
+			foreground: 0xebdbb200,
+			background: Some(0x28282800),
+			window_chrome: false,
+		}
+	}
+}
+
+/// Width of the grid cell, and height of the row, relative to the font size
+const CELL_WIDTH: f64 = 0.6;
+const ROW_HEIGHT: f64 = 1.4;
+/// Height of the title bar, relative to the padding
+const CHROME_HEIGHT: f64 = 2.0;
+const CHROME_BUTTONS: [u32; 3] = [0xff5f5600, 0xffbd2e00, 0x27c93f00];
+
+fn color(color: u32) -> String {
+	let [r, g, b, _a] = color.to_be_bytes();
+	format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Rounded to hundredths, so the output doesn't depend on float error accumulation
+fn px(value: f64) -> String {
+	let out = format!("{:.2}", value);
+	out.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+fn escape(text: &str, out: &mut String) {
+	for c in text.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			c => out.push(c),
+		}
+	}
+}
+
+fn attributes(formatting: &Formatting, opts: &SvgOpts, out: &mut String) {
+	let fill = formatting.color.unwrap_or(opts.foreground);
+	write!(out, " fill=\"{}\"", color(fill)).expect("no fmt error");
+	if formatting.bold {
+		out.push_str(" font-weight=\"bold\"");
+	}
+	if formatting.dim {
+		out.push_str(" opacity=\"0.5\"");
+	}
+	if formatting.underline {
+		out.push_str(" text-decoration=\"underline\"");
+	}
+}
+
+impl Source {
+	/// Render snippet as a standalone SVG image, every styled run of the rendered lines becomes a `<tspan>`.
+	///
+	/// Every run is positioned by its display column, so fullwidth chars don't shift the following runs,
+	/// even if the font doesn't match the terminal widths
+	///
+	/// ```
+	/// use ass_stroke::{SnippetBuilder, SvgOpts, Text};
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let svg = snippet.build().to_svg(&SvgOpts::default());
+	/// assert!(svg.starts_with("<svg "));
+	/// assert!(svg.contains(">here</tspan>"));
+	/// ```
+	pub fn to_svg(&self, opts: &SvgOpts) -> String {
+		let cell = opts.font_size * CELL_WIDTH;
+		let row = opts.font_size * ROW_HEIGHT;
+		let chrome = if opts.window_chrome {
+			opts.padding * CHROME_HEIGHT
+		} else {
+			0.0
+		};
+		let width = opts.padding * 2.0 + self.rendered_width() as f64 * cell;
+		let height = opts.padding * 2.0 + chrome + self.lines.len() as f64 * row;
+
+		let mut out = String::new();
+		writeln!(
+			out,
+			"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+			w = px(width),
+			h = px(height),
+		)
+		.expect("no fmt error");
+		if let Some(background) = opts.background {
+			let radius = if opts.window_chrome { " rx=\"6\"" } else { "" };
+			writeln!(
+				out,
+				"<rect width=\"100%\" height=\"100%\" fill=\"{}\"{radius}/>",
+				color(background),
+			)
+			.expect("no fmt error");
+		}
+		if opts.window_chrome {
+			let radius = opts.padding / 3.0;
+			for (i, button) in CHROME_BUTTONS.into_iter().enumerate() {
+				writeln!(
+					out,
+					"<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\"/>",
+					px(opts.padding + radius + i as f64 * radius * 3.0),
+					px(chrome / 2.0 + opts.padding / 2.0),
+					px(radius),
+					color(button),
+				)
+				.expect("no fmt error");
+			}
+		}
+
+		let top = opts.padding + chrome;
+		// Backgrounds go first, so they don't cover the text of the neighbouring runs
+		for (i, line) in self.lines.iter().enumerate() {
+			let data = line.rendered();
+			let mut column = 0;
+			for segment in data.segments() {
+				let start = column;
+				column += segment.iter().map(|c| char_width(*c)).sum::<usize>();
+				let Some(bg_color) = segment.meta().bg_color else {
+					continue;
+				};
+				writeln!(
+					out,
+					"<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+					px(opts.padding + start as f64 * cell),
+					px(top + i as f64 * row),
+					px((column - start) as f64 * cell),
+					px(row),
+					color(bg_color),
+				)
+				.expect("no fmt error");
+			}
+		}
+
+		out.push_str("<g font-family=\"");
+		escape(&opts.font_family, &mut out);
+		writeln!(out, "\" font-size=\"{}\">", px(opts.font_size)).expect("no fmt error");
+		for (i, line) in self.lines.iter().enumerate() {
+			let data = line.rendered();
+			// Baseline is placed so the glyphs are vertically centered in the row
+			let baseline = top + i as f64 * row + (row + opts.font_size * 0.7) / 2.0;
+			write!(out, "<text y=\"{}\" xml:space=\"preserve\">", px(baseline))
+				.expect("no fmt error");
+			let mut column = 0;
+			for segment in data.segments() {
+				let start = column;
+				column += segment.iter().map(|c| char_width(*c)).sum::<usize>();
+				// Positions are explicit, so blank runs don't need to be emitted
+				if segment.iter().all(|c| *c == ' ') {
+					continue;
+				}
+				write!(
+					out,
+					"<tspan x=\"{}\"",
+					px(opts.padding + start as f64 * cell)
+				)
+				.expect("no fmt error");
+				attributes(segment.meta(), opts, &mut out);
+				out.push('>');
+				escape(&segment.iter().collect::<String>(), &mut out);
+				out.push_str("</tspan>");
+			}
+			out.push_str("</text>\n");
+		}
+		out.push_str("</g>\n</svg>\n");
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use range_map::Range;
+
+	use super::SvgOpts;
+	use crate::{Annotation, Formatting, Opts, PreparedSource, Text};
+
+	#[test]
+	fn golden() {
+		let mut prepared = PreparedSource::new(
+			&Text::from("let 名前 = \"a<b\";\nlet b = 名前;"),
+			Opts::default(),
+		);
+		prepared.add(Annotation::new(
+			[Range::new(4, 9)].into_iter().collect(),
+			Text::from("defined here"),
+			Formatting::ERROR,
+		));
+		prepared.add(Annotation::new(
+			[Range::new(28, 33)].into_iter().collect(),
+			Text::from("used here"),
+			Formatting::NOTE,
+		));
+		let source = prepared.render().expect("valid annotations");
+		let svg = source.to_svg(&SvgOpts {
+			window_chrome: true,
+			..SvgOpts::default()
+		});
+		assert_eq!(svg, include_str!("../../../fixtures/snippet.svg"));
+	}
+}
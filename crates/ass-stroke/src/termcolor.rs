@@ -0,0 +1,173 @@
+//! [`termcolor`] backend, for the consoles without escape sequences support, i.e legacy Windows console
+
+use std::io;
+
+use ::termcolor::{Color, ColorSpec, WriteColor};
+
+use crate::{
+	formatting::{Formatting, Text, ANSI_COLORS},
+	Source,
+};
+
+/// Indexed the same way as [`ANSI_COLORS`], intense colors are following the normal ones
+const COLORS: [Color; 8] = [
+	Color::Black,
+	Color::Red,
+	Color::Green,
+	Color::Yellow,
+	Color::Blue,
+	Color::Magenta,
+	Color::Cyan,
+	Color::White,
+];
+
+/// Index of the nearest of 16 [`ANSI_COLORS`], searching only in the range of `candidates`
+fn nearest(color: u32, candidates: std::ops::Range<usize>) -> usize {
+	let [r, g, b, _a] = color.to_be_bytes();
+	candidates
+		.min_by_key(|&i| {
+			let [cr, cg, cb, _a] = ANSI_COLORS[i].to_be_bytes();
+			[(r, cr), (g, cg), (b, cb)]
+				.into_iter()
+				.map(|(a, b)| (a as i32 - b as i32).pow(2))
+				.sum::<i32>()
+		})
+		.expect("candidates are not empty")
+}
+
+fn color_spec(formatting: &Formatting) -> ColorSpec {
+	let mut spec = ColorSpec::new();
+	spec.set_bold(formatting.bold)
+		.set_dimmed(formatting.dim)
+		.set_underline(formatting.underline);
+	// Intensity is shared between foreground and background, so it is decided by the foreground
+	let mut bank = 0..16;
+	if let Some(color) = formatting.color {
+		let i = nearest(color, bank.clone());
+		spec.set_fg(Some(COLORS[i % 8])).set_intense(i >= 8);
+		bank = if i >= 8 { 8..16 } else { 0..8 };
+	}
+	if let Some(bg_color) = formatting.bg_color {
+		let i = nearest(bg_color, bank);
+		spec.set_bg(Some(COLORS[i % 8]));
+		if formatting.color.is_none() {
+			spec.set_intense(i >= 8);
+		}
+	}
+	spec
+}
+
+fn write_text(text: &Text, out: &mut dyn WriteColor) -> io::Result<()> {
+	let mut active = ColorSpec::new();
+	for segment in text.segments().filter(|s| !s.is_empty()) {
+		let spec = color_spec(segment.meta());
+		if spec != active {
+			if spec.is_none() {
+				out.reset()?;
+			} else {
+				out.set_color(&spec)?;
+			}
+			active = spec;
+		}
+		write!(out, "{}", segment.iter().collect::<String>())?;
+	}
+	if !active.is_none() {
+		out.reset()?;
+	}
+	Ok(())
+}
+
+impl Source {
+	/// Write snippet using [`termcolor`] styles, colors are mapped to the nearest of 16 terminal colors
+	///
+	/// ```
+	/// use ass_stroke::{SnippetBuilder, Text};
+	/// use termcolor::Buffer;
+	///
+	/// let mut snippet = SnippetBuilder::new("let a = 1;");
+	/// snippet.error(Text::from("here")).range(4..=4).build();
+	/// let source = snippet.build();
+	///
+	/// let mut buffer = Buffer::no_color();
+	/// source.write_termcolor(&mut buffer).unwrap();
+	/// assert_eq!(buffer.as_slice(), source.plain().to_string().as_bytes());
+	/// ```
+	pub fn write_termcolor(&self, out: &mut dyn WriteColor) -> io::Result<()> {
+		for (i, line) in self.lines.iter().enumerate() {
+			write_text(&line.rendered(), out)?;
+			write!(out, "{}", self.line_terminator(i))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use ::termcolor::{Buffer, Color};
+
+	use super::color_spec;
+	use crate::{Formatting, Line, LineEnding, RawLine, Segment, SnippetBuilder, Source, Text};
+
+	#[test]
+	fn nearest_colors() {
+		let spec = color_spec(&Formatting::color(0xfb493400));
+		assert_eq!(spec.fg(), Some(&Color::Red));
+		assert!(spec.intense());
+
+		let spec = color_spec(&Formatting {
+			bg_color: Some(0x28282800),
+			..Formatting::color(0x92837400)
+		});
+		assert_eq!(spec.fg(), Some(&Color::Black));
+		assert_eq!(spec.bg(), Some(&Color::Black));
+		assert!(spec.intense());
+
+		let spec = color_spec(&Formatting::default());
+		assert!(spec.is_none());
+	}
+
+	#[test]
+	fn buffer() {
+		let source = Source {
+			lines: vec![Line::Raw(RawLine::new(Text::new([
+				Segment::new("a ".chars(), Formatting::default()),
+				Segment::new("err".chars(), Formatting::color(0xff000000)),
+				Segment::new(
+					"or".chars(),
+					Formatting {
+						bold: true,
+						..Formatting::color(0xff000000)
+					},
+				),
+				Segment::new(" b".chars(), Formatting::default()),
+			])))],
+			warnings: Vec::new(),
+			summaries: Vec::new(),
+			paddings: Vec::new(),
+			wrap_prefixes: Vec::new(),
+			dedent: 0,
+			folded: Vec::new(),
+			line_ending: LineEnding::Lf,
+			final_line_ending: true,
+			gutter_width: 0,
+			continued_above: HashSet::new(),
+			continued_below: HashSet::new(),
+			finalized: true,
+		};
+		let mut buffer = Buffer::ansi();
+		source.write_termcolor(&mut buffer).expect("buffer write");
+		assert_eq!(
+			String::from_utf8_lossy(buffer.as_slice()),
+			"a \x1b[0m\x1b[38;5;9merr\x1b[0m\x1b[1m\x1b[38;5;9mor\x1b[0m b\n"
+		);
+
+		let mut snippet = SnippetBuilder::new("let a = 1;");
+		snippet.error(Text::from("here")).range(4..=4).build();
+		let source = snippet.build();
+		let mut buffer = Buffer::no_color();
+		source.write_termcolor(&mut buffer).expect("buffer write");
+		assert_eq!(buffer.as_slice(), source.plain().to_string().as_bytes());
+	}
+}